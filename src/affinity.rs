@@ -0,0 +1,80 @@
+use std::fmt::Debug;
+use std::io;
+
+/// A set of logical CPUs for a process or thread to run on, checked against the number of CPUs
+/// in the system.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) struct CpuSet {
+    #[cfg(target_os = "linux")]
+    set: libc::cpu_set_t,
+}
+
+#[cfg(target_os = "linux")]
+impl CpuSet {
+    pub fn new(cpus: &[usize]) -> io::Result<Self> {
+        if cpus.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "CPU affinity needs at least one CPU",
+            ));
+        }
+        // SAFETY: FFI call with no pointers.
+        let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+        let count = usize::try_from(count)
+            .map_err(|_| io::Error::last_os_error())?
+            .min(libc::CPU_SETSIZE as usize);
+        // SAFETY: cpu_set_t is a plain C bitmask.
+        let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+        for &cpu in cpus {
+            if cpu >= count {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no CPU {cpu}: there are {count}"),
+                ));
+            }
+            // SAFETY: the index is within the set.
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        Ok(Self { set })
+    }
+
+    /// Restrict the calling thread to these CPUs. Between fork and exec, that's the whole child
+    /// process. This only makes an async-signal-safe call.
+    pub fn apply(&self) -> io::Result<()> {
+        // SAFETY: FFI call with a valid pointer and size.
+        let ret = unsafe {
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &self.set)
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl CpuSet {
+    pub fn new(_cpus: &[usize]) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "CPU affinity is only supported on Linux",
+        ))
+    }
+
+    pub fn apply(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Debug for CpuSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut set = f.debug_set();
+        #[cfg(target_os = "linux")]
+        set.entries((0..libc::CPU_SETSIZE as usize).filter(|&cpu| {
+            // SAFETY: the index is within the set.
+            unsafe { libc::CPU_ISSET(cpu, &self.set) }
+        }));
+        set.finish()
+    }
+}
@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::io;
+
+use crate::lambda::LambdaError;
+use crate::process::ChildExit;
+use crate::{Filter, IoChainError, OwnedPipeEnd, ReadStream, RunningFilter, WriteStream};
+
+/// Conversion from the result of a running filter into a plain success or failure, so that
+/// different kinds of filters can be handled the same way.
+///
+/// See also [`RunningFilter::wait_ok()`], for when all that matters is whether a filter succeeded.
+pub trait NormalizedResult {
+    /// Convert to `Ok(())` if the filter succeeded, or the first error if not.
+    fn normalize(self) -> io::Result<()>;
+
+    /// Convert to `Ok(())` if the filter succeeded, or an [`IoChainError`] with everything that
+    /// went wrong if not.
+    fn into_chain_result(self) -> Result<(), IoChainError>;
+}
+
+impl NormalizedResult for ChildExit {
+    fn normalize(self) -> io::Result<()> {
+        self.combine().map_err(io::Error::other)
+    }
+
+    fn into_chain_result(self) -> Result<(), IoChainError> {
+        self.into_result()
+    }
+}
+
+impl<R> NormalizedResult for io::Result<R> {
+    fn normalize(self) -> io::Result<()> {
+        self.map(|_| ())
+    }
+
+    fn into_chain_result(self) -> Result<(), IoChainError> {
+        self.map(|_| ()).map_err(IoChainError::from)
+    }
+}
+
+impl<R> NormalizedResult for Result<R, LambdaError<R>> {
+    fn normalize(self) -> io::Result<()> {
+        self.map(|_| ()).map_err(io::Error::from)
+    }
+
+    fn into_chain_result(self) -> Result<(), IoChainError> {
+        self.map(|_| ()).map_err(|e| IoChainError::from(e.error))
+    }
+}
+
+impl NormalizedResult for Vec<io::Result<()>> {
+    fn normalize(self) -> io::Result<()> {
+        self.into_iter().collect()
+    }
+
+    fn into_chain_result(self) -> Result<(), IoChainError> {
+        IoChainError::collect(self)
+    }
+}
+
+impl<A: NormalizedResult, B: NormalizedResult> NormalizedResult for (A, B) {
+    fn normalize(self) -> io::Result<()> {
+        let (a, b) = self;
+        a.normalize().and(b.normalize())
+    }
+
+    fn into_chain_result(self) -> Result<(), IoChainError> {
+        let (a, b) = self;
+        let errors = [a.into_chain_result(), b.into_chain_result()]
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+        IoChainError::from_vec(errors)
+    }
+}
+
+/// Turn any error into an [`io::Error`], without wrapping it again if it already is one.
+fn into_io_error(e: impl Error + Send + Sync + 'static) -> io::Error {
+    let boxed: Box<dyn Error + Send + Sync> = Box::new(e);
+    match boxed.downcast::<io::Error>() {
+        Ok(e) => *e,
+        Err(other) => io::Error::other(other),
+    }
+}
+
+trait DynFilter: Send {
+    fn start_boxed(
+        self: Box<Self>,
+        input: ReadStream,
+        output: WriteStream,
+    ) -> io::Result<BoxedRunning>;
+}
+
+impl<F> DynFilter for F
+where
+    F: Filter + Send,
+    F::Error: Send + Sync + 'static,
+    F::Running: Send + 'static,
+    <F::Running as RunningFilter>::Result: NormalizedResult,
+{
+    fn start_boxed(
+        self: Box<Self>,
+        input: ReadStream,
+        output: WriteStream,
+    ) -> io::Result<BoxedRunning> {
+        let running = (*self).start(input, output).map_err(into_io_error)?;
+        Ok(BoxedRunning {
+            inner: Box::new(running),
+        })
+    }
+}
+
+/// A type-erased [`Filter`], so that different kinds of filters can be kept together, for example
+/// in a `Vec`. The running filter's result is converted with [`NormalizedResult`].
+pub struct BoxedFilter {
+    inner: Box<dyn DynFilter>,
+}
+
+impl BoxedFilter {
+    /// Wrap the given filter.
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Filter + Send + 'static,
+        F::Error: Send + Sync + 'static,
+        F::Running: Send + 'static,
+        <F::Running as RunningFilter>::Result: NormalizedResult,
+    {
+        Self {
+            inner: Box::new(filter),
+        }
+    }
+}
+
+impl Filter for BoxedFilter {
+    type Running = BoxedRunning;
+    type Error = io::Error;
+
+    fn start(self, input: ReadStream, output: WriteStream) -> io::Result<Self::Running> {
+        self.inner.start_boxed(input, output)
+    }
+}
+
+trait DynRunning: Send {
+    fn wait_boxed(self: Box<Self>) -> io::Result<()>;
+    fn is_finished(&mut self) -> bool;
+    fn abort(&mut self);
+    fn on_complete(&mut self, f: Box<dyn FnOnce() + Send>);
+    fn name(&self) -> Option<&str>;
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd>;
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd>;
+}
+
+impl<R> DynRunning for R
+where
+    R: RunningFilter + Send,
+    R::Result: NormalizedResult,
+{
+    fn wait_boxed(self: Box<Self>) -> io::Result<()> {
+        (*self).wait().normalize()
+    }
+
+    fn is_finished(&mut self) -> bool {
+        RunningFilter::is_finished(self)
+    }
+
+    fn abort(&mut self) {
+        RunningFilter::abort(self)
+    }
+
+    fn on_complete(&mut self, f: Box<dyn FnOnce() + Send>) {
+        RunningFilter::on_complete(self, f)
+    }
+
+    fn name(&self) -> Option<&str> {
+        RunningFilter::name(self)
+    }
+
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        RunningFilter::input_pipe(self)
+    }
+
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        RunningFilter::output_pipe(self)
+    }
+}
+
+/// A running [`BoxedFilter`].
+pub struct BoxedRunning {
+    inner: Box<dyn DynRunning>,
+}
+
+impl RunningFilter for BoxedRunning {
+    type Result = io::Result<()>;
+
+    fn wait(self) -> Self::Result {
+        self.inner.wait_boxed()
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.inner.is_finished()
+    }
+
+    fn abort(&mut self) {
+        self.inner.abort()
+    }
+
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static) {
+        self.inner.on_complete(Box::new(f))
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.inner.name()
+    }
+
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.inner.input_pipe()
+    }
+
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.inner.output_pipe()
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::WriteStream;
+
+/// The collected output of a [`WriteStream::capture()`] stream.
+///
+/// Reading from this does not wait for the filter to finish: it returns whatever has been written
+/// so far. Read it after [`RunningFilter::wait()`](crate::RunningFilter::wait) returns to get the
+/// complete output.
+#[derive(Debug, Clone, Default)]
+pub struct CapturedOutput {
+    inner: Arc<Mutex<Vec<u8>>>,
+}
+
+impl CapturedOutput {
+    /// Get a copy of the bytes written so far.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.inner.lock().clone()
+    }
+
+    /// Take the bytes written so far. If the filter is still running, anything it writes after this
+    /// is called is still collected, starting from empty, and can only be got from another clone
+    /// of this handle.
+    pub fn into_bytes(self) -> Vec<u8> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(mx) => mx.into_inner(),
+            Err(arc) => std::mem::take(&mut *arc.lock()),
+        }
+    }
+}
+
+struct CaptureWriter {
+    inner: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct CountingWriter {
+    count: Arc<AtomicU64>,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteStream {
+    /// Create a stream which collects everything written to it in memory, along with a handle for
+    /// getting the bytes out afterwards.
+    pub fn capture() -> (WriteStream, CapturedOutput) {
+        let captured = CapturedOutput::default();
+        let writer = CaptureWriter {
+            inner: Arc::clone(&captured.inner),
+        };
+        (WriteStream::Rust(Box::new(writer)), captured)
+    }
+
+    /// Create a stream which discards everything written to it, like [`WriteStream::Null`], but
+    /// counts the bytes. The count is complete once the filter has finished.
+    ///
+    /// Unlike [`WriteStream::Null`], a [`ChildProcess`](crate::ChildProcess) needs a copy thread to
+    /// count its output.
+    pub fn counting() -> (WriteStream, Arc<AtomicU64>) {
+        let count = Arc::new(AtomicU64::new(0));
+        let writer = CountingWriter {
+            count: Arc::clone(&count),
+        };
+        (WriteStream::Rust(Box::new(writer)), count)
+    }
+}
@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What `execvp` searches when there's no `PATH` at all.
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+
+/// Why [`ChildProcess::check()`](crate::ChildProcess::check) expects starting a child to fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpawnCheckError {
+    /// The program doesn't exist: there's no such file, if it was given as a path, or nothing by
+    /// that name in any directory in `PATH`.
+    NotFound {
+        /// The program, as given to the [`Command`].
+        program: OsString,
+    },
+    /// The program exists, but isn't an executable file.
+    NotExecutable {
+        /// The program, as given to the [`Command`].
+        program: OsString,
+        /// Where it was found.
+        path: PathBuf,
+    },
+}
+
+impl Display for SpawnCheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnCheckError::NotFound { program } if is_path(program) => {
+                write!(f, "program '{}' not found", program.to_string_lossy())
+            }
+            SpawnCheckError::NotFound { program } => {
+                write!(
+                    f,
+                    "program '{}' not found in PATH",
+                    program.to_string_lossy()
+                )
+            }
+            SpawnCheckError::NotExecutable { program, path } => write!(
+                f,
+                "program '{}' found at {} but not executable",
+                program.to_string_lossy(),
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Error for SpawnCheckError {}
+
+/// Whether the program is a path to run, rather than a name to look for in `PATH`.
+fn is_path(program: &OsStr) -> bool {
+    program.as_bytes().contains(&b'/')
+}
+
+/// Find the program the command would run, the same way `execvp` does: as a path if it contains
+/// a slash, and otherwise in each directory in the command's `PATH`, or this process's if the
+/// command doesn't override it.
+pub(crate) fn check_program(cmd: &Command) -> Result<(), SpawnCheckError> {
+    let program = cmd.get_program();
+    let candidates = if is_path(program) {
+        let path = match cmd.get_current_dir() {
+            Some(dir) => dir.join(program),
+            None => PathBuf::from(program),
+        };
+        vec![path]
+    } else {
+        let path = match cmd.get_envs().find(|(key, _)| *key == "PATH") {
+            Some((_, value)) => value.map(OsStr::to_owned),
+            None => std::env::var_os("PATH"),
+        };
+        let path = path.unwrap_or_else(|| DEFAULT_PATH.into());
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .collect()
+    };
+
+    let mut not_executable = None;
+    for path in candidates {
+        match executable(&path) {
+            Some(true) => return Ok(()),
+            // Keep looking, as execvp does, but report this if nothing better turns up.
+            Some(false) => {
+                not_executable.get_or_insert(path);
+            }
+            None => (),
+        }
+    }
+    let program = program.to_owned();
+    Err(match not_executable {
+        Some(path) => SpawnCheckError::NotExecutable { program, path },
+        None => SpawnCheckError::NotFound { program },
+    })
+}
+
+/// Whether the path is an executable file, or `None` if there's nothing there.
+fn executable(path: &Path) -> Option<bool> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some(metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
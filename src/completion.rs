@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Keeps track of the threads making up a running filter, and calls the callbacks given to
+/// [`RunningFilter::on_complete()`](crate::RunningFilter::on_complete) once all of them are done.
+#[derive(Clone, Default)]
+pub(crate) struct Completion(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    callbacks: Vec<Callback>,
+}
+
+impl Completion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count something as running until the returned guard is dropped. Threads should hold the
+    /// guard for their whole lifetime so that it's dropped even if they panic.
+    pub fn guard(&self) -> CompletionGuard {
+        self.0.lock().running += 1;
+        CompletionGuard(self.clone())
+    }
+
+    /// Call `f` when everything is done, or right away if it already is.
+    pub fn on_complete(&self, f: impl FnOnce() + Send + 'static) {
+        let mut state = self.0.lock();
+        if state.running == 0 {
+            drop(state);
+            f();
+        } else {
+            state.callbacks.push(Box::new(f));
+        }
+    }
+}
+
+pub(crate) struct CompletionGuard(Completion);
+
+impl Drop for CompletionGuard {
+    fn drop(&mut self) {
+        let callbacks = {
+            let mut state = self.0 .0.lock();
+            state.running -= 1;
+            if state.running == 0 {
+                std::mem::take(&mut state.callbacks)
+            } else {
+                vec![]
+            }
+        };
+        for f in callbacks {
+            f();
+        }
+    }
+}
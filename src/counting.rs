@@ -0,0 +1,122 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::lambda::RunningLambda;
+use crate::{Filter, Lambda, LambdaError, LambdaFilter, ReadStream, WriteStream};
+
+/// How much went through a [`CountingFilter`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamCounts {
+    /// The number of bytes.
+    pub bytes: u64,
+    /// The number of buffers they came in.
+    pub chunks: u64,
+}
+
+/// An I/O filter which counts the data going through it, and returns the totals when finished.
+/// This is a [`LambdaFilter`] with the counting done for you, so with [`WriteStream::Null`] as its
+/// output it's a consumer, just reading and counting, and otherwise it passes everything on.
+pub struct CountingFilter {
+    inner: LambdaFilter<Counter>,
+}
+
+impl CountingFilter {
+    /// Create a new instance.
+    pub fn new() -> Self {
+        Self {
+            inner: LambdaFilter::new(Counter {
+                counts: StreamCounts::default(),
+                live: None,
+            }),
+        }
+    }
+
+    /// Give the filter a name, which is included in errors and used to name its thread.
+    pub fn name(self, name: impl Into<String>) -> Self {
+        Self {
+            inner: self.inner.name(name),
+        }
+    }
+
+    /// Like [`LambdaFilter::output_epipe_ok()`]: the counts are then of what got through.
+    pub fn output_epipe_ok(self, ok: bool) -> Self {
+        Self {
+            inner: self.inner.output_epipe_ok(ok),
+        }
+    }
+
+    /// Read the input into a buffer of the given size, like [`LambdaFilter::buffer_size()`].
+    pub fn buffer_size(self, bytes: usize) -> Self {
+        Self {
+            inner: self.inner.buffer_size(bytes),
+        }
+    }
+
+    /// Run the filter's thread only on the given logical CPUs, like
+    /// [`LambdaFilter::cpu_affinity()`].
+    pub fn cpu_affinity(self, cpus: &[usize]) -> Self {
+        Self {
+            inner: self.inner.cpu_affinity(cpus),
+        }
+    }
+
+    /// Give the filter's thread a stack of the given size, like [`LambdaFilter::stack_size()`].
+    pub fn stack_size(self, bytes: usize) -> Self {
+        Self {
+            inner: self.inner.stack_size(bytes),
+        }
+    }
+
+    /// Get a counter of the bytes so far, which is kept up to date while the filter runs, for
+    /// showing progress. Every call gives the same counter.
+    pub fn live_bytes(&mut self) -> Arc<AtomicU64> {
+        Arc::clone(self.inner.handler.live.get_or_insert_with(Default::default))
+    }
+
+    /// Run the filter on the current thread, copying `input` to `output` and returning the
+    /// counts, like [`LambdaFilter::run_blocking()`].
+    pub fn run_blocking(
+        self,
+        input: impl Read,
+        output: impl Write,
+    ) -> Result<StreamCounts, LambdaError<StreamCounts>> {
+        self.inner.run_blocking(input, output)
+    }
+}
+
+impl Default for CountingFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filter for CountingFilter {
+    type Running = RunningLambda<StreamCounts>;
+    type Error = io::Error;
+
+    fn start(self, input: ReadStream, output: WriteStream) -> Result<Self::Running, Self::Error> {
+        self.inner.start(input, output)
+    }
+}
+
+struct Counter {
+    counts: StreamCounts,
+    live: Option<Arc<AtomicU64>>,
+}
+
+impl Lambda for Counter {
+    type FinishResult = StreamCounts;
+
+    fn handle(&mut self, buf: &[u8]) {
+        self.counts.bytes += buf.len() as u64;
+        self.counts.chunks += 1;
+        if let Some(live) = &self.live {
+            live.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn finish(self) -> Self::FinishResult {
+        self.counts
+    }
+}
@@ -0,0 +1,53 @@
+use std::io;
+
+/// The user and groups to switch a child process to, for
+/// [`ChildProcess::uid()`](crate::ChildProcess::uid) and friends. The IDs are collected here
+/// beforehand so that switching doesn't need to allocate.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Credentials {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub groups: Option<Vec<libc::gid_t>>,
+}
+
+impl Credentials {
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.groups.is_none()
+    }
+
+    /// Switch the current process to these credentials: supplementary groups first, then the
+    /// group, then the user, since changing the user drops the privileges needed for the others.
+    /// Any failure is returned, so that the command never runs with more privileges than asked.
+    ///
+    /// This is called between fork and exec, so it must only do async-signal-safe things.
+    pub fn apply(&self) -> io::Result<()> {
+        // SAFETY: getuid can't fail and has no pointers.
+        let is_root = unsafe { libc::getuid() } == 0;
+        match &self.groups {
+            // SAFETY: FFI call with a valid pointer and length.
+            Some(groups) => check(unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) })?,
+            // Otherwise root's supplementary groups would be kept after switching users.
+            // SAFETY: FFI call with an empty list, which setgroups allows to be null.
+            None if is_root && self.uid.is_some() => {
+                check(unsafe { libc::setgroups(0, std::ptr::null()) })?
+            }
+            None => (),
+        }
+        if let Some(gid) = self.gid {
+            // SAFETY: FFI call with no pointers.
+            check(unsafe { libc::setgid(gid as libc::gid_t) })?;
+        }
+        if let Some(uid) = self.uid {
+            // SAFETY: FFI call with no pointers.
+            check(unsafe { libc::setuid(uid as libc::uid_t) })?;
+        }
+        Ok(())
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+
+use crate::process::{ChildExit, RunningChild};
+use crate::{ChildProcess, Filter, OwnedPipeEnd, ReadStream, RunningFilter, WriteStream};
+
+/// A child process whose stdin and stdout are both connected to one end of a Unix socket pair,
+/// for programs that talk in both directions. The other end of the socket is returned by
+/// [`RunningDuplex::socket()`].
+pub struct DuplexChild {
+    inner: ChildProcess,
+}
+
+impl DuplexChild {
+    /// Create a [`DuplexChild`] from the given [`Command`]. As with [`ChildProcess::new()`], don't
+    /// set up stdin or stdout of the command.
+    pub fn new(cmd: Command) -> Self {
+        Self {
+            inner: ChildProcess::new(cmd),
+        }
+    }
+
+    /// Give the filter a name, which is included in errors.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.inner = self.inner.name(name);
+        self
+    }
+
+    /// Start the child process.
+    pub fn start(self) -> io::Result<RunningDuplex> {
+        let (ours, theirs) = UnixStream::pair()?;
+        let input = ReadStream::from(theirs.try_clone()?);
+        let child = self.inner.start(input, WriteStream::from(theirs))?;
+        Ok(RunningDuplex {
+            child,
+            socket: Some(ours),
+        })
+    }
+}
+
+/// A running [`DuplexChild`].
+pub struct RunningDuplex {
+    child: RunningChild,
+    socket: Option<UnixStream>,
+}
+
+impl RunningDuplex {
+    /// Take our end of the socket. Writing to it sends data to the child's stdin, and reading from
+    /// it gets the child's stdout. Use
+    /// [`UnixStream::shutdown()`](std::os::unix::net::UnixStream::shutdown) with
+    /// [`Shutdown::Write`](std::net::Shutdown::Write) to send EOF to the child while still reading
+    /// its output.
+    ///
+    /// Returns `None` if the socket was already taken.
+    pub fn socket(&mut self) -> Option<UnixStream> {
+        self.socket.take()
+    }
+}
+
+impl RunningFilter for RunningDuplex {
+    type Result = ChildExit;
+
+    fn wait(self) -> Self::Result {
+        self.child.wait()
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.child.is_finished()
+    }
+
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static) {
+        self.child.on_complete(f)
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.child.name()
+    }
+
+    fn abort(&mut self) {
+        self.child.abort();
+        self.socket = None;
+    }
+
+    /// A duplex child doesn't use pipes; use [`RunningDuplex::socket()`] instead.
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        None
+    }
+
+    /// A duplex child doesn't use pipes; use [`RunningDuplex::socket()`] instead.
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        None
+    }
+}
@@ -0,0 +1,165 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::io;
+use std::process::ExitStatus;
+
+use crate::misc::{find_error, name_error, ThreadPanicked};
+use crate::process::{ChildExit, ChildExitError, ChildExitErrorKind};
+
+/// A single error type covering the ways any of the filters in this crate can fail, so that a
+/// chain of different filters can report errors in one way.
+#[derive(Debug)]
+pub enum IoChainError {
+    /// A child process exited unsuccessfully.
+    ChildFailed {
+        /// How the child exited.
+        status: ExitStatus,
+        /// The name of the filter, if it was given one.
+        name: Option<String>,
+        /// The end of the child's stderr, if it was captured with
+        /// [`ChildProcess::capture_stderr_tail()`](crate::ChildProcess::capture_stderr_tail).
+        stderr_tail: Option<String>,
+        /// The program the child ran.
+        program: Option<OsString>,
+    },
+
+    /// An I/O error.
+    Io(io::Error),
+
+    /// A filter thread or copy thread panicked.
+    Panicked {
+        /// What it panicked with, if it was a message.
+        message: Option<String>,
+    },
+
+    /// More than one thing went wrong.
+    Multiple(Vec<IoChainError>),
+}
+
+impl IoChainError {
+    /// Collect a list of results, like the one from a [`RunningTee`](crate::RunningTee), into a
+    /// single result.
+    pub fn collect(results: impl IntoIterator<Item = io::Result<()>>) -> Result<(), IoChainError> {
+        let errors = results
+            .into_iter()
+            .filter_map(Result::err)
+            .map(IoChainError::from)
+            .collect();
+        IoChainError::from_vec(errors)
+    }
+
+    pub(crate) fn from_vec(mut errors: Vec<IoChainError>) -> Result<(), IoChainError> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.pop().unwrap()),
+            _ => Err(IoChainError::Multiple(errors)),
+        }
+    }
+}
+
+impl From<io::Error> for IoChainError {
+    fn from(e: io::Error) -> Self {
+        match find_error::<ThreadPanicked>(&e) {
+            Some(panicked) => IoChainError::Panicked {
+                message: panicked.message.clone(),
+            },
+            None => IoChainError::Io(e),
+        }
+    }
+}
+
+impl From<ChildExitError> for IoChainError {
+    fn from(e: ChildExitError) -> Self {
+        let mut errors = vec![];
+        let mut next = Some(Box::new(e));
+        while let Some(e) = next {
+            let e = *e;
+            let name = e.name;
+            errors.push(match e.kind {
+                ChildExitErrorKind::ChildExit(status) => IoChainError::ChildFailed {
+                    status,
+                    name,
+                    stderr_tail: e.stderr_tail,
+                    program: e.program,
+                },
+                ChildExitErrorKind::ChildWait(e)
+                | ChildExitErrorKind::Waiter(e)
+                | ChildExitErrorKind::ReadThread(e)
+                | ChildExitErrorKind::WriteThread(e)
+                | ChildExitErrorKind::ErrThread(e) => {
+                    IoChainError::from(name_error(name.as_deref(), e))
+                }
+                kind @ ChildExitErrorKind::Stalled(_) => IoChainError::from(name_error(
+                    name.as_deref(),
+                    io::Error::new(io::ErrorKind::TimedOut, kind.to_string()),
+                )),
+            });
+            next = e.next;
+        }
+        match IoChainError::from_vec(errors) {
+            Err(e) => e,
+            Ok(()) => unreachable!("ChildExitError always has at least one error"),
+        }
+    }
+}
+
+impl Display for IoChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IoChainError::ChildFailed {
+                status,
+                name,
+                stderr_tail,
+                program,
+            } => {
+                if let Some(name) = name {
+                    write!(f, "filter '{name}': ")?;
+                }
+                match program {
+                    Some(program) => write!(
+                        f,
+                        "'{}' exited unsuccessfully: {status}",
+                        program.to_string_lossy()
+                    )?,
+                    None => write!(f, "child exited unsuccessfully: {status}")?,
+                }
+                if let Some(tail) = stderr_tail {
+                    write!(f, "\n   stderr: {}", tail.trim_end())?;
+                }
+                Ok(())
+            }
+            IoChainError::Io(e) => e.fmt(f),
+            IoChainError::Panicked { message } => ThreadPanicked {
+                message: message.clone(),
+            }
+            .fmt(f),
+            IoChainError::Multiple(errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str("\n   and also ")?;
+                    }
+                    e.fmt(f)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for IoChainError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IoChainError::Io(e) => Some(e),
+            IoChainError::Multiple(errors) => errors.first().map(|e| e as &(dyn Error + 'static)),
+            IoChainError::ChildFailed { .. } | IoChainError::Panicked { .. } => None,
+        }
+    }
+}
+
+impl ChildExit {
+    /// Like [`ChildExit::combine()`], but returning an [`IoChainError`].
+    pub fn into_result(self) -> Result<(), IoChainError> {
+        self.combine().map_err(IoChainError::from)
+    }
+}
@@ -0,0 +1,100 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::misc::path_error;
+
+/// A named pipe, created if it didn't already exist, and opened on first use so that starting a
+/// filter doesn't block waiting for the other end.
+pub(crate) struct LazyFifo {
+    path: PathBuf,
+    remove: bool,
+    created: bool,
+    write: bool,
+    file: Option<File>,
+}
+
+impl LazyFifo {
+    pub fn new(path: PathBuf, remove: bool, write: bool) -> io::Result<Self> {
+        let created = mkfifo(&path).map_err(|e| path_error(&path, e))?;
+        Ok(Self {
+            path,
+            remove,
+            created,
+            write,
+            file: None,
+        })
+    }
+
+    fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .read(!self.write)
+                .write(self.write)
+                .open(&self.path)
+                .map_err(|e| path_error(&self.path, e))?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+/// Create a FIFO at the given path, returning whether it was created (as opposed to already
+/// existing).
+fn mkfifo(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: FFI call with a valid NUL-terminated path.
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o666) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() != io::ErrorKind::AlreadyExists {
+        return Err(e);
+    }
+    if !std::fs::metadata(path)?.file_type().is_fifo() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "file exists and is not a FIFO",
+        ));
+    }
+    Ok(false)
+}
+
+impl Read for LazyFifo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file()?.read(buf)
+    }
+}
+
+impl Write for LazyFifo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(f) => f.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for LazyFifo {
+    fn drop(&mut self) {
+        if self.write && self.file.is_none() {
+            // Nothing was written, but a reader may be waiting for the FIFO to be opened. Open and
+            // close it without blocking so that it sees EOF.
+            let _ = OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&self.path);
+        }
+        if self.created && self.remove {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
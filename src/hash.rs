@@ -0,0 +1,106 @@
+use std::io::{self, Read, Write};
+
+use digest::{Digest, Output};
+
+use crate::lambda::RunningLambda;
+use crate::{Filter, Lambda, LambdaError, LambdaFilter, ReadStream, WriteStream};
+
+/// An I/O filter which hashes the data going through it with any [`Digest`], and returns the hash
+/// when finished. Like [`CountingFilter`](crate::CountingFilter), with [`WriteStream::Null`] as
+/// its output it's a consumer, taking the place of a command like `sha256sum`, and otherwise it
+/// passes everything on.
+pub struct HashFilter<D> {
+    inner: LambdaFilter<Hasher<D>>,
+}
+
+#[cfg(feature = "sha2")]
+impl HashFilter<sha2::Sha256> {
+    /// Create a new instance which computes a SHA-256 hash. This needs the `sha2` feature.
+    pub fn sha256() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: Digest> HashFilter<D> {
+    /// Create a new instance.
+    pub fn new() -> Self {
+        Self {
+            inner: LambdaFilter::new(Hasher(D::new())),
+        }
+    }
+
+    /// Give the filter a name, which is included in errors and used to name its thread.
+    pub fn name(self, name: impl Into<String>) -> Self {
+        Self {
+            inner: self.inner.name(name),
+        }
+    }
+
+    /// Like [`LambdaFilter::output_epipe_ok()`]: the hash is then of what got through.
+    pub fn output_epipe_ok(self, ok: bool) -> Self {
+        Self {
+            inner: self.inner.output_epipe_ok(ok),
+        }
+    }
+
+    /// Read the input into a buffer of the given size, like [`LambdaFilter::buffer_size()`].
+    pub fn buffer_size(self, bytes: usize) -> Self {
+        Self {
+            inner: self.inner.buffer_size(bytes),
+        }
+    }
+
+    /// Run the filter's thread only on the given logical CPUs, like
+    /// [`LambdaFilter::cpu_affinity()`].
+    pub fn cpu_affinity(self, cpus: &[usize]) -> Self {
+        Self {
+            inner: self.inner.cpu_affinity(cpus),
+        }
+    }
+
+    /// Give the filter's thread a stack of the given size, like [`LambdaFilter::stack_size()`].
+    pub fn stack_size(self, bytes: usize) -> Self {
+        Self {
+            inner: self.inner.stack_size(bytes),
+        }
+    }
+
+    /// Run the filter on the current thread, copying `input` to `output` and returning the hash,
+    /// like [`LambdaFilter::run_blocking()`].
+    pub fn run_blocking(
+        self,
+        input: impl Read,
+        output: impl Write,
+    ) -> Result<Output<D>, LambdaError<Output<D>>> {
+        self.inner.run_blocking(input, output)
+    }
+}
+
+impl<D: Digest> Default for HashFilter<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: Digest + Send + 'static> Filter for HashFilter<D> {
+    type Running = RunningLambda<Output<D>>;
+    type Error = io::Error;
+
+    fn start(self, input: ReadStream, output: WriteStream) -> Result<Self::Running, Self::Error> {
+        self.inner.start(input, output)
+    }
+}
+
+struct Hasher<D>(D);
+
+impl<D: Digest> Lambda for Hasher<D> {
+    type FinishResult = Output<D>;
+
+    fn handle(&mut self, buf: &[u8]) {
+        self.0.update(buf);
+    }
+
+    fn finish(self) -> Self::FinishResult {
+        self.0.finalize()
+    }
+}
@@ -1,10 +1,20 @@
-use std::io::Write;
-use std::os::fd::OwnedFd;
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+use std::io::{Read, Write};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::JoinHandle;
-use std::{io, thread};
 
-use crate::misc::{read_stream, write_stream, ThreadPanicked};
-use crate::{Filter, ReadStream, RunningFilter, WriteStream};
+use crate::affinity::CpuSet;
+use crate::completion::Completion;
+use crate::misc::{
+    copy_epipe_ok, is_error, name_error, read_stream_fd, spawn_thread_sized, write_stream, Aborted,
+    ThreadPanicked,
+};
+use crate::stop::{StopHandle, StopReader};
+use crate::{Filter, OwnedPipeEnd, ReadStream, RunningFilter, WriteStream};
 
 /// A transparent operation to be performed on a stream of data.
 pub trait Lambda: Sized {
@@ -18,7 +28,7 @@ pub trait Lambda: Sized {
     fn finish(self) -> Self::FinishResult;
 }
 
-impl<F: FnMut(&[u8]) + Send> Lambda for F {
+impl<F: FnMut(&[u8])> Lambda for F {
     type FinishResult = ();
 
     fn handle(&mut self, buf: &[u8]) {
@@ -28,85 +38,1152 @@ impl<F: FnMut(&[u8]) + Send> Lambda for F {
     fn finish(self) -> Self::FinishResult {}
 }
 
+/// Like [`Lambda`], but the handler is also told where each buffer starts in the stream, counting
+/// from 0. Use it with [`LambdaFilter::with_offsets()`].
+pub trait OffsetLambda: Sized {
+    /// The result from calling [`OffsetLambda::finish()`] when the stream is done.
+    type FinishResult: Send;
+
+    /// Do something with a buffer of data, which starts `offset` bytes into the stream.
+    fn handle(&mut self, offset: u64, buf: &[u8]);
+
+    /// Called when the stream is finished, with its total length.
+    fn finish(self, len: u64) -> Self::FinishResult;
+}
+
+impl<F: FnMut(u64, &[u8])> OffsetLambda for F {
+    type FinishResult = ();
+
+    fn handle(&mut self, offset: u64, buf: &[u8]) {
+        (self)(offset, buf);
+    }
+
+    fn finish(self, _len: u64) -> Self::FinishResult {}
+}
+
+/// Keeps track of the offset for an [`OffsetLambda`], making it a [`Lambda`].
+pub struct WithOffsets<L> {
+    inner: L,
+    offset: u64,
+}
+
+impl<L: OffsetLambda> Lambda for WithOffsets<L> {
+    type FinishResult = L::FinishResult;
+
+    fn handle(&mut self, buf: &[u8]) {
+        self.inner.handle(self.offset, buf);
+        self.offset += buf.len() as u64;
+    }
+
+    fn finish(self) -> Self::FinishResult {
+        self.inner.finish(self.offset)
+    }
+}
+
+/// Like [`Lambda`], but the handler can fail, for example when it finds data it can't accept, or
+/// stop the stream early, for example once it has seen all it needs to.
+///
+/// A failure stops the filter: it stops copying, closes its input and output so that the filters
+/// around it see a broken pipe and end-of-file, and returns a [`LambdaFailed`] error from
+/// [`RunningFilter::wait()`], without calling [`TryLambda::finish()`]. Failing in
+/// [`TryLambda::finish()`] gives the same error, but only after the stream has ended. Returning
+/// [`ControlFlow::Break`] stops the filter the same way, like `head` does in a shell pipeline, but
+/// then [`TryLambda::finish()`] is called as usual, with [`StreamEnd::Stopped`].
+///
+/// Every [`Lambda`] is a [`TryLambda`] which never fails or stops early.
+pub trait TryLambda: Sized {
+    /// The result from calling [`TryLambda::finish()`] when the stream is done.
+    type FinishResult: Send;
+
+    /// Do something with a buffer of data, and say whether to carry on. The buffer has already
+    /// been passed on to the output, so the next filter has it even if this fails or stops, unless
+    /// the filter is set to [`observe_before_write()`](LambdaFilter::observe_before_write).
+    fn handle(&mut self, buf: &[u8]) -> io::Result<ControlFlow<()>>;
+
+    /// Called when the stream is finished, with how it ended. The input and output are already
+    /// closed by then.
+    fn finish(self, end: StreamEnd) -> io::Result<Self::FinishResult>;
+
+    /// Called before any data is passed on, to write something ahead of it, such as a header. If
+    /// this fails, the filter fails without passing anything on or calling
+    /// [`TryLambda::finish()`]. By default, nothing is written.
+    fn prologue(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called after the last buffer, to write something after the data, such as a trailer. This
+    /// is only called if the stream ended with [`StreamEnd::Eof`] or [`StreamEnd::Stopped`], and
+    /// comes before [`TryLambda::finish()`]; if it fails, that's an error in the stream, as if
+    /// writing the data had failed. By default, nothing is written.
+    fn epilogue(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<L: Lambda> TryLambda for L {
+    type FinishResult = L::FinishResult;
+
+    fn handle(&mut self, buf: &[u8]) -> io::Result<ControlFlow<()>> {
+        Lambda::handle(self, buf);
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn finish(self, _end: StreamEnd) -> io::Result<Self::FinishResult> {
+        Ok(Lambda::finish(self))
+    }
+}
+
+/// How the stream through a [`TryLambda`] ended, for [`TryLambda::finish()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEnd {
+    /// All the input went through.
+    Eof,
+    /// [`TryLambda::handle()`] returned [`ControlFlow::Break`].
+    Stopped,
+    /// Whatever reads the output closed it early, and the filter was set up to allow that with
+    /// [`LambdaFilter::output_epipe_ok()`].
+    OutputClosed,
+    /// Reading or writing failed, or the filter was aborted. The result is returned along with
+    /// the error, in [`LambdaError::partial`].
+    Error,
+}
+
+/// An error from a [`LambdaFilter`], along with the lambda's result, if it got one.
+#[derive(Debug)]
+pub struct LambdaError<R> {
+    /// What went wrong.
+    pub error: io::Error,
+    /// What [`TryLambda::finish()`] returned, if it was called. After reading or writing fails,
+    /// it's called with [`StreamEnd::Error`], so that whatever the lambda made of the stream up to
+    /// then isn't lost. It isn't called if the lambda itself failed ([`LambdaFailed`]), if its
+    /// thread panicked, or if the thread was left behind by [`RunningFilter::abort()`].
+    pub partial: Option<R>,
+}
+
+impl<R> LambdaError<R> {
+    pub(crate) fn named(self, name: Option<&str>) -> Self {
+        Self {
+            error: name_error(name, self.error),
+            partial: self.partial,
+        }
+    }
+}
+
+impl<R> From<io::Error> for LambdaError<R> {
+    fn from(error: io::Error) -> Self {
+        Self {
+            error,
+            partial: None,
+        }
+    }
+}
+
+impl<R> From<LambdaError<R>> for io::Error {
+    fn from(e: LambdaError<R>) -> Self {
+        e.error
+    }
+}
+
+impl<R> Display for LambdaError<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<R: std::fmt::Debug> Error for LambdaError<R> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Returned from a [`LambdaFilter`] whose [`TryLambda::handle()`] failed, wrapping the error it
+/// returned, so that it can be told apart from errors reading or writing the streams.
+#[derive(Debug)]
+pub struct LambdaFailed(pub io::Error);
+
+impl LambdaFailed {
+    /// Check whether an error is a [`LambdaFailed`] error.
+    pub fn is(e: &io::Error) -> bool {
+        is_error::<LambdaFailed>(e)
+    }
+}
+
+impl Display for LambdaFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "lambda failed: {}", self.0)
+    }
+}
+
+impl Error for LambdaFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// What [`LineLambda`] does with a line longer than its maximum length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongLines {
+    /// Pass the line on in pieces of the maximum length, and then whatever is left.
+    Split,
+    /// Fail with [`InvalidData`](io::ErrorKind::InvalidData), which stops the filter.
+    Fail,
+}
+
+/// Splits the stream into lines, or other records, and calls a closure on each one, without the
+/// separator. A line can straddle any number of buffers, and so can the separator; the line is
+/// held back until the rest of it comes. A last line without a separator is passed on at the end
+/// of the stream.
+///
+/// It goes in a [`LambdaFilter`] like any other lambda:
+/// `LambdaFilter::new(LineLambda::new(|line| ...))`.
+pub struct LineLambda<F> {
+    callback: F,
+    separator: Vec<u8>,
+    partial: Vec<u8>,
+    max_len: Option<(usize, LongLines)>,
+}
+
+impl<F: FnMut(&[u8])> LineLambda<F> {
+    /// Call the given closure on each line.
+    pub fn new(callback: F) -> Self {
+        Self {
+            callback,
+            separator: b"\n".to_vec(),
+            partial: vec![],
+            max_len: None,
+        }
+    }
+
+    /// Split records on the given separator, such as `b"\r\n"`, instead of a newline. It must not
+    /// be empty; if it is, the filter fails with [`InvalidInput`](io::ErrorKind::InvalidInput).
+    pub fn separator(mut self, separator: impl Into<Vec<u8>>) -> Self {
+        self.separator = separator.into();
+        self
+    }
+
+    /// Split records on NUL bytes, as from `find -print0`, instead of newlines.
+    pub fn nul_separated(self) -> Self {
+        self.separator(b"\0".to_vec())
+    }
+
+    /// Don't hold more than `len` bytes of a line, but deal with longer lines as `policy` says. By
+    /// default, there's no limit, and a stream without separators is held in memory until the
+    /// end.
+    pub fn max_line_len(mut self, len: usize, policy: LongLines) -> Self {
+        self.max_len = Some((len.max(1), policy));
+        self
+    }
+
+    /// Pass on a complete line, in pieces if it's too long.
+    fn line(&mut self, line: &[u8]) -> io::Result<()> {
+        match self.max_len {
+            Some((max, policy)) if line.len() > max => {
+                if policy == LongLines::Fail {
+                    return Err(too_long(max));
+                }
+                line.chunks(max).for_each(&mut self.callback);
+            }
+            _ => (self.callback)(line),
+        }
+        Ok(())
+    }
+
+    /// Check the length of the line held back, passing on what it can if it's too long.
+    fn hold(&mut self) -> io::Result<()> {
+        let Some((max, policy)) = self.max_len else {
+            return Ok(());
+        };
+        // The end of what's held might be the start of a separator, and so not part of the line.
+        let pending = (1..self.separator.len())
+            .rev()
+            .find(|&n| self.partial.ends_with(&self.separator[..n]))
+            .unwrap_or(0);
+        let len = self.partial.len() - pending;
+        if len > max {
+            if policy == LongLines::Fail {
+                return Err(too_long(max));
+            }
+            // Hold on to the last piece, which might be all that's left of the line, so that an
+            // empty piece isn't passed on when the separator comes.
+            let whole = (len - 1) / max * max;
+            self.partial[..whole]
+                .chunks(max)
+                .for_each(&mut self.callback);
+            self.partial.drain(..whole);
+        }
+        Ok(())
+    }
+}
+
+fn too_long(max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line longer than {max} bytes"),
+    )
+}
+
+/// Find the first place `needle` occurs in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if let [byte] = needle {
+        return haystack.iter().position(|b| b == byte);
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+impl<F: FnMut(&[u8])> TryLambda for LineLambda<F> {
+    type FinishResult = ();
+
+    fn handle(&mut self, mut buf: &[u8]) -> io::Result<ControlFlow<()>> {
+        let sep_len = self.separator.len();
+        if sep_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty line separator",
+            ));
+        }
+        if !self.partial.is_empty() {
+            // A separator which starts in what's held back can only finish in the first few bytes
+            // of this buffer.
+            let held = self.partial.len();
+            let start = held.saturating_sub(sep_len - 1);
+            self.partial
+                .extend_from_slice(&buf[..buf.len().min(sep_len - 1)]);
+            let straddling = find(&self.partial[start..], &self.separator).map(|i| start + i);
+            self.partial.truncate(held);
+            let end = straddling.or_else(|| find(buf, &self.separator).map(|i| held + i));
+            let Some(end) = end else {
+                self.partial.extend_from_slice(buf);
+                self.hold()?;
+                return Ok(ControlFlow::Continue(()));
+            };
+            let mut line = std::mem::take(&mut self.partial);
+            line.truncate(end);
+            line.extend_from_slice(&buf[..end.saturating_sub(held)]);
+            buf = &buf[end + sep_len - held..];
+            self.line(&line)?;
+            // Keep the allocation.
+            line.clear();
+            self.partial = line;
+        }
+        while let Some(i) = find(buf, &self.separator) {
+            self.line(&buf[..i])?;
+            buf = &buf[i + sep_len..];
+        }
+        self.partial.extend_from_slice(buf);
+        self.hold()?;
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn finish(mut self, _end: StreamEnd) -> io::Result<Self::FinishResult> {
+        let partial = std::mem::take(&mut self.partial);
+        if !partial.is_empty() {
+            self.line(&partial)?;
+        }
+        Ok(())
+    }
+}
+
+/// What [`Utf8Lambda`] does with bytes which aren't valid UTF-8.
+pub enum InvalidUtf8 {
+    /// Fail with [`InvalidData`](io::ErrorKind::InvalidData), which stops the filter.
+    Fail,
+    /// Pass on a replacement character, `U+FFFD`, for each invalid sequence, as
+    /// [`String::from_utf8_lossy()`] does.
+    Replace,
+    /// Pass each invalid sequence to this closure instead.
+    #[allow(clippy::type_complexity)] // spelled out so the docs show what it takes
+    Bytes(Box<dyn FnMut(&[u8]) + Send>),
+}
+
+/// Decodes the stream as UTF-8, and calls a closure on each run of text. A character can be split
+/// between buffers; the start of it is held back until the rest comes. What happens to invalid
+/// bytes, including an unfinished character at the end of the stream, is up to the
+/// [`InvalidUtf8`] policy, which by default is to fail.
+///
+/// It goes in a [`LambdaFilter`] like any other lambda:
+/// `LambdaFilter::new(Utf8Lambda::new(|text| ...))`.
+pub struct Utf8Lambda<F> {
+    callback: F,
+    invalid: InvalidUtf8,
+    partial: Vec<u8>,
+}
+
+impl<F: FnMut(&str)> Utf8Lambda<F> {
+    /// Call the given closure on each run of text.
+    pub fn new(callback: F) -> Self {
+        Self {
+            callback,
+            invalid: InvalidUtf8::Fail,
+            partial: vec![],
+        }
+    }
+
+    /// Deal with invalid bytes as the given policy says.
+    pub fn invalid(mut self, policy: InvalidUtf8) -> Self {
+        self.invalid = policy;
+        self
+    }
+
+    fn text(&mut self, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            // SAFETY: only called with what from_utf8 said was valid.
+            (self.callback)(unsafe { std::str::from_utf8_unchecked(bytes) });
+        }
+    }
+
+    fn invalid_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match &mut self.invalid {
+            InvalidUtf8::Fail => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "stream is not valid UTF-8",
+                ))
+            }
+            InvalidUtf8::Replace => (self.callback)("\u{FFFD}"),
+            InvalidUtf8::Bytes(f) => f(bytes),
+        }
+        Ok(())
+    }
+
+    /// Pass on the character held back, if this buffer finishes it. Returns how much of the
+    /// buffer that took, or `None` if it still isn't finished.
+    fn finish_partial(&mut self, buf: &[u8]) -> io::Result<Option<usize>> {
+        let held = self.partial.len();
+        let mut bytes = std::mem::take(&mut self.partial);
+        bytes.extend_from_slice(&buf[..buf.len().min(4 - held)]);
+        let len = match std::str::from_utf8(&bytes) {
+            Ok(text) => text.chars().next().map_or(0, char::len_utf8),
+            Err(e) if e.valid_up_to() > 0 => {
+                // SAFETY: from_utf8 said this much was valid.
+                let text = unsafe { std::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) };
+                text.chars().next().map_or(0, char::len_utf8)
+            }
+            Err(e) => match e.error_len() {
+                Some(len) => {
+                    self.invalid_bytes(&bytes[..len])?;
+                    return Ok(Some(len.saturating_sub(held)));
+                }
+                None => {
+                    self.partial = bytes;
+                    return Ok(None);
+                }
+            },
+        };
+        self.text(&bytes[..len]);
+        Ok(Some(len - held))
+    }
+}
+
+impl<F: FnMut(&str)> TryLambda for Utf8Lambda<F> {
+    type FinishResult = ();
+
+    fn handle(&mut self, mut buf: &[u8]) -> io::Result<ControlFlow<()>> {
+        if !self.partial.is_empty() {
+            match self.finish_partial(buf)? {
+                Some(n) => buf = &buf[n..],
+                None => return Ok(ControlFlow::Continue(())),
+            }
+        }
+        loop {
+            match std::str::from_utf8(buf) {
+                Ok(_) => {
+                    self.text(buf);
+                    break;
+                }
+                Err(e) => {
+                    let (valid, rest) = buf.split_at(e.valid_up_to());
+                    self.text(valid);
+                    match e.error_len() {
+                        Some(len) => {
+                            self.invalid_bytes(&rest[..len])?;
+                            buf = &rest[len..];
+                        }
+                        None => {
+                            self.partial = rest.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn finish(mut self, _end: StreamEnd) -> io::Result<Self::FinishResult> {
+        let partial = std::mem::take(&mut self.partial);
+        if !partial.is_empty() {
+            self.invalid_bytes(&partial)?;
+        }
+        Ok(())
+    }
+}
+
+/// Passes only some of the buffers on to another [`Lambda`], for watching a stream which is too
+/// fast to look at all of. Which ones are picked depends only on the sizes of the buffers, so the
+/// same stream read the same way is always sampled the same way. The whole stream still goes
+/// through the filter.
+///
+/// When finished, it returns the inner lambda's result along with how much it was given.
+pub struct Sampling<L> {
+    inner: L,
+    interval: SampleInterval,
+    counts: SampleCounts,
+    buffers: u64,
+}
+
+enum SampleInterval {
+    Bytes(u64),
+    Buffers(u64),
+}
+
+/// How much of the stream a [`Sampling`] lambda saw.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SampleCounts {
+    /// The length of the whole stream.
+    pub total: u64,
+    /// How many bytes were given to the inner lambda.
+    pub sampled: u64,
+}
+
+impl<L: Lambda> Sampling<L> {
+    /// Sample the buffer which contains every `n`th byte of the stream, starting with the first.
+    /// If one buffer contains several, it's only given once. An interval of 0 is taken as 1.
+    pub fn every_bytes(n: u64, inner: L) -> Self {
+        Self::new(SampleInterval::Bytes(n.max(1)), inner)
+    }
+
+    /// Sample every `n`th buffer, starting with the first. An interval of 0 is taken as 1.
+    pub fn every_nth(n: u64, inner: L) -> Self {
+        Self::new(SampleInterval::Buffers(n.max(1)), inner)
+    }
+
+    fn new(interval: SampleInterval, inner: L) -> Self {
+        Self {
+            inner,
+            interval,
+            counts: SampleCounts::default(),
+            buffers: 0,
+        }
+    }
+}
+
+impl<L: Lambda> Lambda for Sampling<L> {
+    type FinishResult = (L::FinishResult, SampleCounts);
+
+    fn handle(&mut self, buf: &[u8]) {
+        let start = self.counts.total;
+        let end = start + buf.len() as u64;
+        let sample = match self.interval {
+            // Whether a multiple of n falls in [start, end).
+            SampleInterval::Bytes(n) => start.div_ceil(n) * n < end,
+            SampleInterval::Buffers(n) => self.buffers.is_multiple_of(n),
+        };
+        self.counts.total = end;
+        self.buffers += 1;
+        if sample {
+            self.counts.sampled += buf.len() as u64;
+            self.inner.handle(buf);
+        }
+    }
+
+    fn finish(self) -> Self::FinishResult {
+        (self.inner.finish(), self.counts)
+    }
+}
+
 /// An I/O filter which runs a closure of Rust code on each buffer, but otherwise does not alter the
 /// data stream.
+///
+/// Started with [`WriteStream::Null`] as its output, it's just a consumer: it reads straight into
+/// the handler without passing anything on. The handler sees exactly the same buffers, and is
+/// finished the same way at the end, as it would with an output.
 pub struct LambdaFilter<F> {
-    handler: F,
+    pub(crate) handler: F,
+    pub(crate) name: Option<String>,
+    pub(crate) output_epipe_ok: bool,
+    pub(crate) cpu_affinity: Option<Vec<usize>>,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) buffer_size: Option<usize>,
+    pub(crate) chunk_size: Option<usize>,
+    pub(crate) observe_before_write: bool,
+    pub(crate) whole_buffers: bool,
+    pub(crate) stop: Option<StopHandle>,
 }
 
-impl<F: Lambda> LambdaFilter<F> {
+impl<L: OffsetLambda> LambdaFilter<WithOffsets<L>> {
+    /// Create a new instance from a closure which is passed each buffer along with its offset in
+    /// the stream. The offsets count the same bytes the handler is given, so each one starts where
+    /// the last one ended.
+    pub fn with_offsets(handler: L) -> Self {
+        Self::new(WithOffsets {
+            inner: handler,
+            offset: 0,
+        })
+    }
+}
+
+impl<F: TryLambda> LambdaFilter<F> {
     /// Create a new instance from a given closure. The closure will be invoked on each buffer that
     /// is forwarded through the filter.
     pub fn new(handler: F) -> Self {
-        Self { handler }
+        Self {
+            handler,
+            name: None,
+            output_epipe_ok: false,
+            cpu_affinity: None,
+            stack_size: None,
+            buffer_size: None,
+            chunk_size: None,
+            observe_before_write: false,
+            whole_buffers: false,
+            stop: None,
+        }
+    }
+
+    /// Give the filter a name, which is included in errors and used to name its thread.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// If whatever reads the filter's output stops before the end (as `head` does), finish
+    /// normally instead of failing with [`BrokenPipe`](io::ErrorKind::BrokenPipe). The lambda only
+    /// sees the data which got through. By default, the error is returned.
+    pub fn output_epipe_ok(mut self, ok: bool) -> Self {
+        self.output_epipe_ok = ok;
+        self
+    }
+
+    /// Read the input into a buffer of the given size, instead of the default of 8 KiB, so that
+    /// the lambda can be given bigger pieces at a time. It's still given less whenever a read or
+    /// write comes up short.
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = Some(bytes);
+        self
+    }
+
+    /// Give the lambda the data in blocks of exactly the given size, holding back what's left
+    /// over from each read until there's enough for another. The last block may be shorter: it's
+    /// given to [`TryLambda::handle()`] at the end of the stream, just before
+    /// [`TryLambda::epilogue()`], or before [`TryLambda::finish()`] if the stream ended some
+    /// other way. Only what the lambda sees is chunked; the data is still passed on as it's read.
+    /// A size of 0 is taken as 1.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = Some(bytes.max(1));
+        self
+    }
+
+    /// Give the lambda each buffer as soon as it's read, before passing it on, rather than after.
+    /// Then the lambda sees everything read from the input, even what the output didn't take
+    /// before failing, so a hash or count matches what was consumed; but it may include bytes that
+    /// never reached the next filter. If [`TryLambda::handle()`] fails, the buffer isn't passed on
+    /// at all. By default, the lambda only sees what was passed on.
+    pub fn observe_before_write(mut self, before: bool) -> Self {
+        self.observe_before_write = before;
+        self
+    }
+
+    /// Pass each buffer on in full before giving it to the lambda, so that it's given each buffer
+    /// as it was read, in one piece, however little the output takes at a time. If writing fails
+    /// partway, the lambda is given just the part that was passed on, if any, before the error
+    /// ends the stream. By default, the lambda is given each piece as soon as the output takes
+    /// it. With [`LambdaFilter::observe_before_write()`], buffers are always given whole.
+    pub fn whole_buffers(mut self, whole: bool) -> Self {
+        self.whole_buffers = whole;
+        self
+    }
+
+    /// Run the filter's thread only on the given logical CPUs, numbered from 0. They're checked
+    /// against the number in the system when the filter is started. This needs Linux; elsewhere,
+    /// starting the filter fails with [`Unsupported`](io::ErrorKind::Unsupported).
+    /// [`LambdaFilter::run_blocking()`] ignores this, since it runs on the caller's thread.
+    pub fn cpu_affinity(mut self, cpus: &[usize]) -> Self {
+        self.cpu_affinity = Some(cpus.to_vec());
+        self
+    }
+
+    /// Give the filter's thread a stack of the given size, instead of Rust's default, for a lambda
+    /// which keeps a lot on the stack. [`LambdaFilter::run_blocking()`] ignores this too.
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Get a handle which can stop the filter from another thread once it's started, keeping the
+    /// lambda's result. Every call gives a handle to the same filter.
+    pub fn stop_handle(&mut self) -> StopHandle {
+        self.stop.get_or_insert_with(StopHandle::default).clone()
+    }
+
+    /// Run the filter on the current thread, copying `input` to `output` and returning the result
+    /// of [`TryLambda::finish()`]. This works just like starting the filter and waiting for it, but
+    /// neither the streams nor the lambda need to be [`Send`].
+    pub fn run_blocking(
+        self,
+        input: impl Read,
+        output: impl Write,
+    ) -> Result<F::FinishResult, LambdaError<F::FinishResult>> {
+        let opts = self.run_opts();
+        let aborted = Arc::new(AtomicBool::new(false));
+        match self.stop {
+            Some(stop) => {
+                let input = StopReader::new(input, None, stop)
+                    .map_err(|e| name_error(self.name.as_deref(), e))?;
+                run(self.handler, input, output, opts, aborted)
+            }
+            None => run(self.handler, input, output, opts, aborted),
+        }
+        .map_err(|e| e.named(self.name.as_deref()))
+    }
+
+    pub(crate) fn run_opts(&self) -> RunOpts {
+        RunOpts {
+            output_epipe_ok: self.output_epipe_ok,
+            buffer_size: self.buffer_size,
+            chunk_size: self.chunk_size,
+            observe_before_write: self.observe_before_write,
+            whole_buffers: self.whole_buffers,
+            stop: self.stop.clone(),
+            consume_only: false,
+        }
     }
 }
 
-impl<F: Lambda + Send + 'static> Filter for LambdaFilter<F> {
+impl<F: TryLambda + Send + 'static> Filter for LambdaFilter<F> {
     type Running = RunningLambda<F::FinishResult>;
     type Error = io::Error;
 
     fn start(self, input: ReadStream, output: WriteStream) -> Result<Self::Running, Self::Error> {
-        let (mut input_rx, input_tx) = read_stream(input)?;
-        let (output_tx, output_rx) = write_stream(output)?;
+        let opts = self.run_opts().for_output(&output);
+        let handler = self.handler;
+        let thread = LambdaThread {
+            name: self.name,
+            kind: "lambda",
+            cpu_affinity: self.cpu_affinity.as_deref(),
+            stack_size: self.stack_size,
+        };
+        RunningLambda::spawn(
+            thread,
+            self.stop,
+            input,
+            output,
+            move |input, output, aborted| run(handler, input, output, opts, aborted),
+        )
+    }
+}
 
-        let handle = thread::spawn(move || {
-            let mut shim = Shim {
-                handler: self.handler,
-                next_write: output_tx,
+/// Copy `input` to `output` through the handler, then finish it. This is the body of the thread
+/// started for a lambda filter.
+pub(crate) fn run<F: TryLambda>(
+    handler: F,
+    input: impl Read,
+    output: impl Write,
+    opts: RunOpts,
+    aborted: Arc<AtomicBool>,
+) -> Result<F::FinishResult, LambdaError<F::FinishResult>> {
+    match opts.chunk_size {
+        Some(size) => {
+            let handler = Chunked {
+                inner: handler,
+                size,
+                pending: Vec::with_capacity(size),
             };
-            io::copy(&mut input_rx, &mut shim)?;
-            Ok(shim.handler.finish())
-        });
-        Ok(RunningLambda {
-            handle,
-            input_pipe: input_tx.map(Into::into),
-            output_pipe: output_rx.map(Into::into),
-        })
+            run_handler(handler, input, output, opts, aborted)
+        }
+        None => run_handler(handler, input, output, opts, aborted),
+    }
+}
+
+fn run_handler<F: TryLambda>(
+    handler: F,
+    mut input: impl Read,
+    output: impl Write,
+    opts: RunOpts,
+    aborted: Arc<AtomicBool>,
+) -> Result<F::FinishResult, LambdaError<F::FinishResult>> {
+    let (handler, end, error) = if opts.consume_only {
+        drop(output);
+        consume(handler, &mut input, opts.buffer_size, &aborted)?
+    } else {
+        forward(handler, &mut input, output, &opts, aborted)?
+    };
+    // Stopping by the handle looks like the end of the input to the copy.
+    let end = match &opts.stop {
+        Some(stop) if end == StreamEnd::Eof && stop.is_stopped() => StreamEnd::Stopped,
+        _ => end,
+    };
+    // Let the filters on either side see the end before finishing, which might take a while.
+    drop(input);
+    let result = handler
+        .finish(end)
+        .map_err(|e| io::Error::other(LambdaFailed(e)));
+    match error {
+        Some(error) => Err(LambdaError {
+            error,
+            partial: result.ok(),
+        }),
+        None => Ok(result?),
+    }
+}
+
+/// Copy `input` to `output` through the handler, and say how the stream ended, or fail if the
+/// handler did.
+fn forward<F: TryLambda>(
+    handler: F,
+    input: &mut impl Read,
+    output: impl Write,
+    opts: &RunOpts,
+    aborted: Arc<AtomicBool>,
+) -> io::Result<(F, StreamEnd, Option<io::Error>)> {
+    let mut shim = Shim {
+        handler,
+        next_write: output,
+        aborted,
+        observe_first: opts.observe_before_write,
+        whole_buffers: opts.whole_buffers,
+        stopped: false,
+    };
+    match shim.handler.prologue(&mut shim.next_write) {
+        Ok(()) => (),
+        Err(e) if opts.output_epipe_ok && e.kind() == io::ErrorKind::BrokenPipe => {
+            return Ok((shim.handler, StreamEnd::OutputClosed, None));
+        }
+        Err(e) => return Err(e),
+    }
+    let result = copy_epipe_ok(input, &mut shim, opts.output_epipe_ok, opts.buffer_size);
+    let (end, error) = match result {
+        _ if shim.aborted.load(Ordering::SeqCst) => (StreamEnd::Error, Some(Aborted::ioerr())),
+        _ if shim.stopped => (StreamEnd::Stopped, None),
+        Ok((_, false)) => (StreamEnd::Eof, None),
+        Ok((_, true)) => (StreamEnd::OutputClosed, None),
+        // The lambda is in no state to finish.
+        Err(e) if LambdaFailed::is(&e) => return Err(e),
+        Err(e) => (StreamEnd::Error, Some(e)),
+    };
+    // Make sure everything passed on has really gone out, in case the output buffers it.
+    let (end, error) = match end {
+        StreamEnd::Eof | StreamEnd::Stopped => match shim
+            .handler
+            .epilogue(&mut shim.next_write)
+            .and_then(|()| shim.next_write.flush())
+        {
+            Ok(()) => (end, error),
+            Err(e) if LambdaFailed::is(&e) => return Err(e),
+            Err(e) if opts.output_epipe_ok && e.kind() == io::ErrorKind::BrokenPipe => {
+                (StreamEnd::OutputClosed, None)
+            }
+            Err(e) => (StreamEnd::Error, Some(e)),
+        },
+        _ => (end, error),
+    };
+    Ok((shim.handler, end, error))
+}
+
+/// Like [`forward()`], for when the output goes nowhere: just hand the handler each buffer as it's
+/// read, without the shim in the way.
+fn consume<F: TryLambda>(
+    mut handler: F,
+    input: &mut impl Read,
+    buffer_size: Option<usize>,
+    aborted: &AtomicBool,
+) -> io::Result<(F, StreamEnd, Option<io::Error>)> {
+    // The same size io::copy uses.
+    let mut stack_buf = [0; 8 * 1024];
+    let mut heap_buf;
+    let buf = match buffer_size {
+        Some(size) => {
+            heap_buf = vec![0; size.max(1)];
+            &mut heap_buf[..]
+        }
+        None => &mut stack_buf[..],
+    };
+    handler.prologue(&mut io::sink())?;
+    let end = loop {
+        if aborted.load(Ordering::SeqCst) {
+            return Ok((handler, StreamEnd::Error, Some(Aborted::ioerr())));
+        }
+        let n = match input.read(buf) {
+            Ok(0) => break StreamEnd::Eof,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Ok((handler, StreamEnd::Error, Some(e))),
+        };
+        let flow = handler
+            .handle(&buf[..n])
+            .map_err(|e| io::Error::other(LambdaFailed(e)))?;
+        if flow.is_break() {
+            break StreamEnd::Stopped;
+        }
+    };
+    match handler.epilogue(&mut io::sink()) {
+        Ok(()) => Ok((handler, end, None)),
+        Err(e) if LambdaFailed::is(&e) => Err(e),
+        Err(e) => Ok((handler, StreamEnd::Error, Some(e))),
+    }
+}
+
+/// Gives the handler its data in blocks of a fixed size, for [`LambdaFilter::chunk_size()`].
+struct Chunked<F> {
+    inner: F,
+    size: usize,
+    pending: Vec<u8>,
+}
+
+impl<F: TryLambda> Chunked<F> {
+    /// Give the handler whatever is left over at the end, as a short block.
+    fn handle_rest(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let rest = std::mem::take(&mut self.pending);
+            // There's nothing left to stop.
+            let _ = self.inner.handle(&rest)?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: TryLambda> TryLambda for Chunked<F> {
+    type FinishResult = F::FinishResult;
+
+    fn handle(&mut self, mut buf: &[u8]) -> io::Result<ControlFlow<()>> {
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.size - self.pending.len());
+            self.pending.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+            if self.pending.len() < self.size {
+                return Ok(ControlFlow::Continue(()));
+            }
+            let flow = self.inner.handle(&self.pending)?;
+            self.pending.clear();
+            if flow.is_break() {
+                return Ok(flow);
+            }
+        }
+        let mut chunks = buf.chunks_exact(self.size);
+        for chunk in &mut chunks {
+            let flow = self.inner.handle(chunk)?;
+            if flow.is_break() {
+                return Ok(flow);
+            }
+        }
+        self.pending.extend_from_slice(chunks.remainder());
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn finish(mut self, end: StreamEnd) -> io::Result<Self::FinishResult> {
+        // If the stream ended badly, the epilogue wasn't called, so this hasn't been done yet.
+        self.handle_rest()?;
+        self.inner.finish(end)
+    }
+
+    fn prologue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.inner.prologue(out)
+    }
+
+    fn epilogue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.handle_rest()
+            .map_err(|e| io::Error::other(LambdaFailed(e)))?;
+        self.inner.epilogue(out)
+    }
+}
+
+/// Options for [`run()`], from the [`LambdaFilter`].
+#[derive(Debug, Clone)]
+pub(crate) struct RunOpts {
+    output_epipe_ok: bool,
+    buffer_size: Option<usize>,
+    chunk_size: Option<usize>,
+    observe_before_write: bool,
+    whole_buffers: bool,
+    stop: Option<StopHandle>,
+    consume_only: bool,
+}
+
+impl RunOpts {
+    /// Note whether the output is [`WriteStream::Null`], so there's no need to pass anything on.
+    pub(crate) fn for_output(mut self, output: &WriteStream) -> Self {
+        self.consume_only = matches!(output, WriteStream::Null);
+        self
     }
 }
 
 struct Shim<F, W> {
     handler: F,
     next_write: W,
+    aborted: Arc<AtomicBool>,
+    observe_first: bool,
+    whole_buffers: bool,
+    stopped: bool,
 }
 
-impl<F: Lambda, W: Write> Write for Shim<F, W> {
+impl<F: TryLambda, W> Shim<F, W> {
+    fn handle(&mut self, buf: &[u8]) -> io::Result<()> {
+        let flow = self
+            .handler
+            .handle(buf)
+            .map_err(|e| io::Error::other(LambdaFailed(e)))?;
+        self.stopped = flow.is_break();
+        Ok(())
+    }
+}
+
+impl<F: TryLambda, W: Write> Write for Shim<F, W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.next_write.write(buf) {
-            Ok(n) => {
-                // Only process the bytes which were successfully forwarded.
-                self.handler.handle(&buf[0..n]);
-                Ok(n)
+        if self.aborted.load(Ordering::SeqCst) {
+            return Err(Aborted::ioerr());
+        }
+        let n = if self.observe_first {
+            // Take the whole buffer, so the lambda doesn't see any of it twice.
+            self.handle(buf)?;
+            self.next_write.write_all(buf)?;
+            buf.len()
+        } else if self.whole_buffers {
+            let (n, result) = write_prefix(&mut self.next_write, buf);
+            if n > 0 {
+                self.handle(&buf[..n])?;
             }
-            Err(e) => Err(e),
+            result?;
+            n
+        } else {
+            // Only process the bytes which were successfully forwarded.
+            let n = self.next_write.write(buf)?;
+            self.handle(&buf[..n])?;
+            n
+        };
+        if self.stopped {
+            // Any error will do to end the copy; it's ignored once this is set.
+            return Err(io::ErrorKind::Other.into());
         }
+        Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        self.next_write.flush()
+    }
+}
+
+/// Like [`Write::write_all()`], but also says how much was written if it fails.
+fn write_prefix(w: &mut impl Write, buf: &[u8]) -> (usize, io::Result<()>) {
+    let mut written = 0;
+    while written < buf.len() {
+        match w.write(&buf[written..]) {
+            Ok(0) => return (written, Err(io::ErrorKind::WriteZero.into())),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return (written, Err(e)),
+        }
     }
+    (written, Ok(()))
 }
 
-/// A running instance of a [`Lambda`] I/O filter.
+/// A running instance of a [`LambdaFilter`] or [`TransformFilter`](crate::TransformFilter).
 pub struct RunningLambda<R> {
-    handle: JoinHandle<io::Result<R>>,
-    input_pipe: Option<OwnedFd>,
-    output_pipe: Option<OwnedFd>,
+    handle: JoinHandle<Result<R, LambdaError<R>>>,
+    name: Option<String>,
+    aborted: Arc<AtomicBool>,
+    completion: Completion,
+    input_pipe: Option<OwnedPipeEnd>,
+    output_pipe: Option<OwnedPipeEnd>,
+}
+
+/// How to start the thread for a [`RunningLambda`].
+pub(crate) struct LambdaThread<'a> {
+    /// The filter's name, which also names the thread.
+    pub name: Option<String>,
+    /// What sort of filter it is, to name the thread if the filter has no name.
+    pub kind: &'static str,
+    pub cpu_affinity: Option<&'a [usize]>,
+    pub stack_size: Option<usize>,
+}
+
+impl<R: Send + 'static> RunningLambda<R> {
+    /// Start a thread running `body` on the filter's streams. `body` should fail with [`Aborted`]
+    /// once the flag it's given is set.
+    pub(crate) fn spawn(
+        thread: LambdaThread,
+        stop: Option<StopHandle>,
+        input: ReadStream,
+        output: WriteStream,
+        body: impl FnOnce(
+                Box<dyn Read + Send>,
+                Box<dyn Write + Send>,
+                Arc<AtomicBool>,
+            ) -> Result<R, LambdaError<R>>
+            + Send
+            + 'static,
+    ) -> io::Result<Self> {
+        let name = thread.name;
+        let cpus = thread
+            .cpu_affinity
+            .map(CpuSet::new)
+            .transpose()
+            .map_err(|e| name_error(name.as_deref(), e))?;
+        let (input_rx, input_tx, input_fd) = read_stream_fd(input)?;
+        let input_rx: Box<dyn Read + Send> = match stop {
+            Some(stop) => Box::new(StopReader::new(input_rx, input_fd, stop)?),
+            None => input_rx,
+        };
+        let (output_tx, output_rx) = write_stream(output)?;
+
+        let aborted = Arc::new(AtomicBool::new(false));
+        let shim_aborted = Arc::clone(&aborted);
+        let completion = Completion::new();
+        let guard = completion.guard();
+        let thread_name = name.clone().unwrap_or_else(|| thread.kind.to_owned());
+        let handle = spawn_thread_sized(Some(thread_name), thread.stack_size, move || {
+            let _guard = guard;
+            if let Some(cpus) = cpus {
+                cpus.apply()?;
+            }
+            body(input_rx, output_tx, shim_aborted)
+        })?;
+        Ok(RunningLambda {
+            handle,
+            name,
+            aborted,
+            completion,
+            input_pipe: input_tx.map(Into::into),
+            output_pipe: output_rx.map(Into::into),
+        })
+    }
 }
 
 impl<R> RunningFilter for RunningLambda<R> {
-    type Result = io::Result<R>;
+    type Result = Result<R, LambdaError<R>>;
 
     fn wait(self) -> Self::Result {
-        self.handle.join().unwrap_or(Err(ThreadPanicked::ioerr()))
+        let result = if self.aborted.load(Ordering::SeqCst) && !self.handle.is_finished() {
+            Err(Aborted::ioerr().into())
+        } else {
+            self.handle
+                .join()
+                .unwrap_or_else(|p| Err(ThreadPanicked::ioerr(p).into()))
+        };
+        result.map_err(|e| e.named(self.name.as_deref()))
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn abort(&mut self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        self.input_pipe = None;
+        self.output_pipe = None;
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.handle.is_finished()
+    }
+
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static) {
+        self.completion.on_complete(f);
     }
 
-    fn input_pipe(&mut self) -> Option<OwnedFd> {
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
         self.input_pipe.take()
     }
 
-    fn output_pipe(&mut self) -> Option<OwnedFd> {
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
         self.output_pipe.take()
     }
 }
@@ -4,16 +4,82 @@
 //! background thread), and [`ChildProcess`] runs a command as a child process.
 //!
 //! Copying data can largely be avoided by using pipes between processes.
+//!
+//! Only Unix is supported so far. The public API refers to OS handles through [`OwnedPipeEnd`]
+//! rather than [`OwnedFd`](std::os::fd::OwnedFd) so that it can stay the same on Windows.
 
 #![deny(missing_docs)]
 
+mod affinity;
+mod boxed;
+mod capture;
+mod check;
+mod completion;
+mod counting;
+mod credentials;
+mod duplex;
+mod error;
+mod fifo;
+#[cfg(feature = "digest")]
+mod hash;
 mod lambda;
+mod limits;
+mod lines;
 mod misc;
+mod passfd;
+mod pipe;
+mod priority;
 mod process;
+mod progress;
+mod pty;
+mod respawn;
+mod scope;
+#[cfg(feature = "signals")]
+mod signals;
+mod socket;
+mod stall;
+mod stop;
 mod tee;
+mod then;
 mod traits;
+mod transform;
+mod words;
 
-pub use lambda::{Lambda, LambdaFilter, RunningLambda};
-pub use process::{ChildProcess, RunningChild};
+pub use boxed::{BoxedFilter, BoxedRunning, NormalizedResult};
+pub use capture::CapturedOutput;
+pub use check::SpawnCheckError;
+pub use counting::{CountingFilter, StreamCounts};
+pub use duplex::{DuplexChild, RunningDuplex};
+pub use error::IoChainError;
+#[cfg(feature = "digest")]
+pub use hash::HashFilter;
+pub use lambda::{
+    InvalidUtf8, Lambda, LambdaError, LambdaFailed, LambdaFilter, LineLambda, LongLines,
+    OffsetLambda, RunningLambda, SampleCounts, Sampling, StreamEnd, TryLambda, Utf8Lambda,
+    WithOffsets,
+};
+pub use limits::Resource;
+pub use lines::{LineTiming, TimedLine};
+pub use misc::{pipe_capacity, Aborted};
+pub use passfd::ExtraFd;
+pub use pipe::{BufferedInput, InputPipe, OutputPipe};
+#[cfg(target_os = "linux")]
+pub use priority::IoPriority;
+pub use process::{
+    ChildExit, ChildExitError, ChildExitErrorKind, ChildProcess, ExitPolicy, OutputSource,
+    RunningChild, ShutdownOutcome,
+};
+pub use progress::Direction;
+pub use respawn::{Respawn, RespawnExit, RespawnPolicy, RunningRespawn};
+pub use scope::{scope, Scope, ScopedLambda};
+#[cfg(feature = "signals")]
+pub use signals::install_signal_forwarding;
+pub use socket::{split_socket, split_unix_socket};
+pub use stop::StopHandle;
 pub use tee::{RunningTee, Tee};
-pub use traits::{Filter, ReadStream, RunningFilter, WriteStream};
+pub use then::{RunningThen, Then, ThenError};
+pub use traits::{
+    FileOpts, FileRef, Filter, OwnedPipeEnd, PipeOpts, ReadStream, RunningFilter, WriteStream,
+};
+pub use transform::{MutateFilter, MutateLambda, Transform, TransformFilter};
+pub use words::{ParseError, ParseErrorKind};
@@ -0,0 +1,71 @@
+use std::io;
+
+/// A resource whose use by a child process can be limited with
+/// [`ChildProcess::limit()`](crate::ChildProcess::limit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// The size of the process's virtual memory in bytes (`RLIMIT_AS`). Allocations beyond it
+    /// fail.
+    AddressSpace,
+    /// CPU time in seconds (`RLIMIT_CPU`). The process gets `SIGXCPU` when it reaches the soft
+    /// limit, and `SIGKILL` at the hard limit.
+    CpuTime,
+    /// One more than the highest file descriptor number the process can open (`RLIMIT_NOFILE`).
+    OpenFiles,
+    /// The largest file the process can create or extend, in bytes (`RLIMIT_FSIZE`). Writing past
+    /// it sends the process `SIGXFSZ`.
+    FileSize,
+    /// The largest core dump the process can write, in bytes (`RLIMIT_CORE`). Zero disables core
+    /// dumps.
+    CoreSize,
+}
+
+impl Resource {
+    /// No limit, for either the soft or the hard limit.
+    #[allow(clippy::unnecessary_cast)] // rlim_t isn't u64 everywhere
+    pub const UNLIMITED: u64 = libc::RLIM_INFINITY as u64;
+
+    /// The resource whose limit makes the kernel send the given signal, if there is one.
+    pub(crate) fn from_signal(sig: i32) -> Option<Resource> {
+        match sig {
+            libc::SIGXCPU => Some(Resource::CpuTime),
+            libc::SIGXFSZ => Some(Resource::FileSize),
+            _ => None,
+        }
+    }
+}
+
+/// A limit to set in the child before it execs. This is plain data, so that applying it doesn't
+/// need to allocate.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limit {
+    pub resource: Resource,
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl Limit {
+    /// Set the limit for the current process. This is called between fork and exec, so it must
+    /// only do async-signal-safe things.
+    #[allow(clippy::unnecessary_cast)] // rlim_t isn't u64 everywhere
+    pub fn apply(&self) -> io::Result<()> {
+        let rlim = libc::rlimit {
+            rlim_cur: self.soft as libc::rlim_t,
+            rlim_max: self.hard as libc::rlim_t,
+        };
+        // SAFETY: FFI calls with a valid pointer. setrlimit is async-signal-safe.
+        let ret = unsafe {
+            match self.resource {
+                Resource::AddressSpace => libc::setrlimit(libc::RLIMIT_AS, &rlim),
+                Resource::CpuTime => libc::setrlimit(libc::RLIMIT_CPU, &rlim),
+                Resource::OpenFiles => libc::setrlimit(libc::RLIMIT_NOFILE, &rlim),
+                Resource::FileSize => libc::setrlimit(libc::RLIMIT_FSIZE, &rlim),
+                Resource::CoreSize => libc::setrlimit(libc::RLIMIT_CORE, &rlim),
+            }
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+use crate::OutputSource;
+
+/// A line of a child's output, and when it was produced, from
+/// [`ChildProcess::time_lines()`](crate::ChildProcess::time_lines).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedLine {
+    /// When the end of the line was read from the child. (For the last line, if it had no
+    /// newline, this is when the stream ended.)
+    pub time: Instant,
+    /// Which stream the line came from.
+    pub source: OutputSource,
+    /// The line, without its newline.
+    pub line: Vec<u8>,
+    /// Whether the line was cut off at the maximum length.
+    pub truncated: bool,
+}
+
+type LineCallback = Box<dyn FnMut(&TimedLine) + Send>;
+
+/// Options for [`ChildProcess::time_lines()`](crate::ChildProcess::time_lines).
+///
+/// By default, the last 1000 lines are kept, each cut off after 1024 bytes.
+pub struct LineTiming {
+    max_lines: usize,
+    max_line_len: usize,
+    callback: Option<LineCallback>,
+}
+
+impl Default for LineTiming {
+    fn default() -> Self {
+        Self {
+            max_lines: 1000,
+            max_line_len: 1024,
+            callback: None,
+        }
+    }
+}
+
+impl LineTiming {
+    /// The default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep only the last `max` lines. Zero keeps none, for when they're only wanted by the
+    /// callback given to [`LineTiming::on_line()`].
+    pub fn max_lines(mut self, max: usize) -> Self {
+        self.max_lines = max;
+        self
+    }
+
+    /// Cut off lines after `max` bytes. The rest of the line is still passed on to the child's
+    /// output, but not recorded.
+    pub fn max_line_len(mut self, max: usize) -> Self {
+        self.max_line_len = max;
+        self
+    }
+
+    /// Call `f` with each line as it's produced. It's called from the thread copying the stream
+    /// the line came from, before the line is recorded, so a slow callback slows the copy down,
+    /// and holds up the other stream's thread too if it has a line at the same time. Anything more
+    /// than a quick update should be handed off to another thread.
+    pub fn on_line(mut self, f: impl FnMut(&TimedLine) + Send + 'static) -> Self {
+        self.callback = Some(Box::new(f));
+        self
+    }
+}
+
+/// The lines recorded so far, shared by the taps on a child's streams.
+#[derive(Clone)]
+pub(crate) struct LineLog(Arc<LogState>);
+
+struct LogState {
+    max_lines: usize,
+    max_line_len: usize,
+    // Locked separately from the lines, so that the callback can take its time, or look at the
+    // lines itself.
+    callback: Mutex<Option<LineCallback>>,
+    lines: Mutex<VecDeque<TimedLine>>,
+}
+
+impl LineLog {
+    pub fn new(timing: LineTiming) -> Self {
+        Self(Arc::new(LogState {
+            max_lines: timing.max_lines,
+            max_line_len: timing.max_line_len,
+            callback: Mutex::new(timing.callback),
+            lines: Mutex::new(VecDeque::new()),
+        }))
+    }
+
+    /// A copy of the lines kept so far, oldest first.
+    pub fn lines(&self) -> Vec<TimedLine> {
+        self.0.lines.lock().iter().cloned().collect()
+    }
+
+    fn max_line_len(&self) -> usize {
+        self.0.max_line_len
+    }
+
+    fn push(&self, line: TimedLine) {
+        if let Some(callback) = &mut *self.0.callback.lock() {
+            callback(&line);
+        }
+        if self.0.max_lines == 0 {
+            return;
+        }
+        let mut lines = self.0.lines.lock();
+        if lines.len() == self.0.max_lines {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+/// Passes everything written to it on to another stream, recording each line on the way.
+pub(crate) struct LineTap<W> {
+    inner: W,
+    source: OutputSource,
+    log: LineLog,
+    max_len: usize,
+    partial: Vec<u8>,
+    truncated: bool,
+    // Whether there's a line in progress, since an empty partial could be an empty line.
+    started: bool,
+}
+
+impl<W> LineTap<W> {
+    pub fn new(inner: W, source: OutputSource, log: LineLog) -> Self {
+        Self {
+            inner,
+            source,
+            max_len: log.max_line_len(),
+            log,
+            partial: vec![],
+            truncated: false,
+            started: false,
+        }
+    }
+
+    fn record(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            self.started = true;
+            let (chunk, ends_line) = match data.iter().position(|&b| b == b'\n') {
+                Some(i) => (&data[..i], true),
+                None => (data, false),
+            };
+            let room = self.max_len.saturating_sub(self.partial.len());
+            self.partial.extend(&chunk[..chunk.len().min(room)]);
+            self.truncated |= chunk.len() > room;
+            data = &data[(chunk.len() + usize::from(ends_line))..];
+            if ends_line {
+                self.finish_line();
+            }
+        }
+    }
+
+    fn finish_line(&mut self) {
+        self.log.push(TimedLine {
+            time: Instant::now(),
+            source: self.source,
+            line: std::mem::take(&mut self.partial),
+            truncated: std::mem::take(&mut self.truncated),
+        });
+        self.started = false;
+    }
+}
+
+impl<W: Write> Write for LineTap<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        // Only record what was actually passed on.
+        self.record(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W> Drop for LineTap<W> {
+    /// The stream has ended, so record any unfinished line.
+    fn drop(&mut self) {
+        if self.started {
+            self.finish_line();
+        }
+    }
+}
@@ -1,31 +1,634 @@
+use std::any::Any;
 use std::error::Error;
 use std::fmt::Display;
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
 use os_pipe::{PipeReader, PipeWriter};
 
-use crate::{ReadStream, WriteStream};
+use crate::fifo::LazyFifo;
+use crate::{FileOpts, FileRef, PipeOpts, ReadStream, WriteStream};
 
 /// Returned if a copy thread panics, meaning the input or output stream's [`Read::read`] or
-/// [`Write::write`] implementation panicked.
+/// [`Write::write`] implementation panicked, or a lambda's thread panics.
 #[derive(Debug)]
-pub struct ThreadPanicked;
+pub struct ThreadPanicked {
+    /// What the thread panicked with, if it was a message.
+    pub message: Option<String>,
+}
 
 impl ThreadPanicked {
-    pub fn ioerr() -> io::Error {
-        io::Error::new(io::ErrorKind::Other, ThreadPanicked)
+    /// Make an error from what a thread panicked with, as returned from [`JoinHandle::join()`].
+    pub fn ioerr(payload: Box<dyn Any + Send>) -> io::Error {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => Some(*message),
+            Err(payload) => payload.downcast_ref::<&str>().map(|&s| s.to_owned()),
+        };
+        io::Error::other(ThreadPanicked { message })
     }
 }
 
 impl Display for ThreadPanicked {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("copy thread panicked")
+        f.write_str("copy thread panicked")?;
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+        Ok(())
     }
 }
 
 impl Error for ThreadPanicked {}
 
+/// Returned from a filter which was stopped by [`RunningFilter::abort()`](crate::RunningFilter::abort).
+#[derive(Debug)]
+pub struct Aborted;
+
+impl Aborted {
+    pub(crate) fn ioerr() -> io::Error {
+        io::Error::other(Aborted)
+    }
+
+    /// Check whether an error is an [`Aborted`] error.
+    pub fn is(e: &io::Error) -> bool {
+        is_error::<Aborted>(e)
+    }
+}
+
+impl Display for Aborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("filter was aborted")
+    }
+}
+
+impl Error for Aborted {}
+
+/// An error from a filter that was given a name.
+#[derive(Debug)]
+struct NamedError {
+    name: String,
+    inner: io::Error,
+}
+
+impl Display for NamedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "filter '{}': {}", self.name, self.inner)
+    }
+}
+
+impl Error for NamedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.inner)
+    }
+}
+
+/// Add a filter's name, if it has one, to an error from it.
+pub(crate) fn name_error(name: Option<&str>, e: io::Error) -> io::Error {
+    match name {
+        Some(name) => io::Error::new(
+            e.kind(),
+            NamedError {
+                name: name.to_owned(),
+                inner: e,
+            },
+        ),
+        None => e,
+    }
+}
+
+/// Check whether an error is (or was created from) the given error type, looking past any names
+/// added by [`name_error()`].
+pub(crate) fn is_error<T: Error + 'static>(e: &io::Error) -> bool {
+    find_error::<T>(e).is_some()
+}
+
+/// Like [`is_error()`], but get the error.
+pub(crate) fn find_error<T: Error + 'static>(e: &io::Error) -> Option<&T> {
+    let inner = e.get_ref()?;
+    match inner.downcast_ref::<NamedError>() {
+        Some(named) => find_error::<T>(&named.inner),
+        None => inner.downcast_ref::<T>(),
+    }
+}
+
+/// Spawn a thread, with the given name if there is one.
+pub(crate) fn spawn_thread<T: Send + 'static>(
+    name: Option<String>,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> io::Result<JoinHandle<T>> {
+    spawn_thread_sized(name, None, f)
+}
+
+/// Like [`spawn_thread()`], with a stack of the given size if there is one, instead of the default.
+pub(crate) fn spawn_thread_sized<T: Send + 'static>(
+    name: Option<String>,
+    stack_size: Option<usize>,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> io::Result<JoinHandle<T>> {
+    thread_builder(name, stack_size).spawn(f)
+}
+
+/// Like [`spawn_thread_sized()`], but for a thread in a [`thread::Scope`].
+pub(crate) fn spawn_scoped_thread<'scope, T: Send + 'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    name: Option<String>,
+    stack_size: Option<usize>,
+    f: impl FnOnce() -> T + Send + 'scope,
+) -> io::Result<thread::ScopedJoinHandle<'scope, T>> {
+    thread_builder(name, stack_size).spawn_scoped(scope, f)
+}
+
+fn thread_builder(name: Option<String>, stack_size: Option<usize>) -> thread::Builder {
+    let mut builder = thread::Builder::new();
+    if let Some(name) = name {
+        builder = builder.name(name);
+    }
+    if let Some(bytes) = stack_size {
+        builder = builder.stack_size(bytes);
+    }
+    builder
+}
+
+/// Copy everything from `r` to `w`, like [`io::copy()`], using a buffer of the given size if there
+/// is one. Returns how many bytes were copied.
+pub(crate) fn copy(
+    r: &mut impl Read,
+    w: &mut impl Write,
+    buffer_size: Option<usize>,
+) -> io::Result<u64> {
+    let Some(size) = buffer_size else {
+        return io::copy(r, w);
+    };
+    let mut buf = vec![0; size.max(1)];
+    let mut total = 0;
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        w.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+/// Like [`copy()`], but if `epipe_ok` is set, a [`BrokenPipe`](io::ErrorKind::BrokenPipe) error
+/// from writing ends the copy early instead of failing it. Returns how many bytes were written,
+/// and whether the copy was cut short like that.
+pub(crate) fn copy_epipe_ok(
+    r: &mut impl Read,
+    w: &mut impl Write,
+    epipe_ok: bool,
+    buffer_size: Option<usize>,
+) -> io::Result<(u64, bool)> {
+    let mut w = CountingWriter { inner: w, count: 0 };
+    match copy(r, &mut w, buffer_size) {
+        Ok(n) => Ok((n, false)),
+        Err(e) if epipe_ok && e.kind() == io::ErrorKind::BrokenPipe => Ok((w.count, true)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Counts the bytes written through it, for [`copy_epipe_ok()`].
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Add the path to an error from opening a file, so it can be told apart from other errors.
+pub(crate) fn path_error(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {e}", path.display()))
+}
+
+/// Open a file for reading, with the path included in any error.
+pub(crate) fn open_read(path: &Path) -> io::Result<File> {
+    File::open(path).map_err(|e| path_error(path, e))
+}
+
+/// Open a file for writing with the given options, with the path included in any error.
+pub(crate) fn open_write(path: &Path, opts: FileOpts) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(opts.create)
+        .create_new(opts.create_new)
+        .append(opts.append)
+        .truncate(opts.truncate)
+        .open(path)
+        .map_err(|e| path_error(path, e))
+}
+
+/// Adapts a channel of byte chunks into a [`Read`] stream.
+struct ChannelReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl ChannelReader {
+    fn new(rx: Receiver<Vec<u8>>) -> Self {
+        Self {
+            rx,
+            chunk: Cursor::new(vec![]),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n != 0 {
+                return Ok(n);
+            }
+            // Current chunk is used up (or was empty); get another one.
+            match self.rx.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Adapts an iterator of byte chunks into a [`Read`] stream.
+pub(crate) struct IterReader<I> {
+    iter: Option<I>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl<I> IterReader<I> {
+    pub fn new(iter: I) -> Self {
+        Self {
+            iter: Some(iter),
+            chunk: Cursor::new(vec![]),
+        }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Vec<u8>>>> Read for IterReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n != 0 {
+                return Ok(n);
+            }
+            let Some(iter) = &mut self.iter else {
+                return Ok(0);
+            };
+            match iter.next() {
+                Some(Ok(chunk)) => self.chunk = Cursor::new(chunk),
+                Some(Err(e)) => {
+                    self.iter = None;
+                    return Err(e);
+                }
+                None => {
+                    self.iter = None;
+                    return Ok(0);
+                }
+            }
+        }
+    }
+}
+
+/// Adapts a channel into a [`Write`] stream that sends a copy of each buffer written.
+struct ChannelWriter {
+    tx: SyncSender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.tx.send(buf.to_vec()).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "channel receiver was dropped")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes to this process's stdout, flushing each time so that data isn't left sitting in its
+/// buffer when the filter finishes.
+struct StdoutWriter;
+
+impl Write for StdoutWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stdout = io::stdout().lock();
+        let n = stdout.write(buf)?;
+        stdout.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Reads from a sequence of streams, moving on to the next one when each hits EOF.
+struct ChainReader {
+    readers: Vec<Box<dyn Read + Send>>,
+    current: usize,
+}
+
+impl ChainReader {
+    fn new(inputs: Vec<ReadStream>) -> io::Result<Self> {
+        let readers = inputs
+            .into_iter()
+            .enumerate()
+            .map(|(i, input)| {
+                if input.is_pipe() {
+                    return Err(chain_error(
+                        i,
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "a requested pipe can't be used in a chain",
+                        ),
+                    ));
+                }
+                read_stream(input)
+                    .map(|(r, _)| r)
+                    .map_err(|e| chain_error(i, e))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            readers,
+            current: 0,
+        })
+    }
+}
+
+fn chain_error(index: usize, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("chained input {index}: {e}"))
+}
+
+impl Read for ChainReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while let Some(r) = self.readers.get_mut(self.current) {
+            match r.read(buf) {
+                Ok(0) => {
+                    // Drop it now so that its fd gets closed.
+                    self.readers[self.current] = Box::new(io::empty());
+                    self.current += 1;
+                }
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(chain_error(self.current, e)),
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// Like [`Read::take`], but drops the inner stream once the limit is reached.
+struct LimitedReader {
+    inner: Option<Box<dyn Read + Send>>,
+    remaining: u64,
+}
+
+impl Read for LimitedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(inner) = &mut self.inner else {
+            return Ok(0);
+        };
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = inner.read(&mut buf[..max])?;
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            self.inner = None;
+        }
+        Ok(n)
+    }
+}
+
+/// Yields a pattern over and over.
+struct RepeatReader {
+    pattern: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for RepeatReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pattern.is_empty() {
+            return Ok(0);
+        }
+        let mut filled = 0;
+        while filled < buf.len() {
+            let src = &self.pattern[self.pos..];
+            let n = src.len().min(buf.len() - filled);
+            buf[filled..filled + n].copy_from_slice(&src[..n]);
+            filled += n;
+            self.pos = (self.pos + n) % self.pattern.len();
+        }
+        Ok(filled)
+    }
+}
+
+/// Create a pipe for a filter's input, with the given options. The write end is the one that will
+/// be given back to the caller.
+pub(crate) fn input_pipe(opts: PipeOpts) -> io::Result<(PipeReader, PipeWriter)> {
+    let (rx, tx) = pipe(opts)?;
+    if opts.nonblocking {
+        set_nonblocking(&tx)?;
+    }
+    Ok((rx, tx))
+}
+
+/// Create a pipe for a filter's output, with the given options. The read end is the one that will
+/// be given back to the caller.
+pub(crate) fn output_pipe(opts: PipeOpts) -> io::Result<(PipeReader, PipeWriter)> {
+    let (rx, tx) = pipe(opts)?;
+    if opts.nonblocking {
+        set_nonblocking(&rx)?;
+    }
+    Ok((rx, tx))
+}
+
+fn pipe(opts: PipeOpts) -> io::Result<(PipeReader, PipeWriter)> {
+    let (rx, tx) = os_pipe::pipe()?;
+    if let Some(bytes) = opts.capacity {
+        set_pipe_capacity(&rx, bytes);
+    }
+    Ok((rx, tx))
+}
+
+fn set_nonblocking(fd: &impl AsFd) -> io::Result<()> {
+    let fd = fd.as_fd().as_raw_fd();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_pipe_capacity(fd: &impl AsFd, bytes: usize) {
+    let bytes = libc::c_int::try_from(bytes).unwrap_or(libc::c_int::MAX);
+    // This is just a hint; if the kernel won't do it, carry on with the pipe we have.
+    unsafe {
+        libc::fcntl(fd.as_fd().as_raw_fd(), libc::F_SETPIPE_SZ, bytes);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_pipe_capacity(_fd: &impl AsFd, _bytes: usize) {}
+
+/// Get the size in bytes of a pipe's buffer.
+///
+/// This is only supported on Linux; elsewhere it returns an error of kind
+/// [`Unsupported`](io::ErrorKind::Unsupported).
+pub fn pipe_capacity(fd: &impl AsFd) -> io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        let n = unsafe { libc::fcntl(fd.as_fd().as_raw_fd(), libc::F_GETPIPE_SZ) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = fd;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pipe capacity can only be queried on Linux",
+        ))
+    }
+}
+
+/// Reads a range of a file using positional reads, leaving the file's offset alone.
+struct RangeReader {
+    file: Arc<File>,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.end - self.pos;
+        let max = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.file.read_at(&mut buf[..max], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "mmap")]
+fn mmap(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    let file = open_read(path)?;
+    if file.metadata().map_err(|e| path_error(path, e))?.len() == 0 {
+        // Mapping zero bytes is an error on some systems.
+        return Ok(Box::new(io::empty()));
+    }
+    // SAFETY: the caller is responsible for not modifying the file while it is mapped.
+    let map = unsafe { memmap2::Mmap::map(&file) }.map_err(|e| path_error(path, e))?;
+    Ok(Box::new(Cursor::new(map)))
+}
+
+/// Writes to a file using positional writes, leaving the file's offset alone.
+struct PositionalWriter {
+    file: Arc<File>,
+    pos: u64,
+}
+
+impl Write for PositionalWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes everything to each of several streams in turn.
+struct MultiWriter {
+    writers: Vec<(usize, Box<dyn Write + Send>)>,
+}
+
+impl MultiWriter {
+    fn new(outputs: Vec<WriteStream>) -> io::Result<Self> {
+        let mut writers = vec![];
+        for (i, output) in outputs.into_iter().enumerate() {
+            if matches!(output, WriteStream::Null) {
+                continue;
+            }
+            if output.is_pipe() {
+                return Err(multi_error(
+                    i,
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "a requested pipe can't be used in a multi-output stream",
+                    ),
+                ));
+            }
+            let (w, _) = write_stream(output).map_err(|e| multi_error(i, e))?;
+            writers.push((i, w));
+        }
+        Ok(Self { writers })
+    }
+}
+
+fn multi_error(index: usize, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("output {index}: {e}"))
+}
+
+impl Write for MultiWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for (i, w) in &mut self.writers {
+            w.write_all(buf).map_err(|e| multi_error(*i, e))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for (i, w) in &mut self.writers {
+            w.flush().map_err(|e| multi_error(*i, e))?;
+        }
+        Ok(())
+    }
+}
+
 pub(crate) fn read_stream(
     input: ReadStream,
 ) -> io::Result<(Box<dyn Read + Send>, Option<PipeWriter>)> {
@@ -33,13 +636,78 @@ pub(crate) fn read_stream(
         ReadStream::Null => (Box::new(io::empty()), None),
         ReadStream::Fd(fd) => (Box::new(File::from(fd)), None),
         ReadStream::Rust(r) => (Box::new(r), None),
+        ReadStream::File(path) => (Box::new(open_read(&path)?), None),
+        ReadStream::FileRange { file, offset, len } => {
+            let file = match file {
+                FileRef::Path(path) => Arc::new(open_read(&path)?),
+                FileRef::File(file) => file,
+            };
+            let r = RangeReader {
+                file,
+                pos: offset,
+                end: offset.saturating_add(len),
+            };
+            (Box::new(r), None)
+        }
+        #[cfg(feature = "mmap")]
+        ReadStream::Mmap(path) => (mmap(&path)?, None),
+        ReadStream::Fifo { path, remove } => (Box::new(LazyFifo::new(path, remove, false)?), None),
+        ReadStream::Bytes(b) => (Box::new(Cursor::new(b)), None),
+        ReadStream::Channel(rx) => (Box::new(ChannelReader::new(rx)), None),
+        ReadStream::Inherit => (Box::new(io::stdin()), None),
+        ReadStream::Chain(inputs) => (Box::new(ChainReader::new(inputs)?), None),
+        ReadStream::Repeat(pattern) => (Box::new(RepeatReader { pattern, pos: 0 }), None),
+        ReadStream::Zeros => (Box::new(io::repeat(0)), None),
+        ReadStream::Limited { inner, limit } => {
+            if inner.is_pipe() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a requested pipe can't be limited",
+                ));
+            }
+            let (inner, _) = read_stream(*inner)?;
+            let r = LimitedReader {
+                inner: Some(inner),
+                remaining: limit,
+            };
+            (Box::new(r), None)
+        }
         ReadStream::PipeRequested => {
-            let (rx, tx) = os_pipe::pipe()?;
+            let (rx, tx) = input_pipe(PipeOpts::default())?;
+            (Box::new(rx), Some(tx))
+        }
+        ReadStream::Pipe(opts) => {
+            let (rx, tx) = input_pipe(opts)?;
             (Box::new(rx), Some(tx))
         }
     })
 }
 
+/// Like [`read_stream()`], but also gives the file descriptor the stream reads from, if it's a
+/// pipe or a file descriptor it was given, so that reads from it can be waited for with `poll`.
+/// It stays open as long as the stream does.
+#[allow(clippy::type_complexity)] // read_stream()'s result, plus one
+pub(crate) fn read_stream_fd(
+    input: ReadStream,
+) -> io::Result<(Box<dyn Read + Send>, Option<PipeWriter>, Option<RawFd>)> {
+    let opts = match input {
+        ReadStream::PipeRequested => PipeOpts::default(),
+        ReadStream::Pipe(opts) => opts,
+        ReadStream::Fd(ref fd) => {
+            let fd = fd.as_raw_fd();
+            let (rx, tx) = read_stream(input)?;
+            return Ok((rx, tx, Some(fd)));
+        }
+        input => {
+            let (rx, tx) = read_stream(input)?;
+            return Ok((rx, tx, None));
+        }
+    };
+    let (rx, tx) = input_pipe(opts)?;
+    let fd = rx.as_raw_fd();
+    Ok((Box::new(rx), Some(tx), Some(fd)))
+}
+
 pub(crate) fn write_stream(
     output: WriteStream,
 ) -> io::Result<(Box<dyn Write + Send>, Option<PipeReader>)> {
@@ -47,8 +715,24 @@ pub(crate) fn write_stream(
         WriteStream::Null => (Box::new(io::sink()), None),
         WriteStream::Fd(fd) => (Box::new(File::from(fd)), None),
         WriteStream::Rust(r) => (Box::new(r), None),
+        WriteStream::File { path, options } => (Box::new(open_write(&path, options)?), None),
+        WriteStream::FileAt { file, offset } => {
+            let file = match file {
+                FileRef::Path(path) => Arc::new(open_write(&path, FileOpts::new().create(true))?),
+                FileRef::File(file) => file,
+            };
+            (Box::new(PositionalWriter { file, pos: offset }), None)
+        }
+        WriteStream::Fifo { path, remove } => (Box::new(LazyFifo::new(path, remove, true)?), None),
+        WriteStream::Multi(outputs) => (Box::new(MultiWriter::new(outputs)?), None),
+        WriteStream::Channel(tx) => (Box::new(ChannelWriter { tx }), None),
+        WriteStream::Inherit => (Box::new(StdoutWriter), None),
         WriteStream::PipeRequested => {
-            let (rx, tx) = os_pipe::pipe()?;
+            let (rx, tx) = output_pipe(PipeOpts::default())?;
+            (Box::new(tx), Some(rx))
+        }
+        WriteStream::Pipe(opts) => {
+            let (rx, tx) = output_pipe(opts)?;
             (Box::new(tx), Some(rx))
         }
     })
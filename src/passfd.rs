@@ -0,0 +1,138 @@
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use crate::misc::{self, open_read, open_write};
+use crate::{OwnedPipeEnd, PipeOpts, ReadStream, WriteStream};
+
+/// A stream to pass to a child process as an extra file descriptor, with
+/// [`ChildProcess::pass_fd()`](crate::ChildProcess::pass_fd). Converting from a [`ReadStream`]
+/// gives the child something to read from, and from a [`WriteStream`] something to write to.
+///
+/// Only streams which the child can be given directly are supported: file descriptors, files,
+/// `Null`, and requested pipes. Anything else would need a copy thread, and starting the child
+/// fails with [`InvalidInput`](io::ErrorKind::InvalidInput).
+pub enum ExtraFd {
+    /// A stream for the child to read.
+    Read(ReadStream),
+    /// A stream for the child to write.
+    Write(WriteStream),
+}
+
+impl From<ReadStream> for ExtraFd {
+    fn from(stream: ReadStream) -> Self {
+        ExtraFd::Read(stream)
+    }
+}
+
+impl From<WriteStream> for ExtraFd {
+    fn from(stream: WriteStream) -> Self {
+        ExtraFd::Write(stream)
+    }
+}
+
+impl ExtraFd {
+    /// Open the stream, returning the end for the child and, for a requested pipe, the end for
+    /// the parent.
+    fn open(self) -> io::Result<(OwnedFd, Option<OwnedPipeEnd>)> {
+        Ok(match self {
+            ExtraFd::Read(ReadStream::Fd(fd)) | ExtraFd::Write(WriteStream::Fd(fd)) => (fd, None),
+            ExtraFd::Read(ReadStream::File(path)) => (open_read(&path)?.into(), None),
+            ExtraFd::Write(WriteStream::File { path, options }) => {
+                (open_write(&path, options)?.into(), None)
+            }
+            ExtraFd::Read(ReadStream::Null) => (File::open("/dev/null")?.into(), None),
+            ExtraFd::Write(WriteStream::Null) => {
+                (File::options().write(true).open("/dev/null")?.into(), None)
+            }
+            ExtraFd::Read(ReadStream::PipeRequested) => {
+                let (rx, tx) = misc::input_pipe(PipeOpts::new())?;
+                (rx.into(), Some(tx.into()))
+            }
+            ExtraFd::Read(ReadStream::Pipe(opts)) => {
+                let (rx, tx) = misc::input_pipe(opts)?;
+                (rx.into(), Some(tx.into()))
+            }
+            ExtraFd::Write(WriteStream::PipeRequested) => {
+                let (rx, tx) = misc::output_pipe(PipeOpts::new())?;
+                (tx.into(), Some(rx.into()))
+            }
+            ExtraFd::Write(WriteStream::Pipe(opts)) => {
+                let (rx, tx) = misc::output_pipe(opts)?;
+                (tx.into(), Some(rx.into()))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "extra file descriptors can't use streams that need a copy thread",
+                ))
+            }
+        })
+    }
+}
+
+/// The child's ends of the extra file descriptors, ready to be moved onto their numbers between
+/// fork and exec.
+pub(crate) struct PassedFds(Vec<(OwnedFd, RawFd)>);
+
+impl PassedFds {
+    /// Open the given streams, checking that the target numbers don't collide with stdio or each
+    /// other. Returns the parent's ends of any requested pipes, by target number.
+    pub fn open(extra: Vec<(RawFd, ExtraFd)>) -> io::Result<(Self, Vec<(RawFd, OwnedPipeEnd)>)> {
+        let mut targets = extra.iter().map(|(target, _)| *target).collect::<Vec<_>>();
+        targets.sort_unstable();
+        if targets.first().is_some_and(|&first| first <= 2) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "extra file descriptors can't replace stdin, stdout, or stderr",
+            ));
+        }
+        if targets.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the same extra file descriptor number was given more than once",
+            ));
+        }
+        let above = targets.last().map_or(0, |last| last + 1);
+
+        let mut fds = vec![];
+        let mut pipes = vec![];
+        for (target, stream) in extra {
+            let (fd, pipe) = stream.open()?;
+            // Move the child's end above all the targets, so that putting one in place can't
+            // close another before it's been moved.
+            fds.push((dup_above(&fd, above)?, target));
+            if let Some(pipe) = pipe {
+                pipes.push((target, pipe));
+            }
+        }
+        Ok((Self(fds), pipes))
+    }
+
+    /// Put each file descriptor in place, leaving it open across exec. This is called between
+    /// fork and exec, so it must only do async-signal-safe things.
+    pub fn apply(&self) -> io::Result<()> {
+        for (fd, target) in &self.0 {
+            // SAFETY: FFI calls with no pointers. dup2 and fcntl are async-signal-safe.
+            unsafe {
+                if libc::dup2(fd.as_raw_fd(), *target) == -1
+                    || libc::fcntl(*target, libc::F_SETFD, 0) == -1
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Duplicate a file descriptor to the lowest free number at or above `min`, close-on-exec.
+fn dup_above(fd: &OwnedFd, min: RawFd) -> io::Result<OwnedFd> {
+    // SAFETY: FFI call with no pointers.
+    let new = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, min) };
+    if new == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fcntl succeeded, so this is a new file descriptor which we own.
+    Ok(unsafe { OwnedFd::from_raw_fd(new) })
+}
@@ -0,0 +1,128 @@
+use std::io::{self, BufWriter, Read, Write};
+use std::os::fd::{AsFd, BorrowedFd};
+
+use os_pipe::{PipeReader, PipeWriter};
+
+use crate::OwnedPipeEnd;
+
+/// The write half of a pipe feeding a running filter's input, from
+/// [`RunningFilter::input_writer()`](crate::RunningFilter::input_writer).
+///
+/// The filter sees EOF once this (and any duplicates of it) is closed or dropped.
+#[derive(Debug)]
+pub struct InputPipe {
+    inner: PipeWriter,
+}
+
+impl InputPipe {
+    /// Close the pipe, signalling EOF to the filter. This is the same as dropping it.
+    pub fn close(self) {}
+}
+
+impl From<OwnedPipeEnd> for InputPipe {
+    fn from(fd: OwnedPipeEnd) -> Self {
+        Self {
+            inner: PipeWriter::from(fd),
+        }
+    }
+}
+
+impl From<InputPipe> for OwnedPipeEnd {
+    fn from(pipe: InputPipe) -> Self {
+        pipe.inner.into()
+    }
+}
+
+impl AsFd for InputPipe {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+impl Write for InputPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A buffered [`InputPipe`], from
+/// [`RunningFilter::buffered_input_writer()`](crate::RunningFilter::buffered_input_writer).
+///
+/// Dropping it flushes whatever is buffered and closes the pipe, but any error doing so is lost;
+/// use [`BufferedInput::close()`] to see it.
+#[derive(Debug)]
+pub struct BufferedInput {
+    inner: BufWriter<InputPipe>,
+}
+
+impl BufferedInput {
+    /// Flush whatever is buffered and close the pipe, signalling EOF to the filter.
+    pub fn close(self) -> io::Result<()> {
+        self.inner
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .close();
+        Ok(())
+    }
+}
+
+impl From<InputPipe> for BufferedInput {
+    fn from(pipe: InputPipe) -> Self {
+        Self {
+            inner: BufWriter::new(pipe),
+        }
+    }
+}
+
+impl Write for BufferedInput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The read half of a pipe carrying a running filter's output, from
+/// [`RunningFilter::output_reader()`](crate::RunningFilter::output_reader).
+#[derive(Debug)]
+pub struct OutputPipe {
+    inner: PipeReader,
+}
+
+impl OutputPipe {
+    /// Close the pipe. The filter will get `EPIPE` if it writes any more. This is the same as
+    /// dropping it.
+    pub fn close(self) {}
+}
+
+impl From<OwnedPipeEnd> for OutputPipe {
+    fn from(fd: OwnedPipeEnd) -> Self {
+        Self {
+            inner: PipeReader::from(fd),
+        }
+    }
+}
+
+impl From<OutputPipe> for OwnedPipeEnd {
+    fn from(pipe: OutputPipe) -> Self {
+        pipe.inner.into()
+    }
+}
+
+impl AsFd for OutputPipe {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+impl Read for OutputPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
@@ -0,0 +1,70 @@
+use std::io;
+
+/// An I/O scheduling class and priority for
+/// [`ChildProcess::io_priority()`](crate::ChildProcess::io_priority), as used by `ionice`. Within
+/// a class, level 0 is the highest priority and 7 the lowest.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Always served first. Setting this needs privileges.
+    RealTime(u8),
+    /// The default class.
+    BestEffort(u8),
+    /// Only served when nothing else wants the disk.
+    Idle,
+}
+
+#[cfg(target_os = "linux")]
+impl IoPriority {
+    /// The value for `ioprio_set`.
+    fn raw(self) -> libc::c_int {
+        const CLASS_SHIFT: libc::c_int = 13;
+        let (class, level) = match self {
+            IoPriority::RealTime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        (class << CLASS_SHIFT) | libc::c_int::from(level)
+    }
+
+    /// Set the I/O priority of the current process. This is called between fork and exec, so it
+    /// must only do async-signal-safe things.
+    pub(crate) fn apply(self) -> io::Result<()> {
+        const WHO_PROCESS: libc::c_int = 1;
+        // SAFETY: FFI call with no pointers. A raw system call is async-signal-safe.
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, WHO_PROCESS, 0, self.raw()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Set the niceness of the current process. This is called between fork and exec, so it must only
+/// do async-signal-safe things.
+pub(crate) fn set_nice(nice: i32) -> io::Result<()> {
+    // SAFETY: FFI call with no pointers. setpriority is async-signal-safe.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the niceness of the calling thread only. Linux keeps a niceness for each thread, where
+/// other systems would apply it to the whole process, so this isn't supported elsewhere.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_thread_nice(nice: i32) -> io::Result<()> {
+    // SAFETY: FFI calls with no pointers.
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, nice) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_thread_nice(_nice: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "thread niceness is only supported on Linux",
+    ))
+}
@@ -1,150 +1,2034 @@
+use std::collections::VecDeque;
 use std::error::Error;
+use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fmt::Display;
-use std::os::fd::OwnedFd;
-use std::os::unix::process::ExitStatusExt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::fd::{AsFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread::JoinHandle;
-use std::{io, thread};
+use std::time::{Duration, Instant};
 
-use crate::misc::ThreadPanicked;
-use crate::{Filter, ReadStream, RunningFilter, WriteStream};
+use os_pipe::PipeWriter;
+use parking_lot::Mutex;
+
+use crate::affinity::CpuSet;
+use crate::check::{self, SpawnCheckError};
+use crate::completion::{Completion, CompletionGuard};
+use crate::credentials::Credentials;
+use crate::limits::{Limit, Resource};
+use crate::lines::{LineLog, LineTap, LineTiming, TimedLine};
+use crate::misc::{
+    self, copy_epipe_ok, name_error, open_read, open_write, path_error, read_stream, spawn_thread,
+    spawn_thread_sized, write_stream, Aborted, ThreadPanicked,
+};
+use crate::passfd::{ExtraFd, PassedFds};
+use crate::priority;
+#[cfg(target_os = "linux")]
+use crate::priority::IoPriority;
+use crate::progress::{Direction, ProgressCallback, ProgressReport};
+use crate::pty::{self, Pty, PtyReader};
+#[cfg(feature = "signals")]
+use crate::signals::Registration;
+use crate::stall::{Progress, ProgressReader, ProgressWriter, Watchdog};
+use crate::{
+    BufferedInput, Filter, OwnedPipeEnd, PipeOpts, ReadStream, RunningFilter, WriteStream,
+};
+
+/// On Linux, in-memory input up to this size is written straight into the child's stdin pipe
+/// without a copy thread. Pipes there hold at least one page even when the system is short on pipe
+/// buffers, so this can't block. Other systems don't promise that, so they always use the thread.
+#[cfg(target_os = "linux")]
+const SMALL_INPUT: usize = 4096;
 
 /// A filter that runs as a child process.
+///
+/// The process settings which are applied between fork and exec go in a fixed order, whatever
+/// order the builder methods were called in: the pseudo-terminal, extra file descriptors, resource
+/// limits, niceness, I/O priority, CPU affinity, umask, root directory, user and groups, and
+/// finally the parent death signal. If any of them fails, the command isn't run, and starting the
+/// child returns the error.
 pub struct ChildProcess {
     cmd: Command,
+    stderr: Option<WriteStream>,
+    merge_stderr: bool,
+    stderr_tail: Option<usize>,
+    kill_on_drop: bool,
+    process_group: bool,
+    input_epipe_ok: bool,
+    limits: Vec<Limit>,
+    nice: Option<i32>,
+    #[cfg(target_os = "linux")]
+    io_priority: Option<IoPriority>,
+    cpu_affinity: Option<Vec<usize>>,
+    copy_opts: CopyOpts,
+    copy_thread_cpu_affinity: Option<Vec<usize>>,
+    umask: Option<u32>,
+    chroot: Option<PathBuf>,
+    credentials: Credentials,
+    pty: bool,
+    pty_size: Option<(u16, u16)>,
+    extra_fds: Vec<(RawFd, ExtraFd)>,
+    tagged_output: Option<TaggedCallback>,
+    line_timing: Option<LineTiming>,
+    stall_timeout: Option<Duration>,
+    stall_counts_input: bool,
+    parent_death_signal: Option<i32>,
+    progress: Option<(Duration, ProgressCallback)>,
+    name: Option<String>,
 }
 
 impl ChildProcess {
     /// Create a [`ChildProcess`] from the given [`Command`]. Note: don't set up stdin or stdout of
     /// the command; those will be overwritten upon starting the filter.
     pub fn new(cmd: Command) -> Self {
-        Self { cmd }
+        Self {
+            cmd,
+            stderr: None,
+            merge_stderr: false,
+            stderr_tail: None,
+            kill_on_drop: false,
+            process_group: false,
+            input_epipe_ok: false,
+            limits: vec![],
+            nice: None,
+            #[cfg(target_os = "linux")]
+            io_priority: None,
+            cpu_affinity: None,
+            copy_opts: CopyOpts::default(),
+            copy_thread_cpu_affinity: None,
+            umask: None,
+            chroot: None,
+            credentials: Credentials::default(),
+            pty: false,
+            pty_size: None,
+            extra_fds: vec![],
+            tagged_output: None,
+            line_timing: None,
+            stall_timeout: None,
+            stall_counts_input: true,
+            parent_death_signal: None,
+            progress: None,
+            name: None,
+        }
+    }
+
+    /// Send the child's stderr to the given stream, instead of letting it inherit this process's
+    /// stderr. All the same kinds of streams as for stdout can be used; if it's
+    /// [`WriteStream::PipeRequested`], the pipe is available from [`RunningChild::stderr_pipe()`].
+    pub fn stderr(mut self, stderr: WriteStream) -> Self {
+        self.stderr = Some(stderr);
+        self
+    }
+
+    /// Send the child's stderr to the same place as its stdout, like `2>&1` in a shell. This can't
+    /// be combined with [`ChildProcess::stderr()`].
+    pub fn merge_stderr(mut self, merge: bool) -> Self {
+        self.merge_stderr = merge;
+        self
+    }
+
+    /// Kill the child with `SIGKILL` if the [`RunningChild`] is dropped without being waited on,
+    /// for example because of a panic. It's then reaped in the background, and any copy threads
+    /// are left to finish on their own. By default, the child is left running, and only reaped
+    /// once it exits.
+    pub fn kill_on_drop(mut self, kill: bool) -> Self {
+        self.kill_on_drop = kill;
+        self
+    }
+
+    /// Start the child in a new process group, with the child as its leader, so that it and any
+    /// processes it starts can be signalled together with [`RunningChild::signal_group()`].
+    ///
+    /// The new group is not the terminal's foreground group, so job control signals from the
+    /// terminal, such as `SIGINT` from Ctrl-C, aren't delivered to it; stopping the child is up to
+    /// this process. If the child tries to read from the terminal it is stopped with `SIGTTIN`.
+    pub fn new_process_group(mut self, new_group: bool) -> Self {
+        self.process_group = new_group;
+        self
+    }
+
+    /// Keep the last `max_bytes` of the child's stderr, and include them in the error from
+    /// [`ChildExit::combine()`] if the child fails. They are also available as
+    /// [`ChildExit::stderr_tail`]. The child's stderr doesn't go anywhere else, so this can't be
+    /// combined with [`ChildProcess::stderr()`] or [`ChildProcess::merge_stderr()`].
+    pub fn capture_stderr_tail(mut self, max_bytes: usize) -> Self {
+        self.stderr_tail = Some(max_bytes);
+        self
+    }
+
+    /// If the child stops reading its input before the end (as `head` does), treat the
+    /// [`BrokenPipe`](io::ErrorKind::BrokenPipe) error in the thread copying into its stdin as a
+    /// clean early stop rather than a failure. [`ChildExit::read_bytes`] then counts the bytes
+    /// that got through, and [`ChildExit::input_stopped_early`] is set.
+    ///
+    /// This only matters for input streams which need a copy thread, such as
+    /// [`ReadStream::Rust`]. By default, the error is reported.
+    pub fn input_epipe_ok(mut self, ok: bool) -> Self {
+        self.input_epipe_ok = ok;
+        self
+    }
+
+    /// Limit the child's use of a resource with `setrlimit`, applied after forking and before
+    /// running the command. Use [`Resource::UNLIMITED`] for no limit. Starting the child fails if
+    /// the limit can't be set, for example because the soft limit is above the hard one, or the
+    /// hard limit is above this process's own and it isn't privileged.
+    ///
+    /// A child which is killed for going over a limit shows up as the signal in [`ChildExit`];
+    /// see [`ChildExit::limit_exceeded()`].
+    pub fn limit(mut self, resource: Resource, soft: u64, hard: u64) -> Self {
+        self.limits.push(Limit {
+            resource,
+            soft,
+            hard,
+        });
+        self
+    }
+
+    /// Run the child with the given niceness, from -20 (most favourable scheduling) to 19 (least),
+    /// set with `setpriority` before running the command. This is the niceness itself, not an
+    /// increment like the `nice` command takes. Starting the child fails if it can't be set, for
+    /// example because lowering the niceness needs privileges.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Run the child with the given I/O scheduling class and priority, set with `ioprio_set`
+    /// before running the command. Starting the child fails if it can't be set.
+    #[cfg(target_os = "linux")]
+    pub fn io_priority(mut self, priority: IoPriority) -> Self {
+        self.io_priority = Some(priority);
+        self
+    }
+
+    /// Run the child only on the given logical CPUs, numbered from 0, set with
+    /// `sched_setaffinity` before running the command. The CPUs are checked against the number in
+    /// the system before anything is started. This needs Linux; elsewhere, starting the child
+    /// fails with [`Unsupported`](io::ErrorKind::Unsupported).
+    pub fn cpu_affinity(mut self, cpus: &[usize]) -> Self {
+        self.cpu_affinity = Some(cpus.to_vec());
+        self
+    }
+
+    /// Send the child the given signal if this process dies, so that it doesn't carry on as an
+    /// orphan, using `PR_SET_PDEATHSIG`. If this process has already died by the time the child
+    /// has set that up, the child signals itself instead.
+    ///
+    /// Linux sends the signal when the *thread* that started the child exits, not the whole
+    /// process, so start children with this option from a thread that lives as long as they
+    /// should. The setting is cleared if the child changes its user or group ID, including by
+    /// running a set-user-ID program, but not by [`ChildProcess::uid()`] and
+    /// [`ChildProcess::gid()`], which take effect first.
+    ///
+    /// Only Linux supports this; elsewhere, starting the child fails with
+    /// [`Unsupported`](io::ErrorKind::Unsupported).
+    pub fn parent_death_signal(mut self, sig: i32) -> Self {
+        self.parent_death_signal = Some(sig);
+        self
+    }
+
+    /// Call `callback` from the copy threads with how many bytes they've copied so far, at most
+    /// once per `interval` for each stream, and once more at the end with the total. Only streams
+    /// which need a copy thread are reported on: Rust streams, but not pipes or files given
+    /// straight to the child.
+    ///
+    /// The callback runs on the copy thread, between one read or write and the next, so a slow
+    /// callback slows the copy down. Anything more than a quick update should be handed off to
+    /// another thread.
+    pub fn on_progress(
+        mut self,
+        interval: Duration,
+        callback: impl Fn(Direction, u64) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some((interval, Arc::new(callback)));
+        self
+    }
+
+    /// Run the copy threads for the child's streams, if any are needed, with the given niceness,
+    /// like [`ChildProcess::nice()`] does for the child. If it can't be set, the thread fails
+    /// without copying anything. This needs Linux, where each thread has its own niceness; on
+    /// other systems the copy threads fail.
+    pub fn copy_thread_nice(mut self, nice: i32) -> Self {
+        self.copy_opts.nice = Some(nice);
+        self
+    }
+
+    /// Run the copy threads for the child's streams, if any are needed, only on the given logical
+    /// CPUs, like [`ChildProcess::cpu_affinity()`] does for the child. This needs Linux too.
+    pub fn copy_thread_cpu_affinity(mut self, cpus: &[usize]) -> Self {
+        self.copy_thread_cpu_affinity = Some(cpus.to_vec());
+        self
+    }
+
+    /// Give the copy threads for the child's streams, if any are needed, stacks of the given size,
+    /// instead of Rust's default. This matters for streams like
+    /// [`WriteStream::Rust`](crate::WriteStream::Rust) which run their own code on the copy
+    /// thread.
+    pub fn copy_thread_stack_size(mut self, bytes: usize) -> Self {
+        self.copy_opts.stack_size = Some(bytes);
+        self
+    }
+
+    /// Run the child with the given file mode creation mask, such as `0o077` to keep the files it
+    /// creates private, set with `umask` before running the command.
+    pub fn umask(mut self, mask: u32) -> Self {
+        self.umask = Some(mask);
+        self
+    }
+
+    /// Run the child with the given directory as its root, with `chroot` and then `chdir("/")`
+    /// before running the command. This needs root (or `CAP_SYS_CHROOT`); starting the child fails
+    /// otherwise. It's done before switching to another user with [`ChildProcess::uid()`], so the
+    /// child can still be unprivileged.
+    ///
+    /// The program is looked for inside the new root, along with any libraries it needs. The
+    /// working directory becomes the new root, replacing [`ChildProcess::current_dir()`], and
+    /// [`ChildProcess::check()`] still looks outside it.
+    pub fn chroot(mut self, root: impl Into<PathBuf>) -> Self {
+        self.chroot = Some(root.into());
+        self
+    }
+
+    /// Run the child as the given user, with `setuid` before running the command. This is for a
+    /// privileged process starting commands on behalf of others; if the user can't be switched
+    /// to, starting the child fails rather than running it with this process's privileges.
+    ///
+    /// Unless [`ChildProcess::groups()`] is used too, a child started by root loses all its
+    /// supplementary groups. The user, group, and groups are set after any other process
+    /// settings, such as [`ChildProcess::limit()`] and [`ChildProcess::nice()`], so that those can
+    /// still use this process's privileges.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.credentials.uid = Some(uid);
+        self
+    }
+
+    /// Run the child with the given group, with `setgid` before running the command. As with
+    /// [`ChildProcess::uid()`], starting the child fails if it can't be set.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.credentials.gid = Some(gid);
+        self
+    }
+
+    /// Run the child with the given supplementary groups, with `setgroups` before running the
+    /// command. An empty list drops them all. As with [`ChildProcess::uid()`], starting the child
+    /// fails if they can't be set.
+    pub fn groups(mut self, groups: &[u32]) -> Self {
+        self.credentials.groups = Some(groups.iter().map(|&g| g as libc::gid_t).collect());
+        self
+    }
+
+    /// Run the child on a pseudo-terminal, for programs which behave differently when their output
+    /// isn't a terminal. The terminal's slave end is the child's stdin, stdout, and (unless it's
+    /// redirected) stderr, and the child is the leader of a new session with it as the
+    /// controlling terminal, so [`RunningChild::signal_group()`] can be used.
+    ///
+    /// The master end is what [`RunningFilter::input_pipe()`] returns, if a pipe is requested;
+    /// otherwise the input is copied to it by a thread. The output is always copied from it by a
+    /// thread, into a pipe for [`RunningFilter::output_pipe()`] if one is requested: reading from
+    /// the master end fails with `EIO` once the child and anything it started have exited, and
+    /// the thread treats that as the end of the stream.
+    ///
+    /// The terminal handles its input a line at a time, and interprets control characters in it,
+    /// so it's no good for binary input. It doesn't echo the input, nor turn `\n` into `\r\n`
+    /// in the output. When input copied by a thread runs out, the thread sends the end-of-file
+    /// character, which only ends the child's input at the start of a line. See
+    /// [`RunningChild::send_eof()`].
+    pub fn pty(mut self, pty: bool) -> Self {
+        self.pty = pty;
+        self
+    }
+
+    /// Set the initial window size of the terminal for [`ChildProcess::pty()`], in rows and
+    /// columns. It can be changed later with [`RunningChild::set_window_size()`].
+    pub fn pty_size(mut self, rows: u16, cols: u16) -> Self {
+        self.pty_size = Some((rows, cols));
+        self
+    }
+
+    /// Give the child another file descriptor besides its stdio, at the given number, for
+    /// programs which take data on (say) fd 3 or have an option like `--status-fd=N`. A
+    /// [`ReadStream`] is something for the child to read, and a [`WriteStream`] something for it
+    /// to write; see [`ExtraFd`] for which streams are supported. If it's a requested pipe, this
+    /// process's end is available from [`RunningChild::extra_pipe()`].
+    ///
+    /// Starting the child fails if the number is 0, 1, or 2, or was already used. The file
+    /// descriptor is only left open across exec in this child.
+    pub fn pass_fd(mut self, target_fd: RawFd, stream: impl Into<ExtraFd>) -> Self {
+        self.extra_fds.push((target_fd, stream.into()));
+        self
+    }
+
+    /// Use a buffer of the given size in the copy threads for the child's streams, if any are
+    /// needed, instead of the default of 8 KiB. A bigger buffer means fewer system calls when the
+    /// other side of the copy is a fast in-memory stream.
+    pub fn copy_buffer_size(mut self, bytes: usize) -> Self {
+        self.copy_opts.buffer_size = Some(bytes);
+        self
+    }
+
+    /// Read both the child's stdout and its stderr, and pass each chunk of either to `callback`
+    /// along with where it came from, for a single stream of everything the child printed.
+    ///
+    /// The two streams are read by separate threads, which take turns calling `callback` as data
+    /// arrives. The order is only as good as that: chunks written close together on different
+    /// streams may be seen in either order, and a chunk may hold several of the child's writes.
+    ///
+    /// The callback takes the place of the child's output, so the filter must be started with
+    /// [`WriteStream::Null`] as its output, and this can't be combined with the other ways of
+    /// redirecting stderr.
+    pub fn tagged_output(
+        mut self,
+        callback: impl FnMut(OutputSource, &[u8]) + Send + 'static,
+    ) -> Self {
+        self.tagged_output = Some(Box::new(callback));
+        self
+    }
+
+    /// Record when each line of the child's stdout and stderr was produced, for finding out where
+    /// a slow pipeline is spending its time. The lines are available from
+    /// [`RunningChild::timed_lines()`] and [`ChildExit::timed_lines`], or as they arrive from a
+    /// callback given to [`LineTiming::on_line()`](crate::LineTiming::on_line).
+    ///
+    /// Both streams still go where they otherwise would, but through copy threads, so that they
+    /// can be split into lines on the way. A line is timestamped when its newline is read, which
+    /// is when the child wrote it, give or take the child's own buffering. A last line without a
+    /// newline is recorded when the stream ends.
+    pub fn time_lines(mut self, timing: LineTiming) -> Self {
+        self.line_timing = Some(timing);
+        self
+    }
+
+    /// Kill the child with `SIGKILL` if no data moves through its stdout for `timeout`, for
+    /// commands which can hang forever, like network clients. [`ChildExit::stalled`] records that
+    /// this happened, and [`ChildExit::combine()`] reports it as
+    /// [`ChildExitErrorKind::Stalled`] rather than an ordinary kill.
+    ///
+    /// By default, data moving into the child's stdin counts too, so that a child which is busy
+    /// consuming its input without printing anything isn't killed; see
+    /// [`ChildProcess::stall_counts_input()`]. Data is counted as it passes through copy threads,
+    /// so both streams get one, even if they would otherwise be pipes or file descriptors given
+    /// straight to the child.
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Whether data moving into the child's stdin counts as progress for
+    /// [`ChildProcess::stall_timeout()`]. It does by default.
+    pub fn stall_counts_input(mut self, counts: bool) -> Self {
+        self.stall_counts_input = counts;
+        self
+    }
+
+    /// Create a [`ChildProcess`] running the given program with the given arguments.
+    pub fn command(
+        program: impl AsRef<OsStr>,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> Self {
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        Self::new(cmd)
+    }
+
+    /// Create a [`ChildProcess`] running the given script with `/bin/sh -c`. The exit status is the
+    /// shell's, which for a pipeline is normally the status of its last command.
+    ///
+    /// The script is interpreted by the shell, so never build it by pasting in data from
+    /// elsewhere: quotes, `;`, `$(...)` and the like in that data would be run as shell syntax.
+    /// Pass such values with [`ChildProcess::shell_args()`] instead.
+    pub fn shell(script: impl Into<String>) -> Self {
+        Self::shell_args(script, std::iter::empty::<&OsStr>())
+    }
+
+    /// Like [`ChildProcess::shell()`], but passing the given arguments to the script, where they
+    /// are available as `"$1"`, `"$2"`, ... or all together as `"$@"`. They aren't interpreted by
+    /// the shell unless the script does so.
+    pub fn shell_args(
+        script: impl Into<String>,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> Self {
+        Self::shell_program("/bin/sh", script, args)
+    }
+
+    /// Like [`ChildProcess::shell_args()`], but with a different shell, which must accept
+    /// `-c script name args...` like `sh` does.
+    pub fn shell_program(
+        shell: impl AsRef<OsStr>,
+        script: impl Into<String>,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> Self {
+        let mut cmd = Command::new(&shell);
+        // The argument after the script becomes $0.
+        cmd.arg("-c").arg(script.into()).arg(&shell).args(args);
+        Self::new(cmd)
+    }
+
+    /// Add an argument to the command.
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.cmd.arg(arg);
+        self
+    }
+
+    /// Set an environment variable for the command.
+    pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> Self {
+        self.cmd.env(key, value);
+        self
+    }
+
+    /// Set the working directory for the command.
+    pub fn current_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.cmd.current_dir(dir);
+        self
+    }
+
+    /// Give the filter a name, which is included in errors and used to name its copy threads.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Check that the program exists and is executable, looking for it in `PATH` (the command's
+    /// own, if it sets one) unless it's a path. Checking every filter in a chain before starting
+    /// any of them avoids leaving half a chain running when one can't start.
+    ///
+    /// This can't catch everything, since the file could change in the meantime, or not be a
+    /// valid executable. [`Filter::start()`] also says which program it couldn't run.
+    pub fn check(&self) -> Result<(), SpawnCheckError> {
+        check::check_program(&self.cmd)
+    }
+
+    pub(crate) fn command_ref(&self) -> &Command {
+        &self.cmd
+    }
+
+    /// Name one of the child's threads after the filter, or if it has no name, the program.
+    fn thread_name(&self, what: &str) -> Option<String> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => program_name(self.cmd.get_program()),
+        };
+        Some(format!("{name} {what}"))
+    }
+
+    /// Set up one of the child's output streams, returning what to give the child, our end of the
+    /// pipe if we made one, and the copy thread if one is needed.
+    fn child_output(
+        &self,
+        output: WriteStream,
+        direction: Direction,
+        completion: &Completion,
+    ) -> io::Result<(ChildStdio, Option<OwnedPipeEnd>, Option<CopyThread>)> {
+        Ok(match output {
+            WriteStream::Null => (ChildStdio::Null, None, None),
+            WriteStream::PipeRequested => (ChildStdio::Piped, None, None),
+            WriteStream::Pipe(opts) => {
+                let (rx, tx) = misc::output_pipe(opts)?;
+                (ChildStdio::Fd(tx.into()), Some(rx.into()), None)
+            }
+            WriteStream::Inherit => (ChildStdio::Inherit, None, None),
+            WriteStream::Fd(fd) => (ChildStdio::Fd(fd), None, None),
+            WriteStream::File { path, options } => (
+                ChildStdio::Fd(open_write(&path, options)?.into()),
+                None,
+                None,
+            ),
+            other => {
+                let (w, _) = write_stream(other)?;
+                let w = ProgressReport::new(w, direction, self.progress.clone());
+                let what = match direction {
+                    Direction::Stderr => "stderr",
+                    _ => "stdout",
+                };
+                let (tx, t) = copy_from_child(
+                    self.thread_name(what),
+                    w,
+                    self.copy_opts,
+                    completion.guard(),
+                )?;
+                (ChildStdio::Fd(tx.into()), None, Some(t))
+            }
+        })
+    }
+}
+
+/// What to give the child for one of its output streams. Unlike [`Stdio`], this can be duplicated
+/// for [`ChildProcess::merge_stderr()`].
+enum ChildStdio {
+    Null,
+    Inherit,
+    Piped,
+    Fd(OwnedPipeEnd),
+}
+
+impl ChildStdio {
+    /// Duplicate stdout for use as stderr.
+    fn dup_for_stderr(&self) -> io::Result<ChildStdio> {
+        Ok(match self {
+            ChildStdio::Null => ChildStdio::Null,
+            // Inheriting would give the child our stderr, not our stdout.
+            ChildStdio::Inherit => ChildStdio::Fd(io::stdout().as_fd().try_clone_to_owned()?),
+            ChildStdio::Piped => unreachable!("merged stdout always uses a pipe we own"),
+            ChildStdio::Fd(fd) => ChildStdio::Fd(fd.try_clone()?),
+        })
+    }
+}
+
+impl From<ChildStdio> for Stdio {
+    fn from(stdio: ChildStdio) -> Self {
+        match stdio {
+            ChildStdio::Null => Stdio::null(),
+            ChildStdio::Inherit => Stdio::inherit(),
+            ChildStdio::Piped => Stdio::piped(),
+            ChildStdio::Fd(fd) => fd.into(),
+        }
     }
 }
 
+type CopyThread = JoinHandle<io::Result<u64>>;
+
 impl Filter for ChildProcess {
     type Running = RunningChild;
     type Error = io::Error;
 
-    fn start(mut self, input: ReadStream, output: WriteStream) -> io::Result<Self::Running> {
+    fn start(self, input: ReadStream, output: WriteStream) -> io::Result<Self::Running> {
+        self.start_retaining(input, output, false)
+            .map(|(running, _)| running)
+    }
+}
+
+impl ChildProcess {
+    /// Start the child. If `retain` is set, the [`Command`] is returned too, still holding the
+    /// child's ends of its pipes so that it can be spawned again. [`Command`]'s own pipes aren't
+    /// used then, since each spawn would make new ones.
+    pub(crate) fn start_retaining(
+        mut self,
+        input: ReadStream,
+        output: WriteStream,
+        retain: bool,
+    ) -> io::Result<(RunningChild, Option<Command>)> {
+        // Check for conflicting options before the input is touched, so a mistake doesn't use it
+        // up or leave threads copying it.
+        if self.merge_stderr && self.stderr.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't both merge stderr into stdout and redirect it",
+            ));
+        }
+        if self.tagged_output.is_some()
+            && (self.merge_stderr
+                || self.stderr.is_some()
+                || self.stderr_tail.is_some()
+                || !matches!(output, WriteStream::Null))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't both tag the output and send it somewhere else",
+            ));
+        }
+        if self.stderr_tail.is_some() && (self.merge_stderr || self.stderr.is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't both capture the tail of stderr and redirect it",
+            ));
+        }
+        let command_line = self.to_string();
+        let program = self.cmd.get_program().to_owned();
+        let args = self.cmd.get_args().map(OsStr::to_owned).collect();
+        #[cfg(not(target_os = "linux"))]
+        if self.parent_death_signal.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "parent death signals are only supported on Linux",
+            ));
+        }
+        let cpu_affinity = self.cpu_affinity.as_deref().map(CpuSet::new).transpose()?;
+        if let Some(cpus) = &self.copy_thread_cpu_affinity {
+            self.copy_opts.cpus = Some(CpuSet::new(cpus)?);
+        }
+        let input = match input {
+            ReadStream::PipeRequested if retain => ReadStream::Pipe(PipeOpts::new()),
+            other => other,
+        };
+        if retain && matches!(self.stderr, Some(WriteStream::PipeRequested)) {
+            self.stderr = Some(WriteStream::Pipe(PipeOpts::new()));
+        }
         let mut t1 = None;
-        let mut t2 = None;
-        match input {
-            ReadStream::Null => {
-                self.cmd.stdin(Stdio::null());
+        let mut input_pipe = None;
+        let completion = Completion::new();
+        let stdin_stop = Arc::new(AtomicBool::new(false));
+        let stdin_epipe = Arc::new(AtomicBool::new(false));
+        let progress = self.stall_timeout.map(|_| Progress::new());
+        let mut input = input;
+        let mut tapped_input_pipe = None;
+        if let Some(progress) = &progress {
+            if self.stall_counts_input && !matches!(input, ReadStream::Null) {
+                let (r, tx) = read_stream(input)?;
+                tapped_input_pipe = tx.map(OwnedPipeEnd::from);
+                input = ReadStream::Rust(Box::new(ProgressReader {
+                    inner: r,
+                    progress: progress.clone(),
+                }));
             }
-            ReadStream::PipeRequested => {
-                self.cmd.stdin(Stdio::piped());
+        }
+        let pty = if self.pty {
+            Some(Pty::open(self.pty_size)?)
+        } else {
+            None
+        };
+        if let Some(pty) = &pty {
+            self.cmd.stdin(pty.slave.try_clone()?);
+            match input {
+                ReadStream::Null => (),
+                ReadStream::PipeRequested | ReadStream::Pipe(_) => {
+                    input_pipe = Some(pty.master.try_clone()?);
+                }
+                other => {
+                    let (r, _) = read_stream(other)?;
+                    let r = StopReader {
+                        inner: r,
+                        stop: Arc::clone(&stdin_stop),
+                    };
+                    let r = ProgressReport::new(r, Direction::Input, self.progress.clone());
+                    t1 = Some(copy_to_pty(
+                        self.thread_name("stdin"),
+                        r,
+                        pty.master.try_clone()?,
+                        self.copy_opts,
+                        completion.guard(),
+                    )?);
+                }
             }
-            ReadStream::Fd(fd) => {
-                self.cmd.stdin(fd);
+        } else {
+            match input {
+                ReadStream::Null => {
+                    self.cmd.stdin(Stdio::null());
+                }
+                ReadStream::PipeRequested => {
+                    self.cmd.stdin(Stdio::piped());
+                }
+                ReadStream::Pipe(opts) => {
+                    let (rx, tx) = misc::input_pipe(opts)?;
+                    self.cmd.stdin(rx);
+                    input_pipe = Some(tx.into());
+                }
+                ReadStream::Inherit => {
+                    self.cmd.stdin(Stdio::inherit());
+                }
+                ReadStream::Fd(fd) => {
+                    self.cmd.stdin(fd);
+                }
+                ReadStream::File(path) => {
+                    self.cmd.stdin(open_read(&path)?);
+                }
+                #[cfg(target_os = "linux")]
+                ReadStream::Bytes(b) if b.len() <= SMALL_INPUT => {
+                    let (rx, mut tx) = os_pipe::pipe()?;
+                    tx.write_all(&b)?;
+                    self.cmd.stdin(rx);
+                }
+                other => {
+                    // Everything else needs a thread to copy from a Rust stream.
+                    let (r, _) = read_stream(other)?;
+                    let name = self.thread_name("stdin");
+                    let r = StopReader {
+                        inner: r,
+                        stop: Arc::clone(&stdin_stop),
+                    };
+                    let r = ProgressReport::new(r, Direction::Input, self.progress.clone());
+                    let epipe = self.input_epipe_ok.then(|| Arc::clone(&stdin_epipe));
+                    t1 = Some(copy_to_stdin(
+                        &mut self.cmd,
+                        name,
+                        r,
+                        epipe,
+                        self.copy_opts,
+                        completion.guard(),
+                    )?);
+                }
             }
-            ReadStream::Rust(mut s) => {
-                let (rx, mut tx) = os_pipe::pipe()?;
-                t1 = Some(thread::spawn(move || io::copy(&mut s, &mut tx)));
-                self.cmd.stdin(rx);
+        }
+
+        let input_pipe = input_pipe.or(tapped_input_pipe);
+
+        let mut output = output;
+        if let Some(callback) = self.tagged_output.take() {
+            let callback = Arc::new(Mutex::new(callback));
+            output = WriteStream::Rust(Box::new(TaggedWriter {
+                source: OutputSource::Stdout,
+                callback: Arc::clone(&callback),
+            }));
+            self.stderr = Some(WriteStream::Rust(Box::new(TaggedWriter {
+                source: OutputSource::Stderr,
+                callback,
+            })));
+        }
+        let mut stderr_tail = None;
+        if let Some(max_bytes) = self.stderr_tail {
+            let tail = StderrTail::new(max_bytes);
+            stderr_tail = Some(tail.clone());
+            self.stderr = Some(WriteStream::Rust(Box::new(tail)));
+        }
+        let mut line_log = None;
+        let mut tapped_output_pipe = None;
+        let mut tapped_error_pipe = None;
+        if let Some(timing) = self.line_timing.take() {
+            let log = LineLog::new(timing);
+            let (w, rx) = write_stream(output)?;
+            tapped_output_pipe = rx.map(OwnedPipeEnd::from);
+            output =
+                WriteStream::Rust(Box::new(LineTap::new(w, OutputSource::Stdout, log.clone())));
+            let stderr = match self.stderr.take() {
+                // It goes wherever stdout does, so it's already tapped.
+                None if self.merge_stderr || pty.is_some() => None,
+                // Inheriting would give the tap our stdout, not our stderr.
+                None | Some(WriteStream::Inherit) => {
+                    Some(WriteStream::Rust(Box::new(io::stderr())))
+                }
+                stderr => stderr,
+            };
+            if let Some(stderr) = stderr {
+                let (w, rx) = write_stream(stderr)?;
+                tapped_error_pipe = rx.map(OwnedPipeEnd::from);
+                self.stderr = Some(WriteStream::Rust(Box::new(LineTap::new(
+                    w,
+                    OutputSource::Stderr,
+                    log.clone(),
+                ))));
+            }
+            line_log = Some(log);
+        }
+        if let Some(progress) = &progress {
+            let (w, rx) = write_stream(output)?;
+            if let Some(rx) = rx {
+                tapped_output_pipe = Some(rx.into());
+            }
+            output = WriteStream::Rust(Box::new(ProgressWriter {
+                inner: w,
+                progress: progress.clone(),
+            }));
+        }
+        let output = match output {
+            // Command's own pipe can't be shared with stderr, so make one ourselves.
+            WriteStream::PipeRequested if self.merge_stderr || retain => {
+                WriteStream::Pipe(PipeOpts::new())
             }
+            other => other,
+        };
+        let (stdout, output_pipe, t2) = match &pty {
+            Some(pty) => {
+                // Even a pipe is fed by a copy thread, so that whatever reads it doesn't need to
+                // know that the master end of a pty ends with EIO rather than end-of-file.
+                let stdout = ChildStdio::Fd(pty.slave.try_clone()?);
+                let (w, pipe) = write_stream(output)?;
+                let w = ProgressReport::new(w, Direction::Stdout, self.progress.clone());
+                let r = PtyReader(File::from(pty.master.try_clone()?));
+                let t = copy_thread(
+                    self.thread_name("stdout"),
+                    r,
+                    w,
+                    self.copy_opts,
+                    completion.guard(),
+                )?;
+                (stdout, pipe.map(Into::into), Some(t))
+            }
+            None => self.child_output(output, Direction::Stdout, &completion)?,
+        };
+        let output_pipe = output_pipe.or(tapped_output_pipe);
+        if self.merge_stderr {
+            self.cmd.stderr(stdout.dup_for_stderr()?);
         }
+        self.cmd.stdout(stdout);
 
-        match output {
-            WriteStream::Null => {
-                self.cmd.stdout(Stdio::null());
+        let mut error_pipe = None;
+        let mut t3 = None;
+        if let Some(stderr) = self.stderr.take() {
+            let (stderr, pipe, t) = self.child_output(stderr, Direction::Stderr, &completion)?;
+            self.cmd.stderr(stderr);
+            error_pipe = pipe.or(tapped_error_pipe);
+            t3 = t;
+        } else if let Some(pty) = &pty {
+            if !self.merge_stderr {
+                self.cmd.stderr(pty.slave.try_clone()?);
             }
-            WriteStream::PipeRequested => {
-                self.cmd.stdout(Stdio::piped());
+        }
+
+        if pty.is_some() {
+            // SAFETY: setsid and ioctl are async-signal-safe.
+            unsafe { self.cmd.pre_exec(pty::setup_child) };
+        }
+        let mut extra_pipes = vec![];
+        if !self.extra_fds.is_empty() {
+            let (passed, pipes) = PassedFds::open(std::mem::take(&mut self.extra_fds))?;
+            extra_pipes = pipes;
+            // SAFETY: the hook only calls dup2 and fcntl, which are async-signal-safe.
+            unsafe { self.cmd.pre_exec(move || passed.apply()) };
+        }
+
+        if !self.limits.is_empty() {
+            let limits = std::mem::take(&mut self.limits);
+            // SAFETY: the hook only calls setrlimit, which is async-signal-safe, with limits
+            // prepared beforehand.
+            unsafe {
+                self.cmd
+                    .pre_exec(move || limits.iter().try_for_each(Limit::apply));
             }
-            WriteStream::Fd(fd) => {
-                self.cmd.stdout(fd);
+        }
+        if let Some(nice) = self.nice {
+            // SAFETY: setpriority is async-signal-safe.
+            unsafe { self.cmd.pre_exec(move || priority::set_nice(nice)) };
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(io_priority) = self.io_priority {
+            // SAFETY: ioprio_set is async-signal-safe.
+            unsafe { self.cmd.pre_exec(move || io_priority.apply()) };
+        }
+        if let Some(cpus) = cpu_affinity {
+            // SAFETY: sched_setaffinity is async-signal-safe, and the set was prepared beforehand.
+            unsafe { self.cmd.pre_exec(move || cpus.apply()) };
+        }
+        if let Some(mask) = self.umask {
+            // SAFETY: umask is async-signal-safe.
+            unsafe { self.cmd.pre_exec(move || set_umask(mask)) };
+        }
+        if let Some(root) = &self.chroot {
+            let root = CString::new(root.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // SAFETY: chroot and chdir are async-signal-safe, and the path was prepared beforehand.
+            unsafe { self.cmd.pre_exec(move || change_root(&root)) };
+        }
+        if !self.credentials.is_empty() {
+            let credentials = std::mem::take(&mut self.credentials);
+            // SAFETY: the hook only makes async-signal-safe calls, with IDs prepared beforehand.
+            unsafe { self.cmd.pre_exec(move || credentials.apply()) };
+        }
+        // After the credentials, since changing them clears it.
+        #[cfg(target_os = "linux")]
+        if let Some(sig) = self.parent_death_signal {
+            // SAFETY: FFI call with no pointers.
+            let parent = unsafe { libc::getpid() };
+            // SAFETY: prctl, getppid, getpid and kill are async-signal-safe.
+            unsafe {
+                self.cmd
+                    .pre_exec(move || set_parent_death_signal(sig, parent));
             }
-            WriteStream::Rust(mut s) => {
-                let (mut rx, tx) = os_pipe::pipe()?;
-                t2 = Some(thread::spawn(move || io::copy(&mut rx, &mut s)));
-                self.cmd.stdout(tx);
+        }
+        // A new session is a new process group too, and setpgid would stop setsid from working.
+        if self.process_group && pty.is_none() {
+            // std calls setpgid in the child between fork and exec, which is async-signal-safe.
+            self.cmd.process_group(0);
+        }
+        let child = self.cmd.spawn().map_err(|e| {
+            // These are about the program, so say which one. Others, such as from pre_exec
+            // hooks, are left alone.
+            let e = match e.raw_os_error() {
+                Some(libc::ENOENT | libc::EACCES | libc::ENOEXEC) => {
+                    path_error(Path::new(self.cmd.get_program()), e)
+                }
+                _ => e,
+            };
+            name_error(self.name.as_deref(), e)
+        })?;
+        let child_pid = child.id();
+        let watchdog = match self.stall_timeout.zip(progress) {
+            Some((timeout, progress)) => {
+                match Watchdog::start(child_pid, timeout, progress, self.thread_name("watchdog")) {
+                    Ok(watchdog) => Some(watchdog),
+                    Err(e) => {
+                        let mut child = child;
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(e);
+                    }
+                }
             }
+            None => None,
         };
 
-        let child = self.cmd.spawn()?;
+        // Close our copies of the child's ends of any pipes right away, so that the only thing
+        // holding them open is the child. (All the pipes we create are close-on-exec, so they
+        // don't leak into other children either; Command takes care of making the stdio ends
+        // inheritable in this child only.)
+        let cmd = if retain {
+            Some(self.cmd)
+        } else {
+            drop(self.cmd);
+            None
+        };
 
-        Ok(RunningChild {
+        let running = RunningChild {
             child,
-            threads: [t1, t2],
-        })
+            threads: [t1, t2, t3],
+            input_pipe,
+            output_pipe,
+            error_pipe,
+            stderr_tail,
+            line_log,
+            #[cfg(feature = "signals")]
+            forwarding: None,
+            watchdog,
+            reap_on_drop: Some(ReapOnDrop {
+                pid: child_pid,
+                kill: self.kill_on_drop,
+            }),
+            process_group: self.process_group || pty.is_some(),
+            pty: pty.map(|pty| pty.master),
+            extra_pipes,
+            command_line,
+            program,
+            args,
+            stdin_stop,
+            stdin_epipe,
+            aborted: false,
+            reaped: false,
+            completion,
+            waiter_started: false,
+            waiter_error: None,
+            name: self.name,
+        };
+        Ok((running, cmd))
+    }
+}
+
+/// Attach a pipe to the command's stdin and start a thread copying the given stream into it.
+///
+/// If `epipe` is given, the child closing its stdin early isn't an error, and sets the flag.
+///
+/// The write end stays in this process, so it must be close-on-exec (which pipes from `os_pipe`
+/// are); otherwise later children could inherit it and keep this child from ever seeing EOF.
+fn copy_to_stdin(
+    cmd: &mut Command,
+    thread_name: Option<String>,
+    mut r: impl Read + Send + 'static,
+    epipe: Option<Arc<AtomicBool>>,
+    opts: CopyOpts,
+    guard: CompletionGuard,
+) -> io::Result<JoinHandle<io::Result<u64>>> {
+    let (rx, mut tx) = os_pipe::pipe()?;
+    cmd.stdin(rx);
+    spawn_thread_sized(thread_name, opts.stack_size, move || {
+        let _guard = guard;
+        opts.setup_thread()?;
+        let (n, cut_short) = copy_epipe_ok(&mut r, &mut tx, epipe.is_some(), opts.buffer_size)?;
+        if cut_short {
+            epipe.unwrap().store(true, Ordering::SeqCst);
+        }
+        Ok(n)
+    })
+}
+
+/// Start a thread copying the given stream into the master end of a pty, and then sending the
+/// end-of-file character.
+fn copy_to_pty(
+    thread_name: Option<String>,
+    mut r: impl Read + Send + 'static,
+    master: OwnedPipeEnd,
+    opts: CopyOpts,
+    guard: CompletionGuard,
+) -> io::Result<CopyThread> {
+    spawn_thread_sized(thread_name, opts.stack_size, move || {
+        let _guard = guard;
+        opts.setup_thread()?;
+        let mut w = File::from(master);
+        let n = misc::copy(&mut r, &mut w, opts.buffer_size)?;
+        pty::send_eof(&w)?;
+        Ok(n)
+    })
+}
+
+/// Ends the stream early once `stop` is set, so that a copy thread can be told to finish.
+struct StopReader<R> {
+    inner: R,
+    stop: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for StopReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.stop.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Make a pipe for one of the child's output streams and start a thread copying from it to the
+/// given stream. Returns the end of the pipe to give to the child.
+fn copy_from_child(
+    thread_name: Option<String>,
+    w: impl Write + Send + 'static,
+    opts: CopyOpts,
+    guard: CompletionGuard,
+) -> io::Result<(PipeWriter, CopyThread)> {
+    let (rx, tx) = os_pipe::pipe()?;
+    let t = copy_thread(thread_name, rx, w, opts, guard)?;
+    Ok((tx, t))
+}
+
+/// Start a thread copying `r` to `w`.
+fn copy_thread(
+    thread_name: Option<String>,
+    mut r: impl Read + Send + 'static,
+    mut w: impl Write + Send + 'static,
+    opts: CopyOpts,
+    guard: CompletionGuard,
+) -> io::Result<CopyThread> {
+    spawn_thread_sized(thread_name, opts.stack_size, move || {
+        let _guard = guard;
+        opts.setup_thread()?;
+        misc::copy(&mut r, &mut w, opts.buffer_size)
+    })
+}
+
+/// The last part of the program's path, to name threads after.
+fn program_name(program: &OsStr) -> String {
+    Path::new(program)
+        .file_name()
+        .unwrap_or(program)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Options for the copy threads of a [`ChildProcess`].
+#[derive(Debug, Clone, Copy, Default)]
+struct CopyOpts {
+    nice: Option<i32>,
+    cpus: Option<CpuSet>,
+    buffer_size: Option<usize>,
+    stack_size: Option<usize>,
+}
+
+impl CopyOpts {
+    /// Called by each copy thread before it starts copying.
+    fn setup_thread(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice {
+            priority::set_thread_nice(nice)?;
+        }
+        if let Some(cpus) = &self.cpus {
+            cpus.apply()?;
+        }
+        Ok(())
+    }
+}
+
+/// Which of a child's output streams a chunk of data came from, for
+/// [`ChildProcess::tagged_output()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSource {
+    /// The child's stdout.
+    Stdout,
+    /// The child's stderr.
+    Stderr,
+}
+
+type TaggedCallback = Box<dyn FnMut(OutputSource, &[u8]) + Send>;
+
+/// Passes everything written to it to a callback shared with the other stream, for
+/// [`ChildProcess::tagged_output()`].
+struct TaggedWriter {
+    source: OutputSource,
+    callback: Arc<Mutex<TaggedCallback>>,
+}
+
+impl Write for TaggedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (self.callback.lock())(self.source, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the last bytes written to it, for [`ChildProcess::capture_stderr_tail()`].
+#[derive(Clone)]
+struct StderrTail(Arc<Mutex<(VecDeque<u8>, bool)>>, usize);
+
+impl StderrTail {
+    fn new(max_bytes: usize) -> Self {
+        Self(Arc::new(Mutex::new((VecDeque::new(), false))), max_bytes)
+    }
+
+    /// The bytes kept, prefixed with "..." if earlier ones were dropped.
+    fn into_bytes(self) -> Vec<u8> {
+        let (tail, truncated) = &*self.0.lock();
+        let mut bytes = if *truncated { b"...".to_vec() } else { vec![] };
+        bytes.extend(tail);
+        bytes
+    }
+}
+
+impl Write for StderrTail {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (tail, truncated) = &mut *self.0.lock();
+        tail.extend(buf);
+        if tail.len() > self.1 {
+            tail.drain(..tail.len() - self.1);
+            *truncated = true;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reaps a child process when dropped, so that it doesn't stay a zombie, and first kills it if
+/// [`ChildProcess::kill_on_drop()`] was used.
+struct ReapOnDrop {
+    pid: u32,
+    kill: bool,
+}
+
+impl ReapOnDrop {
+    /// Drop without touching the child, because it's been (or is about to be) reaped, or the
+    /// caller has taken it over.
+    fn disarm(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for ReapOnDrop {
+    fn drop(&mut self) {
+        let pid = self.pid as libc::pid_t;
+        if self.kill {
+            // SAFETY: FFI call with no pointers. The child hasn't been reaped, so the PID is still
+            // ours.
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+        if !try_reap_pid(pid) {
+            reap_later(pid);
+        }
+    }
+}
+
+/// Reap the given child if it has exited, returning whether it's gone.
+fn try_reap_pid(pid: libc::pid_t) -> bool {
+    // SAFETY: FFI call with a null status pointer, which waitpid allows.
+    let ret = unsafe { libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) };
+    // An error means there's nothing to reap (or never will be).
+    ret != 0
+}
+
+/// Hand a child which hasn't exited yet to a background thread, which reaps it when it does. The
+/// thread is started the first time it's needed, and checks on its children periodically, since
+/// waiting for any child would steal the ones other code is waiting for.
+fn reap_later(pid: libc::pid_t) {
+    static REAPER: Mutex<Option<mpsc::Sender<libc::pid_t>>> = Mutex::new(None);
+    let mut reaper = REAPER.lock();
+    if let Some(tx) = &*reaper {
+        if tx.send(pid).is_ok() {
+            return;
+        }
+    }
+    let (tx, rx) = mpsc::channel();
+    let started = spawn_thread(Some("child reaper".to_owned()), move || {
+        let mut pids = vec![pid];
+        loop {
+            let next = if pids.is_empty() {
+                rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+            } else {
+                rx.recv_timeout(Duration::from_millis(100))
+            };
+            match next {
+                Ok(pid) => pids.push(pid),
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+            pids.retain(|&pid| !try_reap_pid(pid));
+        }
+    });
+    // If the thread can't be started, the child stays a zombie, which is the best we can do.
+    if started.is_ok() {
+        *reaper = Some(tx);
     }
 }
 
 /// A running child process.
 pub struct RunningChild {
     child: Child,
-    threads: [Option<JoinHandle<io::Result<u64>>>; 2],
+    threads: [Option<CopyThread>; 3],
+    // Pipes we created ourselves, rather than having Command do it.
+    input_pipe: Option<OwnedPipeEnd>,
+    output_pipe: Option<OwnedPipeEnd>,
+    error_pipe: Option<OwnedPipeEnd>,
+    stderr_tail: Option<StderrTail>,
+    line_log: Option<LineLog>,
+    // Only present until the child is reaped. These have to be dropped before reap_on_drop, which
+    // could otherwise free the PID while signals can still be sent to it.
+    #[cfg(feature = "signals")]
+    forwarding: Option<Registration>,
+    watchdog: Option<Watchdog>,
+    // Only present until the child is reaped, or the caller takes it over.
+    reap_on_drop: Option<ReapOnDrop>,
+    process_group: bool,
+    // Our own copy of the master end of the pty, if there is one.
+    pty: Option<OwnedPipeEnd>,
+    // Our ends of pipes for ChildProcess::pass_fd(), by the child's fd number.
+    extra_pipes: Vec<(RawFd, OwnedPipeEnd)>,
+    command_line: String,
+    program: OsString,
+    args: Vec<OsString>,
+    // Tells the stdin copy thread to stop early.
+    stdin_stop: Arc<AtomicBool>,
+    // Set by the stdin copy thread if the child closed its stdin early.
+    stdin_epipe: Arc<AtomicBool>,
+    aborted: bool,
+    reaped: bool,
+    completion: Completion,
+    waiter_started: bool,
+    // Why the thread waiting for the child to exit couldn't be started, if it couldn't.
+    waiter_error: Option<io::Error>,
+    name: Option<String>,
+}
+
+impl std::fmt::Debug for RunningChild {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RunningChild")
+            .field("pid", &self.child.id())
+            .field("command_line", &self.command_line)
+            .field("name", &self.name)
+            .field("reaped", &self.reaped)
+            .finish_non_exhaustive()
+    }
 }
 
 impl RunningFilter for RunningChild {
-    /// The result from the process, and the results from the threads doing copies to the input and
-    /// output pipes, respectively, if either were created.
+    /// The result from the process, and the results from the threads doing copies to the input,
+    /// output, and error pipes, if any were created.
     type Result = ChildExit;
 
     fn wait(mut self) -> Self::Result {
-        let errs = self.threads.map(|t| match t {
-            Some(t) => match t.join() {
-                Err(_) => Some(Err(ThreadPanicked::ioerr())),
-                Ok(Err(e)) => Some(Err(e)),
-                Ok(Ok(_n)) => Some(Ok(())),
-            },
+        if let Some(guard) = self.reap_on_drop.take() {
+            guard.disarm();
+        }
+        self.detach_signallers();
+        let aborted = self.aborted;
+        let results = self.threads.map(|t| match t {
+            Some(t) if aborted && !t.is_finished() => Some(Err(Aborted::ioerr())),
+            Some(t) => Some(t.join().unwrap_or_else(|p| Err(ThreadPanicked::ioerr(p)))),
             None => None,
         });
-        let [read_thread, write_thread] = errs;
+        let [read_bytes, write_bytes, err_bytes] = results
+            .each_ref()
+            .map(|r| r.as_ref()?.as_ref().ok().copied());
+        let [read_thread, write_thread, err_thread] = results.map(|r| r.map(|r| r.map(drop)));
+        let child = self.child.wait();
         ChildExit {
-            child: self.child.wait(),
+            child,
             read_thread,
             write_thread,
+            err_thread,
+            read_bytes,
+            write_bytes,
+            err_bytes,
+            input_stopped_early: self.stdin_epipe.load(Ordering::SeqCst),
+            shutdown: None,
+            stderr_tail: self.stderr_tail.map(StderrTail::into_bytes),
+            timed_lines: self.line_log.map(|log| log.lines()),
+            stalled: self.watchdog.as_ref().and_then(Watchdog::stalled),
+            command_line: self.command_line,
+            program: self.program,
+            args: self.args,
+            waiter: self.waiter_error,
+            name: self.name,
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn abort(&mut self) {
+        // This fails if the child was already reaped, which is fine.
+        let _ = self.kill();
+    }
+
+    fn is_finished(&mut self) -> bool {
+        // If the child has exited, Child remembers its status for the later call to wait().
+        let exited = !matches!(self.try_reap(), Ok(false));
+        exited && self.threads.iter().flatten().all(JoinHandle::is_finished)
+    }
+
+    /// For a child process, this starts a thread to wait for it to exit (without reaping it), the
+    /// first time it's called. If that thread can't be started, `f` is called right away, and the
+    /// error is returned in [`ChildExit::waiter`].
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static) {
+        // If the child was already reaped, its PID might belong to someone else by now, so only
+        // start waiting on it if it's still running.
+        if !self.waiter_started && matches!(self.try_reap(), Ok(false)) {
+            let guard = self.completion.guard();
+            let pid = self.child.id();
+            let name = match &self.name {
+                Some(name) => name.clone(),
+                None => program_name(&self.program),
+            };
+            let spawned = spawn_thread(Some(format!("{name} waiter")), move || {
+                let _guard = guard;
+                wait_exited(pid);
+            });
+            match spawned {
+                Ok(_) => self.waiter_started = true,
+                Err(e) => {
+                    // Nothing would tell us when the child exits, so don't leave the caller
+                    // waiting for that.
+                    self.waiter_error = Some(e);
+                    f();
+                    return;
+                }
+            }
         }
+        self.completion.on_complete(f);
     }
 
-    fn input_pipe(&mut self) -> Option<OwnedFd> {
-        self.child.stdin.take().map(Into::into)
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.input_pipe
+            .take()
+            .or_else(|| self.child.stdin.take().map(Into::into))
     }
 
-    fn output_pipe(&mut self) -> Option<OwnedFd> {
-        self.child.stdout.take().map(Into::into)
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.output_pipe
+            .take()
+            .or_else(|| self.child.stdout.take().map(Into::into))
     }
 }
 
-/// Running a [`ChildProcess`] involves potentially as many as 3 operations that can fail: the child
-/// process itself, a copy thread for the input and/or output (if one is required).
+impl RunningChild {
+    /// The command line the child was started with, as shown by [`ChildProcess`]'s [`Display`]
+    /// implementation.
+    pub fn command_line(&self) -> &str {
+        &self.command_line
+    }
+
+    /// The program the child is running, as given to its [`Command`].
+    pub fn program(&self) -> &OsStr {
+        &self.program
+    }
+
+    /// The arguments the child was given, not including the program.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// The child's process ID.
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Wait for the child to exit, but only for up to `timeout`. If it hasn't exited by then, the
+    /// running child is returned so that the caller can kill it or keep waiting.
+    ///
+    /// The copy threads are only joined once the child has exited, so a stuck thread can still
+    /// make this block past the timeout if the child exits without closing its end of the pipe
+    /// (e.g. by passing it on to a grandchild).
+    #[allow(clippy::result_large_err)] // same shape as RunningFilter::try_wait()
+    pub fn wait_timeout(mut self, timeout: Duration) -> Result<ChildExit, RunningChild> {
+        let deadline = Instant::now() + timeout;
+        let mut interval = Duration::from_millis(1);
+        loop {
+            // An error here comes back from wait() too.
+            if !matches!(self.try_reap(), Ok(false)) {
+                return Ok(self.wait());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(self);
+            }
+            std::thread::sleep(interval.min(deadline - now));
+            interval = (interval * 2).min(Duration::from_millis(50));
+        }
+    }
+
+    /// Stop the child gracefully: close its input, so that it sees EOF, and give it `grace` to
+    /// exit; then send it `SIGTERM` and give it `term_grace` more; and finally kill it with
+    /// `SIGKILL`. [`ChildExit::shutdown`] records how far this went.
+    ///
+    /// A copy thread feeding the child's stdin stops before its next read. If it's blocked reading
+    /// from a Rust stream which never returns, waiting for it still blocks.
+    pub fn shutdown(mut self, grace: Duration, term_grace: Duration) -> ChildExit {
+        self.stdin_stop.store(true, Ordering::SeqCst);
+        self.child.stdin = None;
+        self.input_pipe = None;
+        let (mut exit, outcome) = match self.wait_timeout(grace) {
+            Ok(exit) => (exit, ShutdownOutcome::Exited),
+            Err(mut running) => {
+                let _ = running.terminate();
+                match running.wait_timeout(term_grace) {
+                    Ok(exit) => (exit, ShutdownOutcome::Terminated),
+                    Err(mut running) => {
+                        let _ = running.kill();
+                        (running.wait(), ShutdownOutcome::Killed)
+                    }
+                }
+            }
+        };
+        exit.shutdown = Some(outcome);
+        exit
+    }
+
+    /// Give up on managing the child, and return it along with the copy threads for its stdin,
+    /// stdout, and stderr, if there are any. Waiting for the child and joining the threads are
+    /// then the caller's responsibility.
+    ///
+    /// Pipes requested with [`ReadStream::Pipe`] or [`WriteStream::Pipe`] which haven't been taken
+    /// yet are closed; pipes made by [`Command`] stay in the [`Child`].
+    pub fn into_child(self) -> (Child, [Option<JoinHandle<io::Result<u64>>>; 3]) {
+        if let Some(guard) = self.reap_on_drop {
+            guard.disarm();
+        }
+        (self.child, self.threads)
+    }
+
+    /// Let the child run on its own, without being waited for, killed, or reaped. Any copy
+    /// threads are left to finish on their own too. Dropping the [`RunningChild`] instead reaps
+    /// the child when it exits, so that it doesn't stay a zombie.
+    ///
+    /// The child is still this process's child, so it becomes a zombie when it exits unless
+    /// something else reaps it, such as a `SIGCHLD` handler or waiting for any child.
+    pub fn detach(mut self) {
+        if let Some(guard) = self.reap_on_drop.take() {
+            guard.disarm();
+        }
+    }
+
+    /// Kill the child with `SIGKILL`, and close any pipes to or from it that are still held here.
+    /// This is the same as [`RunningFilter::abort()`], but reports whether sending the signal
+    /// failed. It can be called more than once.
+    ///
+    /// [`RunningFilter::wait()`] then returns promptly, with the signal in [`ChildExit::child`],
+    /// and an [`Aborted`] error from any copy thread that is still stuck.
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.aborted = true;
+        self.child.stdin = None;
+        self.child.stdout = None;
+        self.child.stderr = None;
+        self.input_pipe = None;
+        self.output_pipe = None;
+        self.error_pipe = None;
+        self.pty = None;
+        self.extra_pipes.clear();
+        self.child.kill()
+    }
+
+    /// Ask the child to exit by sending it `SIGTERM`. Unlike [`RunningChild::kill()`], this
+    /// leaves everything else alone, and does nothing if the child has already exited.
+    pub fn terminate(&mut self) -> io::Result<()> {
+        if self.try_reap()? {
+            return Ok(());
+        }
+        self.signal(libc::SIGTERM)
+    }
+
+    /// Send the given signal to the child. If the child has been reaped already (by
+    /// [`RunningFilter::is_finished()`], for example), this fails with `ESRCH` rather than risk
+    /// signalling some other process which reused its PID.
+    pub fn signal(&self, sig: i32) -> io::Result<()> {
+        if self.reaped {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        // SAFETY: FFI call with no pointers.
+        if unsafe { libc::kill(self.child.id() as libc::pid_t, sig) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Stop the child with `SIGSTOP`, or its whole process group if it was started with
+    /// [`ChildProcess::new_process_group()`], until [`RunningChild::resume()`] is called. This can
+    /// be called when it's already paused. Once the child has exited, it fails with `ESRCH`.
+    ///
+    /// A paused child stops reading its input and writing its output, so whatever feeds it blocks
+    /// as soon as the pipe between them is full, and whatever reads from it waits. That slows a
+    /// whole chain down to nothing without losing any data, which is usually what's wanted when
+    /// pausing one stage of it.
+    pub fn pause(&mut self) -> io::Result<()> {
+        self.signal_running(libc::SIGSTOP)
+    }
+
+    /// Let the child carry on after [`RunningChild::pause()`], with `SIGCONT`. This can be called
+    /// when it isn't paused. Once the child has exited, it fails with `ESRCH`.
+    pub fn resume(&mut self) -> io::Result<()> {
+        self.signal_running(libc::SIGCONT)
+    }
+
+    /// Send a signal to the child, or its process group if it has one, failing with `ESRCH` if the
+    /// child has exited, even if it hasn't been reaped yet.
+    fn signal_running(&mut self, sig: i32) -> io::Result<()> {
+        if self.try_reap()? {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        if self.process_group {
+            self.signal_group(sig)
+        } else {
+            self.signal(sig)
+        }
+    }
+
+    /// Send the given signal to every process in the child's process group. The child must have
+    /// been started with [`ChildProcess::new_process_group()`].
+    ///
+    /// This still works after the child itself has exited, as long as anything in its group is
+    /// left; the group ID can't be reused until the group is empty.
+    pub fn signal_group(&self, sig: i32) -> io::Result<()> {
+        if !self.process_group {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "child wasn't started in a new process group",
+            ));
+        }
+        // SAFETY: FFI call with no pointers.
+        if unsafe { libc::kill(-(self.child.id() as libc::pid_t), sig) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Kill the child and everything else in its process group with `SIGKILL`. See
+    /// [`RunningChild::signal_group()`].
+    pub fn kill_group(&self) -> io::Result<()> {
+        self.signal_group(libc::SIGKILL)
+    }
+
+    /// Pass on the signals given to [`install_signal_forwarding()`](crate::install_signal_forwarding)
+    /// to the child, or to its process group if [`ChildProcess::new_process_group()`] was used,
+    /// until it's reaped. This fails with `ESRCH` if it has been reaped already.
+    ///
+    /// Only available with the `signals` feature.
+    #[cfg(feature = "signals")]
+    pub fn forward_signals(&mut self) -> io::Result<()> {
+        if self.reaped {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        if self.forwarding.is_none() {
+            self.forwarding = Some(Registration::new(self.child.id(), self.process_group));
+        }
+        Ok(())
+    }
+
+    /// Wait for the child to exit, if anything else might signal it (forwarded signals or the
+    /// stall watchdog), and then stop them, so that it can be reaped without its PID being reused
+    /// while it could still be signalled.
+    fn detach_signallers(&mut self) {
+        #[cfg(feature = "signals")]
+        let forwarding = self.forwarding.is_some();
+        #[cfg(not(feature = "signals"))]
+        let forwarding = false;
+        if (forwarding || self.watchdog.is_some()) && !self.reaped {
+            wait_exited(self.child.id());
+        }
+        #[cfg(feature = "signals")]
+        {
+            self.forwarding = None;
+        }
+        if let Some(watchdog) = &self.watchdog {
+            *watchdog.lock() = None;
+        }
+    }
+
+    /// Wait for the child to exit and reap it, without joining the copy threads.
+    pub(crate) fn wait_child(&mut self) -> io::Result<ExitStatus> {
+        self.detach_signallers();
+        let status = self.child.wait();
+        self.reaped = true;
+        if let Some(guard) = self.reap_on_drop.take() {
+            guard.disarm();
+        }
+        status
+    }
+
+    /// Carry on with a new child, spawned from the same [`Command`] after the old one was reaped.
+    pub(crate) fn replace_child(&mut self, child: Child) {
+        self.reap_on_drop = Some(ReapOnDrop {
+            pid: child.id(),
+            kill: false,
+        });
+        self.child = child;
+        self.reaped = false;
+    }
+
+    /// Set the window size of the child's terminal, in rows and columns. The child gets
+    /// `SIGWINCH`. This fails if the child wasn't started with [`ChildProcess::pty()`].
+    pub fn set_window_size(&self, rows: u16, cols: u16) -> io::Result<()> {
+        pty::set_window_size(self.pty()?, rows, cols)
+    }
+
+    /// Send the end-of-file character (normally Ctrl-D) to the child's terminal. If the child is
+    /// reading at the start of a line, it gets the end of its input; otherwise it just gets the
+    /// line so far, and this needs sending again. This fails if the child wasn't started with
+    /// [`ChildProcess::pty()`].
+    pub fn send_eof(&self) -> io::Result<()> {
+        pty::send_eof(self.pty()?)
+    }
+
+    fn pty(&self) -> io::Result<&OwnedPipeEnd> {
+        self.pty.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "child wasn't started on a pty")
+        })
+    }
+
+    /// Check whether the child has exited, reaping it if so (Child keeps its status for later).
+    fn try_reap(&mut self) -> io::Result<bool> {
+        #[cfg(feature = "signals")]
+        if self.forwarding.is_some() && !self.reaped {
+            if !has_exited(self.child.id())? {
+                return Ok(false);
+            }
+            self.forwarding = None;
+        }
+        let watchdog = self.watchdog.as_ref().map(Watchdog::lock);
+        let exited = self.child.try_wait()?.is_some();
+        if exited {
+            if let Some(mut pid) = watchdog {
+                *pid = None;
+            }
+            self.reaped = true;
+            if let Some(guard) = self.reap_on_drop.take() {
+                guard.disarm();
+            }
+        }
+        Ok(exited)
+    }
+
+    /// The child's stdin, if it was started with [`ReadStream::PipeRequested`], buffered. This is
+    /// the same as [`RunningFilter::buffered_input_writer()`]. Remember to drop or
+    /// [`close()`](BufferedInput::close) it when done, or the child never sees the end of its
+    /// input.
+    pub fn stdin_writer(&mut self) -> Option<BufferedInput> {
+        self.buffered_input_writer()
+    }
+
+    /// If the child's stderr was set to [`WriteStream::PipeRequested`] with
+    /// [`ChildProcess::stderr()`], this will return the read half of a pipe which can be used to
+    /// read it.
+    pub fn stderr_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.error_pipe
+            .take()
+            .or_else(|| self.child.stderr.take().map(Into::into))
+    }
+
+    /// The lines the child has printed so far and when, if [`ChildProcess::time_lines()`] was
+    /// used. A line still being written isn't included until it's finished.
+    pub fn timed_lines(&self) -> Option<Vec<TimedLine>> {
+        self.line_log.as_ref().map(LineLog::lines)
+    }
+
+    /// If a pipe was requested for the child's file descriptor `target_fd` with
+    /// [`ChildProcess::pass_fd()`], this will return this process's end of it: the write end if
+    /// the child reads from it, or the read end if it writes.
+    pub fn extra_pipe(&mut self, target_fd: RawFd) -> Option<OwnedPipeEnd> {
+        let i = self
+            .extra_pipes
+            .iter()
+            .position(|(fd, _)| *fd == target_fd)?;
+        Some(self.extra_pipes.swap_remove(i).1)
+    }
+}
+
+/// Set the file mode creation mask of this process. This is called between fork and exec.
+fn set_umask(mask: u32) -> io::Result<()> {
+    // SAFETY: FFI call with no pointers. umask can't fail.
+    unsafe { libc::umask(mask as libc::mode_t) };
+    Ok(())
+}
+
+/// Make `root` this process's root directory, and go there, so that nothing outside it can be
+/// reached through the working directory. This is called between fork and exec, so it must only
+/// do async-signal-safe things.
+fn change_root(root: &CStr) -> io::Result<()> {
+    // SAFETY: FFI calls with valid, NUL-terminated paths.
+    unsafe {
+        if libc::chroot(root.as_ptr()) == -1 || libc::chdir(c"/".as_ptr()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Have the kernel send this process `sig` when the thread that started it exits. This is called
+/// between fork and exec, so it must only do async-signal-safe things.
+#[cfg(target_os = "linux")]
+fn set_parent_death_signal(sig: i32, parent: libc::pid_t) -> io::Result<()> {
+    // SAFETY: FFI calls with no pointers.
+    unsafe {
+        if libc::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // If the parent died before the prctl, we've been reparented and the signal will never
+        // come, so send it now. Failing stops the exec if the signal is caught or ignored.
+        if libc::getppid() != parent {
+            libc::kill(libc::getpid(), sig);
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+    }
+    Ok(())
+}
+
+/// Block until the given child process has exited, but leave it to be reaped later.
+pub(crate) fn wait_exited(pid: u32) {
+    loop {
+        // SAFETY: siginfo_t is a plain C struct which waitid fills in.
+        let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+        // SAFETY: FFI call with a valid pointer.
+        let ret = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if ret == 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            return;
+        }
+    }
+}
+
+/// Check whether the given child process has exited, but leave it to be reaped later.
+pub(crate) fn has_exited(pid: u32) -> io::Result<bool> {
+    // SAFETY: siginfo_t is a plain C struct which waitid fills in. With WNOHANG, it's left zeroed
+    // if the child hasn't exited.
+    let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+    // SAFETY: FFI call with a valid pointer.
+    let ret = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOWAIT | libc::WNOHANG,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: waitid succeeded, so the PID field is filled in, or zero.
+    Ok(unsafe { info.si_pid() } != 0)
+}
+
+/// Running a [`ChildProcess`] involves potentially as many as 4 operations that can fail: the child
+/// process itself, and a copy thread for each of the input, output, and stderr (if one is
+/// required).
+#[derive(Debug)]
 pub struct ChildExit {
+    /// The result of waiting for the child process.
     pub child: io::Result<ExitStatus>,
+    /// The result of the thread copying into the child's stdin, if there was one.
     pub read_thread: Option<io::Result<()>>,
+    /// The result of the thread copying from the child's stdout, if there was one.
     pub write_thread: Option<io::Result<()>>,
+    /// The result of the thread copying from the child's stderr, if there was one.
+    pub err_thread: Option<io::Result<()>>,
+    /// How many bytes the thread copying into the child's stdin copied, if there was one and it
+    /// succeeded.
+    pub read_bytes: Option<u64>,
+    /// How many bytes the thread copying from the child's stdout copied, if there was one and it
+    /// succeeded.
+    pub write_bytes: Option<u64>,
+    /// How many bytes the thread copying from the child's stderr copied, if there was one and it
+    /// succeeded.
+    pub err_bytes: Option<u64>,
+    /// Whether the child stopped reading its input before the end, which is only reported as
+    /// success with [`ChildProcess::input_epipe_ok()`].
+    pub input_stopped_early: bool,
+    /// How the child was stopped, if [`RunningChild::shutdown()`] was used.
+    pub shutdown: Option<ShutdownOutcome>,
+    /// The end of the child's stderr, if [`ChildProcess::capture_stderr_tail()`] was used.
+    pub stderr_tail: Option<Vec<u8>>,
+    /// The lines the child printed and when, if [`ChildProcess::time_lines()`] was used. Only the
+    /// last ones are kept, up to [`LineTiming::max_lines()`](crate::LineTiming::max_lines).
+    pub timed_lines: Option<Vec<TimedLine>>,
+    /// The timeout, if the child was killed by [`ChildProcess::stall_timeout()`] for going that
+    /// long without making progress.
+    pub stalled: Option<Duration>,
+    /// The command line the child was started with, as from [`RunningChild::command_line()`].
+    pub command_line: String,
+    /// The program the child ran, as given to its [`Command`].
+    pub program: OsString,
+    /// The arguments the child was given, not including the program.
+    pub args: Vec<OsString>,
+    /// Why the thread waiting for the child to exit couldn't be started, if
+    /// [`RunningFilter::on_complete()`] was used and it couldn't. The callback was then called
+    /// before the child had finished.
+    pub waiter: Option<io::Error>,
+    /// The name of the filter, if it was given one.
+    pub name: Option<String>,
 }
 
 impl ChildExit {
+    /// The exit of a child which couldn't be waited for at all.
+    pub(crate) fn failed(
+        e: io::Error,
+        command_line: String,
+        program: OsString,
+        args: Vec<OsString>,
+        name: Option<String>,
+    ) -> Self {
+        Self {
+            child: Err(e),
+            read_thread: None,
+            write_thread: None,
+            err_thread: None,
+            read_bytes: None,
+            write_bytes: None,
+            err_bytes: None,
+            input_stopped_early: false,
+            shutdown: None,
+            stderr_tail: None,
+            timed_lines: None,
+            stalled: None,
+            command_line,
+            program,
+            args,
+            waiter: None,
+            name,
+        }
+    }
+
+    /// Whether the child exited successfully and none of the copy threads or its waiter thread
+    /// failed.
+    pub fn success(&self) -> bool {
+        matches!(&self.child, Ok(status) if status.success())
+            && self.thread_error().is_none()
+            && self.waiter.is_none()
+    }
+
+    /// The child's exit code, if it exited normally.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.child.as_ref().ok()?.code()
+    }
+
+    /// The signal that killed the child, if it was killed by one.
+    pub fn signal(&self) -> Option<i32> {
+        self.child.as_ref().ok()?.signal()
+    }
+
+    /// The resource limit which the child was killed for going over, if it got the signal for
+    /// one: `SIGXCPU` for [`Resource::CpuTime`] or `SIGXFSZ` for [`Resource::FileSize`]. A child
+    /// killed at the hard CPU time limit gets `SIGKILL`, which can't be told apart from any other
+    /// kill. Going over the other limits makes system calls fail, rather than sending a signal.
+    pub fn limit_exceeded(&self) -> Option<Resource> {
+        Resource::from_signal(self.signal()?)
+    }
+
+    /// The first error from a copy thread, if any of them failed.
+    pub fn thread_error(&self) -> Option<&io::Error> {
+        [&self.read_thread, &self.write_thread, &self.err_thread]
+            .into_iter()
+            .find_map(|r| r.as_ref()?.as_ref().err())
+    }
+
     /// Combine the result of the child process exit and any threads into one Result.
-    pub fn combine(mut self) -> Result<(), ChildExitError> {
+    pub fn combine(self) -> Result<(), ChildExitError> {
+        self.combine_with(&ExitPolicy::new())
+    }
+
+    /// Like [`ChildExit::combine()`], but also accepting a child which was killed by `SIGPIPE`,
+    /// as the first stage of a `yes | head` style chain is.
+    pub fn combine_allow_sigpipe(self) -> Result<(), ChildExitError> {
+        self.combine_with(&ExitPolicy::new().allow_signal(libc::SIGPIPE))
+    }
+
+    /// Like [`ChildExit::combine()`], but also accepting the given exit codes, such as 1 from
+    /// `grep` when nothing matched.
+    pub fn combine_allow_codes(self, codes: &[i32]) -> Result<(), ChildExitError> {
+        let policy = codes
+            .iter()
+            .fold(ExitPolicy::new(), |policy, &code| policy.allow_code(code));
+        self.combine_with(&policy)
+    }
+
+    /// Like [`ChildExit::combine()`], but accepting any exit code and returning it. It's still an
+    /// error if the child was killed by a signal or a copy thread failed.
+    pub fn combine_with_code(self) -> Result<i32, ChildExitError> {
+        match self.exit_code() {
+            Some(code) => self
+                .combine_with(&ExitPolicy::new().allow_code(code))
+                .map(|()| code),
+            // Without an exit code, the child didn't succeed.
+            None => self.combine().map(|()| 0),
+        }
+    }
+
+    /// Like [`ChildExit::combine()`], but with a policy for what counts as the child succeeding.
+    /// The copy threads are checked the same way regardless.
+    pub fn combine_with(mut self, policy: &ExitPolicy) -> Result<(), ChildExitError> {
         use std::mem::replace;
+        if self.signal() == Some(libc::SIGPIPE) && policy.accepts_signal(libc::SIGPIPE) {
+            // The thread copying the child's output most likely got EPIPE from the same reader
+            // going away, so that isn't an error either.
+            if matches!(&self.write_thread, Some(Err(e)) if e.kind() == io::ErrorKind::BrokenPipe) {
+                self.write_thread = Some(Ok(()));
+            }
+        }
         match replace(&mut self.child, Ok(ExitStatus::from_raw(0))) {
             Err(e) => {
                 return Err(ChildExitError {
                     kind: ChildExitErrorKind::ChildWait(e),
-                    next: self.combine().err().map(Box::new),
+                    name: self.name.clone(),
+                    stderr_tail: None,
+                    program: None,
+                    next: self.combine_with(policy).err().map(Box::new),
                 });
             }
-            Ok(exit) if !exit.success() => {
+            Ok(exit) if !policy.accepts(exit) => {
+                let stderr_tail = self
+                    .stderr_tail
+                    .take()
+                    .map(|tail| String::from_utf8_lossy(&tail).into_owned());
+                let kind = match self.stalled {
+                    Some(timeout) => ChildExitErrorKind::Stalled(timeout),
+                    None => ChildExitErrorKind::ChildExit(exit),
+                };
                 return Err(ChildExitError {
-                    kind: ChildExitErrorKind::ChildExit(exit),
-                    next: self.combine().err().map(Box::new),
+                    kind,
+                    name: self.name.clone(),
+                    stderr_tail,
+                    program: Some(self.program.clone()),
+                    next: self.combine_with(policy).err().map(Box::new),
                 });
             }
             Ok(_) => (),
         }
-        if let Some(Err(e)) = replace(&mut self.read_thread, None) {
+        if let Some(e) = self.waiter.take() {
+            return Err(ChildExitError {
+                kind: ChildExitErrorKind::Waiter(e),
+                name: self.name.clone(),
+                stderr_tail: None,
+                program: None,
+                next: self.combine_with(policy).err().map(Box::new),
+            });
+        }
+        if let Some(Err(e)) = self.read_thread.take() {
             return Err(ChildExitError {
                 kind: ChildExitErrorKind::ReadThread(e),
-                next: self.combine().err().map(Box::new),
+                name: self.name.clone(),
+                stderr_tail: None,
+                program: None,
+                next: self.combine_with(policy).err().map(Box::new),
             });
         }
-        if let Some(Err(e)) = self.write_thread {
+        if let Some(Err(e)) = self.write_thread.take() {
             return Err(ChildExitError {
                 kind: ChildExitErrorKind::WriteThread(e),
+                name: self.name.clone(),
+                stderr_tail: None,
+                program: None,
+                next: self.combine_with(policy).err().map(Box::new),
+            });
+        }
+        if let Some(Err(e)) = self.err_thread {
+            return Err(ChildExitError {
+                kind: ChildExitErrorKind::ErrThread(e),
+                name: self.name.clone(),
+                stderr_tail: None,
+                program: None,
                 next: None,
             });
         }
@@ -152,15 +2036,86 @@ impl ChildExit {
     }
 }
 
+/// What counts as a child process succeeding, for [`ChildExit::combine_with()`]. By default, only
+/// exiting with status 0 does.
+#[derive(Debug, Default, Clone)]
+pub struct ExitPolicy {
+    codes: Vec<i32>,
+    signals: Vec<i32>,
+}
+
+impl ExitPolicy {
+    /// The default policy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also accept the child exiting with the given code.
+    pub fn allow_code(mut self, code: i32) -> Self {
+        self.codes.push(code);
+        self
+    }
+
+    /// Also accept the child being killed by the given signal.
+    pub fn allow_signal(mut self, sig: i32) -> Self {
+        self.signals.push(sig);
+        self
+    }
+
+    fn accepts_signal(&self, sig: i32) -> bool {
+        self.signals.contains(&sig)
+    }
+
+    fn accepts(&self, status: ExitStatus) -> bool {
+        status.success()
+            || status.code().is_some_and(|code| self.codes.contains(&code))
+            || status.signal().is_some_and(|sig| self.accepts_signal(sig))
+    }
+}
+
+/// How far [`RunningChild::shutdown()`] had to go to stop the child.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// The child exited by itself after its input was closed.
+    Exited,
+    /// The child exited after `SIGTERM`.
+    Terminated,
+    /// The child had to be killed with `SIGKILL`.
+    Killed,
+}
+
+/// An error from [`ChildExit::combine()`]: the first thing that failed, and a list of any others.
 #[derive(Debug)]
 pub struct ChildExitError {
+    /// What went wrong.
     pub kind: ChildExitErrorKind,
+    /// The name of the filter, if it was given one.
+    pub name: Option<String>,
+    /// The end of the child's stderr, if it exited unsuccessfully and
+    /// [`ChildProcess::capture_stderr_tail()`] was used.
+    pub stderr_tail: Option<String>,
+    /// The program the child ran, if it exited unsuccessfully.
+    pub program: Option<OsString>,
+    /// The next error, if more than one thing went wrong.
     pub next: Option<Box<ChildExitError>>,
 }
 
 impl Display for ChildExitError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.kind.fmt(f)?;
+        if let Some(name) = &self.name {
+            write!(f, "filter '{name}': ")?;
+        }
+        match (&self.kind, &self.program) {
+            (ChildExitErrorKind::ChildExit(status), Some(program)) => write!(
+                f,
+                "'{}' exited unsuccessfully: {status}",
+                program.to_string_lossy()
+            )?,
+            (kind, _) => kind.fmt(f)?,
+        }
+        if let Some(tail) = &self.stderr_tail {
+            write!(f, "\n   stderr: {}", tail.trim_end())?;
+        }
         if let Some(next) = &self.next {
             write!(f, "\n   and also {next}")?;
         }
@@ -168,14 +2123,63 @@ impl Display for ChildExitError {
     }
 }
 
-impl Error for ChildExitError {}
+impl ChildExitError {
+    /// What went wrong first.
+    pub fn kind(&self) -> &ChildExitErrorKind {
+        &self.kind
+    }
+
+    /// Everything that went wrong, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &ChildExitErrorKind> {
+        std::iter::successors(Some(self), |e| e.next.as_deref()).map(|e| &e.kind)
+    }
+
+    /// Everything that went wrong, in order.
+    pub fn into_kinds(self) -> Vec<ChildExitErrorKind> {
+        let mut kinds = vec![];
+        let mut next = Some(Box::new(self));
+        while let Some(e) = next {
+            kinds.push(e.kind);
+            next = e.next;
+        }
+        kinds
+    }
+}
+
+impl Error for ChildExitError {
+    /// The I/O error behind the first thing that went wrong, if there is one. Use
+    /// [`ChildExitError::iter()`] to get at the rest.
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            ChildExitErrorKind::ChildWait(e)
+            | ChildExitErrorKind::Waiter(e)
+            | ChildExitErrorKind::ReadThread(e)
+            | ChildExitErrorKind::WriteThread(e)
+            | ChildExitErrorKind::ErrThread(e) => Some(e),
+            ChildExitErrorKind::ChildExit(_) | ChildExitErrorKind::Stalled(_) => None,
+        }
+    }
+}
 
+/// The part of running a [`ChildProcess`] that failed.
 #[derive(Debug)]
 pub enum ChildExitErrorKind {
+    /// Waiting for the child process failed.
     ChildWait(io::Error),
+    /// The child process exited unsuccessfully.
     ChildExit(ExitStatus),
+    /// The thread waiting for the child to exit, for [`RunningFilter::on_complete()`], couldn't be
+    /// started.
+    Waiter(io::Error),
+    /// The thread copying into the child's stdin failed.
     ReadThread(io::Error),
+    /// The thread copying from the child's stdout failed.
     WriteThread(io::Error),
+    /// The thread copying from the child's stderr failed.
+    ErrThread(io::Error),
+    /// The child was killed by [`ChildProcess::stall_timeout()`] after going this long without
+    /// making progress.
+    Stalled(Duration),
 }
 
 impl Display for ChildExitErrorKind {
@@ -183,8 +2187,16 @@ impl Display for ChildExitErrorKind {
         match self {
             ChildExitErrorKind::ChildWait(e) => write!(f, "failed to wait on child process: {e}"),
             ChildExitErrorKind::ChildExit(e) => write!(f, "child exited unsuccessfully: {e}"),
+            ChildExitErrorKind::Waiter(e) => write!(f, "failed to start child waiter thread: {e}"),
             ChildExitErrorKind::ReadThread(e) => write!(f, "read copy thread failed: {e}"),
             ChildExitErrorKind::WriteThread(e) => write!(f, "Write copy thread failed: {e}"),
+            ChildExitErrorKind::ErrThread(e) => write!(f, "stderr copy thread failed: {e}"),
+            ChildExitErrorKind::Stalled(timeout) => {
+                write!(
+                    f,
+                    "child was killed after making no progress for {timeout:?}"
+                )
+            }
         }
     }
 }
@@ -0,0 +1,87 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Which of a child's streams a progress report from
+/// [`ChildProcess::on_progress()`](crate::ChildProcess::on_progress) is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Data copied into the child's stdin.
+    Input,
+    /// Data copied from the child's stdout.
+    Stdout,
+    /// Data copied from the child's stderr.
+    Stderr,
+}
+
+pub(crate) type ProgressCallback = Arc<dyn Fn(Direction, u64) + Send + Sync>;
+
+/// Passes a copy thread's reads or writes through, reporting how many bytes have gone by at most
+/// once per interval, and once more at the end if there's anything new.
+pub(crate) struct ProgressReport<S> {
+    inner: S,
+    direction: Direction,
+    callback: Option<(Duration, ProgressCallback)>,
+    total: u64,
+    reported: u64,
+    last: Instant,
+}
+
+impl<S> ProgressReport<S> {
+    pub fn new(
+        inner: S,
+        direction: Direction,
+        callback: Option<(Duration, ProgressCallback)>,
+    ) -> Self {
+        Self {
+            inner,
+            direction,
+            callback,
+            total: 0,
+            reported: 0,
+            last: Instant::now(),
+        }
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.total += n as u64;
+        if let Some((interval, callback)) = &self.callback {
+            if n > 0 && self.last.elapsed() >= *interval {
+                self.last = Instant::now();
+                self.reported = self.total;
+                callback(self.direction, self.total);
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReport<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.advance(n);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for ProgressReport<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.advance(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S> Drop for ProgressReport<S> {
+    /// The copy is done, so report the final count.
+    fn drop(&mut self) {
+        if let Some((_, callback)) = &self.callback {
+            if self.total != self.reported {
+                callback(self.direction, self.total);
+            }
+        }
+    }
+}
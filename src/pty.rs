@@ -0,0 +1,147 @@
+use std::io::{self, Read};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd};
+
+use crate::OwnedPipeEnd;
+
+/// Both ends of a pseudo-terminal, for [`ChildProcess::pty()`](crate::ChildProcess::pty).
+pub(crate) struct Pty {
+    pub master: OwnedPipeEnd,
+    pub slave: OwnedPipeEnd,
+}
+
+impl Pty {
+    /// Allocate a pseudo-terminal, with the given window size if there is one.
+    ///
+    /// The terminal doesn't echo its input back, and doesn't turn `\n` into `\r\n` on output, so
+    /// that data passes through it unchanged. Input is still handled a line at a time, with
+    /// control characters such as `VEOF` interpreted.
+    pub fn open(size: Option<(u16, u16)>) -> io::Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        // SAFETY: winsize is a plain C struct.
+        let mut winsize = unsafe { std::mem::zeroed::<libc::winsize>() };
+        let winp = match size {
+            Some((rows, cols)) => {
+                winsize.ws_row = rows;
+                winsize.ws_col = cols;
+                &mut winsize as *mut libc::winsize
+            }
+            None => std::ptr::null_mut(),
+        };
+        // SAFETY: FFI call with valid pointers, or nulls where openpty allows them.
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                winp,
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openpty succeeded, so these are open file descriptors which we now own.
+        let pty = unsafe {
+            Pty {
+                master: OwnedPipeEnd::from_raw_fd(master),
+                slave: OwnedPipeEnd::from_raw_fd(slave),
+            }
+        };
+        // openpty can't make them close-on-exec, so there's a window where another thread's
+        // child could inherit them; do it as soon as possible.
+        set_cloexec(&pty.master)?;
+        set_cloexec(&pty.slave)?;
+
+        // SAFETY: termios is a plain C struct which tcgetattr fills in.
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        // SAFETY: FFI calls with a valid file descriptor and pointer.
+        unsafe {
+            check(libc::tcgetattr(pty.slave.as_raw_fd(), &mut termios))?;
+            termios.c_lflag &= !(libc::ECHO | libc::ECHONL);
+            termios.c_oflag &= !libc::ONLCR;
+            check(libc::tcsetattr(
+                pty.slave.as_raw_fd(),
+                libc::TCSANOW,
+                &termios,
+            ))?;
+        }
+        Ok(pty)
+    }
+}
+
+fn set_cloexec(fd: &impl AsRawFd) -> io::Result<()> {
+    // SAFETY: FFI call with no pointers.
+    check(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) })
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Make the child the leader of a new session, with its stdin (the pty's slave end) as its
+/// controlling terminal. This is called between fork and exec, so it must only do
+/// async-signal-safe things.
+pub(crate) fn setup_child() -> io::Result<()> {
+    // SAFETY: FFI calls with no pointers.
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        check(libc::ioctl(0, libc::TIOCSCTTY as _, 0))
+    }
+}
+
+/// Set the window size of the terminal that `fd` belongs to. The child gets `SIGWINCH`.
+pub(crate) fn set_window_size(fd: &impl AsFd, rows: u16, cols: u16) -> io::Result<()> {
+    // SAFETY: winsize is a plain C struct.
+    let mut winsize = unsafe { std::mem::zeroed::<libc::winsize>() };
+    winsize.ws_row = rows;
+    winsize.ws_col = cols;
+    // SAFETY: FFI call with a valid pointer.
+    check(unsafe {
+        libc::ioctl(
+            fd.as_fd().as_raw_fd(),
+            libc::TIOCSWINSZ as _,
+            &winsize as *const libc::winsize,
+        )
+    })
+}
+
+/// Write the terminal's end-of-file character to the master end `fd`, so that a read from the
+/// slave end returns whatever is in the current line, or end of file if the line is empty.
+pub(crate) fn send_eof(fd: &impl AsFd) -> io::Result<()> {
+    // SAFETY: termios is a plain C struct which tcgetattr fills in.
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    // SAFETY: FFI call with a valid file descriptor and pointer.
+    check(unsafe { libc::tcgetattr(fd.as_fd().as_raw_fd(), &mut termios) })?;
+    let eof = termios.c_cc[libc::VEOF];
+    // SAFETY: FFI call with a valid pointer and length.
+    let ret = unsafe {
+        libc::write(
+            fd.as_fd().as_raw_fd(),
+            (&eof as *const libc::cc_t).cast(),
+            1,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reads from a terminal, treating `EIO` as the end of the stream. That's what reading from the
+/// master end of a pseudo-terminal gets once everything has closed the slave end.
+pub(crate) struct PtyReader<R>(pub R);
+
+impl<R: Read> Read for PtyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::ffi::OsString;
+use std::io;
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::completion::Completion;
+use crate::misc::{spawn_thread, ThreadPanicked};
+use crate::process::{wait_exited, ChildExit, ChildExitError, RunningChild};
+use crate::{
+    ChildProcess, Filter, IoChainError, NormalizedResult, OwnedPipeEnd, ReadStream, RunningFilter,
+    WriteStream,
+};
+
+/// How a [`Respawn`] filter restarts its child.
+///
+/// By default the child is restarted any number of times, waiting 100ms before the first restart
+/// and doubling that each time, up to 10s.
+#[derive(Debug, Clone)]
+pub struct RespawnPolicy {
+    max_restarts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RespawnPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: u32::MAX,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RespawnPolicy {
+    /// The default policy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up once the child has been restarted this many times, and report its last exit.
+    pub fn max_restarts(mut self, max: u32) -> Self {
+        self.max_restarts = max;
+        self
+    }
+
+    /// Wait `initial` before the first restart, doubling the wait each time up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+}
+
+/// A [`ChildProcess`] which is started again, attached to the same pipes, whenever it exits
+/// unsuccessfully. Create one with [`ChildProcess::respawn()`].
+///
+/// The child's ends of its pipes are kept open between restarts, so the filters on either side
+/// don't see EOF until the last child exits. Nothing is replayed: input the dead child had already
+/// read is lost, and whatever it had written stays written, so a record it was halfway through
+/// writing is left incomplete in the output. Input which is still in the pipe goes to the next
+/// child.
+///
+/// [`ChildProcess::kill_on_drop()`] only covers the first child. Dropping the running filter stops
+/// any further restarts, but leaves the current child running, to be reaped when it exits.
+pub struct Respawn {
+    child: ChildProcess,
+    policy: RespawnPolicy,
+}
+
+impl ChildProcess {
+    /// Restart the child according to the given policy whenever it exits unsuccessfully. See
+    /// [`Respawn`].
+    pub fn respawn(self, policy: RespawnPolicy) -> Respawn {
+        Respawn {
+            child: self,
+            policy,
+        }
+    }
+}
+
+impl Filter for Respawn {
+    type Running = RunningRespawn;
+    type Error = io::Error;
+
+    fn start(self, input: ReadStream, output: WriteStream) -> io::Result<Self::Running> {
+        let (mut running, cmd) = self.child.start_retaining(input, output, true)?;
+        let cmd = cmd.expect("command is retained");
+        let input_pipe = running.input_pipe();
+        let output_pipe = running.output_pipe();
+        let error_pipe = running.stderr_pipe();
+        let name = running.name().map(str::to_owned);
+        let pid = Arc::new(Mutex::new(Some(running.pid())));
+        let restarts = Arc::new(AtomicU32::new(0));
+        let command_line = running.command_line().to_owned();
+        let program = running.program().to_owned();
+        let args = running.args().to_vec();
+        let (stop_tx, stop_rx) = channel();
+        let completion = Completion::new();
+        let guard = completion.guard();
+        let supervisor_pid = Arc::clone(&pid);
+        let supervisor_restarts = Arc::clone(&restarts);
+        let policy = self.policy;
+        let handle = spawn_thread(
+            name.as_ref().map(|name| format!("{name} supervisor")),
+            move || {
+                let _guard = guard;
+                supervise(
+                    running,
+                    cmd,
+                    &policy,
+                    &supervisor_pid,
+                    &supervisor_restarts,
+                    &stop_rx,
+                )
+            },
+        )?;
+        Ok(RunningRespawn {
+            handle,
+            stop: Some(stop_tx),
+            pid,
+            restarts,
+            command_line,
+            program,
+            args,
+            input_pipe,
+            output_pipe,
+            error_pipe,
+            completion,
+            name,
+        })
+    }
+}
+
+/// Wait for each child in turn, starting another from `cmd` if it failed, until one succeeds, the
+/// policy gives up, or `stop` is disconnected. This is the body of the supervisor thread.
+fn supervise(
+    mut running: RunningChild,
+    mut cmd: Command,
+    policy: &RespawnPolicy,
+    pid: &Mutex<Option<u32>>,
+    restarts: &AtomicU32,
+    stop: &Receiver<()>,
+) -> RespawnExit {
+    let mut delay = policy.backoff;
+    let mut spawn_error = None;
+    loop {
+        // Only reap the child with the lock held, so that abort() can't signal a reused PID.
+        wait_exited(running.pid());
+        let status = {
+            let mut pid = pid.lock();
+            *pid = None;
+            running.wait_child()
+        };
+        if matches!(&status, Ok(status) if status.success())
+            || restarts.load(Ordering::SeqCst) >= policy.max_restarts
+        {
+            break;
+        }
+        if !matches!(stop.recv_timeout(delay), Err(RecvTimeoutError::Timeout)) {
+            break;
+        }
+        delay = (delay * 2).min(policy.max_backoff);
+        let mut pid = pid.lock();
+        // Check again with the lock held: abort() stops us before looking for a child to kill.
+        if matches!(stop.try_recv(), Err(TryRecvError::Disconnected)) {
+            break;
+        }
+        match cmd.spawn() {
+            Ok(child) => {
+                *pid = Some(child.id());
+                running.replace_child(child);
+                restarts.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(e) => {
+                spawn_error = Some(e);
+                break;
+            }
+        }
+    }
+    if matches!(stop.try_recv(), Err(TryRecvError::Disconnected)) {
+        // Don't wait for copy threads which may be stuck. The child is already reaped.
+        let _ = running.kill();
+    }
+    // Close the retained ends of the pipes, so the copy threads can finish.
+    drop(cmd);
+    let mut exit = running.wait();
+    if let Some(e) = spawn_error {
+        exit.child = Err(e);
+    }
+    RespawnExit {
+        restarts: restarts.load(Ordering::SeqCst),
+        exit,
+    }
+}
+
+/// A running [`Respawn`] filter.
+pub struct RunningRespawn {
+    handle: JoinHandle<RespawnExit>,
+    // Dropped to stop the supervisor.
+    stop: Option<Sender<()>>,
+    // The current child, while it's running and not reaped.
+    pid: Arc<Mutex<Option<u32>>>,
+    // Kept to report on the child if the supervisor panics.
+    restarts: Arc<AtomicU32>,
+    command_line: String,
+    program: OsString,
+    args: Vec<OsString>,
+    input_pipe: Option<OwnedPipeEnd>,
+    output_pipe: Option<OwnedPipeEnd>,
+    error_pipe: Option<OwnedPipeEnd>,
+    completion: Completion,
+    name: Option<String>,
+}
+
+impl RunningRespawn {
+    /// The process ID of the current child, if one is running.
+    pub fn pid(&self) -> Option<u32> {
+        *self.pid.lock()
+    }
+
+    /// If the child's stderr was set to [`WriteStream::PipeRequested`] with
+    /// [`ChildProcess::stderr()`], this will return the read half of a pipe which can be used to
+    /// read it. The same pipe is used by every child.
+    pub fn stderr_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.error_pipe.take()
+    }
+}
+
+impl RunningFilter for RunningRespawn {
+    type Result = RespawnExit;
+
+    fn wait(self) -> Self::Result {
+        // Keep the sender alive until the supervisor is done, so it doesn't think it was aborted.
+        let RunningRespawn {
+            handle,
+            stop,
+            restarts,
+            command_line,
+            program,
+            args,
+            name,
+            ..
+        } = self;
+        let exit = handle.join().unwrap_or_else(|p| RespawnExit {
+            restarts: restarts.load(Ordering::SeqCst),
+            exit: ChildExit::failed(ThreadPanicked::ioerr(p), command_line, program, args, name),
+        });
+        drop(stop);
+        exit
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Stop restarting the child, and kill the current one.
+    fn abort(&mut self) {
+        self.stop = None;
+        if let Some(pid) = *self.pid.lock() {
+            // SAFETY: FFI call with no pointers. The child hasn't been reaped while we hold the
+            // lock, so the PID is still ours.
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+        }
+        self.input_pipe = None;
+        self.output_pipe = None;
+        self.error_pipe = None;
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.handle.is_finished()
+    }
+
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static) {
+        self.completion.on_complete(f);
+    }
+
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.input_pipe.take()
+    }
+
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.output_pipe.take()
+    }
+}
+
+/// The result of a [`Respawn`] filter: how many times the child was restarted, and how the last
+/// one exited.
+pub struct RespawnExit {
+    /// How many times the child was restarted.
+    pub restarts: u32,
+    /// The last child's exit, and the results of the copy threads, which are shared by all the
+    /// children. If restarting the child failed, or the thread supervising it panicked,
+    /// [`ChildExit::child`] has the error.
+    pub exit: ChildExit,
+}
+
+impl RespawnExit {
+    /// Combine the last child's exit and the copy threads' results, as with
+    /// [`ChildExit::combine()`].
+    pub fn combine(self) -> Result<(), ChildExitError> {
+        self.exit.combine()
+    }
+}
+
+impl NormalizedResult for RespawnExit {
+    fn normalize(self) -> io::Result<()> {
+        self.exit.normalize()
+    }
+
+    fn into_chain_result(self) -> Result<(), IoChainError> {
+        self.exit.into_chain_result()
+    }
+}
@@ -0,0 +1,180 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, ScopedJoinHandle};
+
+use parking_lot::Mutex;
+
+use crate::affinity::CpuSet;
+use crate::completion::Completion;
+use crate::lambda::{self, LambdaError, TryLambda};
+use crate::misc::{
+    name_error, read_stream_fd, spawn_scoped_thread, write_stream, Aborted, ThreadPanicked,
+};
+use crate::stop::StopReader;
+use crate::{IoChainError, LambdaFilter, OwnedPipeEnd, ReadStream, RunningFilter, WriteStream};
+
+/// Run filters which can borrow from the caller's stack.
+///
+/// Lambdas started with [`Scope::start()`] and streams passed to [`Scope::reader()`] and
+/// [`Scope::writer()`] don't need to be `'static`. Everything started in the scope is finished
+/// before this returns: any filters which weren't waited on are waited on here (and their results
+/// discarded), and the results of the copy threads for the readers and writers are collected and
+/// returned as an error if any of them failed.
+pub fn scope<'env, F, T>(f: F) -> Result<T, IoChainError>
+where
+    F: for<'scope> FnOnce(&Scope<'scope, 'env>) -> T,
+{
+    thread::scope(|s| {
+        let scope = Scope {
+            inner: s,
+            copies: Mutex::new(vec![]),
+        };
+        let result = f(&scope);
+        let copies = scope.copies.into_inner();
+        let results = copies.into_iter().map(|t| {
+            t.join()
+                .unwrap_or_else(|p| Err(ThreadPanicked::ioerr(p)))
+                .map(drop)
+        });
+        IoChainError::collect(results).map(|()| result)
+    })
+}
+
+/// A scope for starting filters, created by [`scope()`].
+pub struct Scope<'scope, 'env: 'scope> {
+    inner: &'scope thread::Scope<'scope, 'env>,
+    copies: Mutex<Vec<ScopedJoinHandle<'scope, io::Result<u64>>>>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Start a [`LambdaFilter`] whose closure borrows from outside the scope.
+    pub fn start<F>(
+        &self,
+        filter: LambdaFilter<F>,
+        input: ReadStream,
+        output: WriteStream,
+    ) -> io::Result<ScopedLambda<'scope, F::FinishResult>>
+    where
+        F: TryLambda + Send + 'scope,
+        F::FinishResult: 'scope,
+    {
+        let cpus = filter
+            .cpu_affinity
+            .as_deref()
+            .map(CpuSet::new)
+            .transpose()
+            .map_err(|e| name_error(filter.name.as_deref(), e))?;
+        let opts = filter.run_opts().for_output(&output);
+        let (input_rx, input_tx, input_fd) = read_stream_fd(input)?;
+        let input_rx: Box<dyn Read + Send> = match filter.stop {
+            Some(stop) => Box::new(StopReader::new(input_rx, input_fd, stop)?),
+            None => input_rx,
+        };
+        let (output_tx, output_rx) = write_stream(output)?;
+
+        let aborted = Arc::new(AtomicBool::new(false));
+        let shim_aborted = Arc::clone(&aborted);
+        let completion = Completion::new();
+        let guard = completion.guard();
+        let handler = filter.handler;
+        let thread_name = filter.name.clone().unwrap_or_else(|| "lambda".to_owned());
+        let handle = spawn_scoped_thread(
+            self.inner,
+            Some(thread_name),
+            filter.stack_size,
+            move || {
+                let _guard = guard;
+                if let Some(cpus) = cpus {
+                    cpus.apply()?;
+                }
+                lambda::run(handler, input_rx, output_tx, opts, shim_aborted)
+            },
+        )?;
+        Ok(ScopedLambda {
+            handle,
+            name: filter.name,
+            aborted,
+            completion,
+            input_pipe: input_tx.map(Into::into),
+            output_pipe: output_rx.map(Into::into),
+        })
+    }
+
+    /// Make a [`ReadStream`] for any filter from a [`Read`] stream which borrows from outside the
+    /// scope. A thread in the scope copies from it into a pipe.
+    pub fn reader(&self, mut r: impl Read + Send + 'scope) -> io::Result<ReadStream> {
+        let (rx, mut tx) = os_pipe::pipe()?;
+        let t = spawn_scoped_thread(self.inner, None, None, move || io::copy(&mut r, &mut tx))?;
+        self.copies.lock().push(t);
+        Ok(ReadStream::Fd(rx.into()))
+    }
+
+    /// Make a [`WriteStream`] for any filter from a [`Write`] stream which borrows from outside
+    /// the scope. A thread in the scope copies into it from a pipe.
+    pub fn writer(&self, mut w: impl Write + Send + 'scope) -> io::Result<WriteStream> {
+        let (mut rx, tx) = os_pipe::pipe()?;
+        let t = spawn_scoped_thread(self.inner, None, None, move || io::copy(&mut rx, &mut w))?;
+        self.copies.lock().push(t);
+        Ok(WriteStream::Fd(tx.into()))
+    }
+}
+
+/// A running [`LambdaFilter`] started in a [`Scope`].
+pub struct ScopedLambda<'scope, R> {
+    handle: ScopedJoinHandle<'scope, Result<R, LambdaError<R>>>,
+    name: Option<String>,
+    aborted: Arc<AtomicBool>,
+    completion: Completion,
+    input_pipe: Option<OwnedPipeEnd>,
+    output_pipe: Option<OwnedPipeEnd>,
+}
+
+impl<R> RunningFilter for ScopedLambda<'_, R> {
+    type Result = Result<R, LambdaError<R>>;
+
+    fn wait(self) -> Self::Result {
+        // Unlike RunningLambda, a thread stuck after an abort can't be left behind, because the
+        // scope waits for it anyway.
+        let finished = self.handle.is_finished();
+        let result = self
+            .handle
+            .join()
+            .unwrap_or_else(|p| Err(ThreadPanicked::ioerr(p).into()));
+        let result = if self.aborted.load(Ordering::SeqCst) && !finished {
+            Err(LambdaError {
+                error: Aborted::ioerr(),
+                partial: result.ok(),
+            })
+        } else {
+            result
+        };
+        result.map_err(|e| e.named(self.name.as_deref()))
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn abort(&mut self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        self.input_pipe = None;
+        self.output_pipe = None;
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.handle.is_finished()
+    }
+
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static) {
+        self.completion.on_complete(f);
+    }
+
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.input_pipe.take()
+    }
+
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.output_pipe.take()
+    }
+}
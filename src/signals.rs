@@ -0,0 +1,132 @@
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, IntoRawFd};
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+use os_pipe::PipeReader;
+use parking_lot::Mutex;
+
+use crate::misc::spawn_thread;
+
+/// The write end of the pipe from the signal handler to the relay thread, or -1 until
+/// [`install_signal_forwarding()`] is first called.
+static HANDLER_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Children to forward signals to: registration ID, PID, and whether to signal its process group.
+static CHILDREN: Mutex<Vec<(u64, libc::pid_t, bool)>> = Mutex::new(vec![]);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Held while installing, so that two callers don't both start a relay thread.
+static INSTALL: Mutex<()> = Mutex::new(());
+
+/// Install handlers for the given signals which pass them on to every child registered with
+/// [`RunningChild::forward_signals()`](crate::RunningChild::forward_signals), or to its process
+/// group if it has one. This is for children started in their own process group, which don't get
+/// `SIGINT` from the terminal when the user hits Ctrl-C.
+///
+/// The handlers replace whatever was there before, including the default action, so this process
+/// is no longer stopped by these signals itself: it's up to the program to notice its children
+/// exiting and exit in turn. The handler only writes to a pipe; a thread reads from it and does
+/// the forwarding. Calling this again adds more signals.
+///
+/// Only available with the `signals` feature.
+pub fn install_signal_forwarding(signals: &[i32]) -> io::Result<()> {
+    let _install = INSTALL.lock();
+    if HANDLER_PIPE.load(Ordering::SeqCst) == -1 {
+        let (rx, tx) = os_pipe::pipe()?;
+        // If the relay thread falls behind, drop signals rather than block in the handler.
+        // SAFETY: FFI calls with no pointers.
+        unsafe {
+            let flags = libc::fcntl(tx.as_raw_fd(), libc::F_GETFL);
+            if flags == -1
+                || libc::fcntl(tx.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        spawn_thread(Some("signal relay".to_owned()), move || relay(rx))?;
+        // The handlers use this for the rest of the process's life.
+        HANDLER_PIPE.store(tx.into_raw_fd(), Ordering::SeqCst);
+    }
+    for &sig in signals {
+        // SAFETY: sigaction is a plain C struct.
+        let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        // SAFETY: FFI calls with valid pointers, or null where sigaction allows it.
+        unsafe {
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(sig, &action, std::ptr::null_mut()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn handler(sig: libc::c_int) {
+    // Signal numbers all fit in a byte. write is async-signal-safe, but can change errno under
+    // whatever the signal interrupted.
+    let byte = sig as u8;
+    // SAFETY: FFI calls with valid pointers.
+    unsafe {
+        let errno = *errno_location();
+        libc::write(
+            HANDLER_PIPE.load(Ordering::Relaxed),
+            (&byte as *const u8).cast(),
+            1,
+        );
+        *errno_location() = errno;
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__error()
+}
+
+/// Read signal numbers from the handler, and send each to all the registered children.
+fn relay(mut rx: PipeReader) -> io::Result<()> {
+    let mut buf = [0; 64];
+    loop {
+        let n = match rx.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // Registrations are removed under this lock before a child is reaped, so none of these
+        // PIDs can have been reused.
+        let children = CHILDREN.lock();
+        for &sig in &buf[..n] {
+            for &(_, pid, group) in children.iter() {
+                let target = if group { -pid } else { pid };
+                // SAFETY: FFI call with no pointers.
+                unsafe { libc::kill(target, sig.into()) };
+            }
+        }
+    }
+}
+
+/// A child's place in the list to forward signals to, which it leaves when this is dropped. This
+/// must happen before the child is reaped.
+pub(crate) struct Registration(u64);
+
+impl Registration {
+    pub fn new(pid: u32, group: bool) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        CHILDREN.lock().push((id, pid as libc::pid_t, group));
+        Self(id)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        CHILDREN.lock().retain(|&(id, _, _)| id != self.0);
+    }
+}
@@ -0,0 +1,25 @@
+use std::io;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+
+use crate::{ReadStream, WriteStream};
+
+/// Split a TCP socket into a stream for reading from it and a stream for writing to it, so that
+/// one filter can take its input from the socket and another (or the same one) can send its output
+/// back.
+///
+/// Each half gets its own file descriptor, so a [`ChildProcess`](crate::ChildProcess) can use them
+/// as its stdin and stdout directly, and closing one half doesn't close the other. Note that
+/// closing a half does not shut down that direction of the socket: the peer only sees EOF once
+/// both halves are closed.
+pub fn split_socket(socket: TcpStream) -> io::Result<(ReadStream, WriteStream)> {
+    let other = socket.try_clone()?;
+    Ok((ReadStream::from(socket), WriteStream::from(other)))
+}
+
+/// Split a Unix socket into a stream for reading from it and a stream for writing to it. See
+/// [`split_socket()`].
+pub fn split_unix_socket(socket: UnixStream) -> io::Result<(ReadStream, WriteStream)> {
+    let other = socket.try_clone()?;
+    Ok((ReadStream::from(socket), WriteStream::from(other)))
+}
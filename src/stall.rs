@@ -0,0 +1,130 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::misc::spawn_thread;
+use crate::process::has_exited;
+
+/// When data last moved through a child's stdout (or stdin), for
+/// [`ChildProcess::stall_timeout()`](crate::ChildProcess::stall_timeout).
+#[derive(Clone)]
+pub(crate) struct Progress(Arc<Mutex<Instant>>);
+
+impl Progress {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    fn update(&self, n: usize) {
+        if n > 0 {
+            *self.0.lock() = Instant::now();
+        }
+    }
+
+    fn last(&self) -> Instant {
+        *self.0.lock()
+    }
+}
+
+/// Passes reads through, noting when data arrives.
+pub(crate) struct ProgressReader<R> {
+    pub inner: R,
+    pub progress: Progress,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.update(n);
+        Ok(n)
+    }
+}
+
+/// Passes writes through, noting when data leaves.
+pub(crate) struct ProgressWriter<W> {
+    pub inner: W,
+    pub progress: Progress,
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.progress.update(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A thread which kills a child with `SIGKILL` if it makes no progress for too long. It stops
+/// when this is dropped.
+pub(crate) struct Watchdog {
+    // The child's PID, until it's reaped. The thread only signals it while holding the lock.
+    pid: Arc<Mutex<Option<u32>>>,
+    stalled: Arc<AtomicBool>,
+    timeout: Duration,
+    _stop: Sender<()>,
+}
+
+impl Watchdog {
+    pub fn start(
+        pid: u32,
+        timeout: Duration,
+        progress: Progress,
+        name: Option<String>,
+    ) -> io::Result<Self> {
+        let pid = Arc::new(Mutex::new(Some(pid)));
+        let stalled = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = channel::<()>();
+        let thread_pid = Arc::clone(&pid);
+        let thread_stalled = Arc::clone(&stalled);
+        spawn_thread(name, move || loop {
+            let wait = (progress.last() + timeout).saturating_duration_since(Instant::now());
+            match stopped.recv_timeout(wait) {
+                Err(RecvTimeoutError::Timeout) => (),
+                _ => return,
+            }
+            if progress.last().elapsed() >= timeout {
+                let pid = thread_pid.lock();
+                // A child which already exited on its own, and is only waiting to be reaped,
+                // didn't stall.
+                if let Some(pid) = pid.filter(|&pid| matches!(has_exited(pid), Ok(false))) {
+                    thread_stalled.store(true, Ordering::SeqCst);
+                    // SAFETY: FFI call with no pointers. The child hasn't been reaped, so the PID
+                    // is still ours.
+                    unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+                }
+                return;
+            }
+        })?;
+        Ok(Self {
+            pid,
+            stalled,
+            timeout,
+            _stop: stop,
+        })
+    }
+
+    /// Hold off the thread while reaping the child. Set the PID to `None` if it was reaped.
+    pub fn lock(&self) -> MutexGuard<'_, Option<u32>> {
+        self.pid.lock()
+    }
+
+    /// The timeout, if the child was killed for going over it.
+    pub fn stalled(&self) -> Option<Duration> {
+        self.stalled.load(Ordering::SeqCst).then_some(self.timeout)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // The thread might already be past checking whether it's been stopped.
+        *self.pid.lock() = None;
+    }
+}
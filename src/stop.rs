@@ -0,0 +1,103 @@
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use os_pipe::{PipeReader, PipeWriter};
+use parking_lot::Mutex;
+
+/// Stops a [`LambdaFilter`](crate::LambdaFilter) from another thread, the same way as its lambda
+/// returning [`ControlFlow::Break`](std::ops::ControlFlow::Break): it stops reading, closes its
+/// input and output, and calls [`TryLambda::finish()`](crate::TryLambda::finish) with
+/// [`StreamEnd::Stopped`](crate::StreamEnd::Stopped), so the lambda's result isn't lost as it is
+/// with [`RunningFilter::abort()`](crate::RunningFilter::abort).
+///
+/// Get one from [`LambdaFilter::stop_handle()`](crate::LambdaFilter::stop_handle) before starting
+/// the filter. It can be cloned and sent to other threads.
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle(Arc<StopState>);
+
+#[derive(Debug, Default)]
+struct StopState {
+    stopped: AtomicBool,
+    /// Write ends of pipes which readers are waiting on along with their input. Closing them wakes
+    /// the readers up.
+    wake: Mutex<Vec<PipeWriter>>,
+}
+
+impl StopHandle {
+    /// Stop the filter. A read waiting on a pipe or other file descriptor is woken up, so the
+    /// filter stops right away, unless it's in the middle of handling a buffer or writing to its
+    /// output, in which case it stops once that's done. A read from a
+    /// [`ReadStream::Rust`](crate::ReadStream::Rust) stream can't be interrupted, so the filter
+    /// stops when it returns.
+    pub fn stop(&self) {
+        self.0.stopped.store(true, Ordering::SeqCst);
+        self.0.wake.lock().clear();
+    }
+
+    /// Whether [`StopHandle::stop()`] has been called.
+    pub fn is_stopped(&self) -> bool {
+        self.0.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Reads from a filter's input until its [`StopHandle`] is used, then gives end-of-file. If the
+/// input is a file descriptor, each read first waits for either it or the stop handle.
+pub(crate) struct StopReader<R> {
+    inner: R,
+    fd: Option<(RawFd, PipeReader)>,
+    stop: StopHandle,
+}
+
+impl<R> StopReader<R> {
+    /// `fd` must stay open as long as `inner` does.
+    pub fn new(inner: R, fd: Option<RawFd>, stop: StopHandle) -> io::Result<Self> {
+        let fd = match fd {
+            Some(fd) => {
+                let (rx, tx) = os_pipe::pipe()?;
+                stop.0.wake.lock().push(tx);
+                Some((fd, rx))
+            }
+            None => None,
+        };
+        Ok(Self { inner, fd, stop })
+    }
+}
+
+impl<R: Read> Read for StopReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.stop.is_stopped() {
+            return Ok(0);
+        }
+        if let Some((fd, wake)) = &self.fd {
+            let mut fds = [
+                libc::pollfd {
+                    fd: *fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: wake.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            loop {
+                // SAFETY: FFI call with a valid pointer and length.
+                if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } != -1 {
+                    break;
+                }
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            // Only stop() closes the other end.
+            if fds[1].revents != 0 {
+                return Ok(0);
+            }
+        }
+        self.inner.read(buf)
+    }
+}
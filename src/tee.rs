@@ -1,21 +1,31 @@
 use std::io::{self, Read, Write};
-use std::os::fd::OwnedFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{sync_channel, SyncSender};
 use std::sync::Arc;
-use std::thread::{self, JoinHandle};
+use std::thread::JoinHandle;
 
 use parking_lot::{Condvar, Mutex, RwLock};
 
-use crate::misc::{read_stream, write_stream, ThreadPanicked};
-use crate::{Filter, ReadStream, RunningFilter, WriteStream};
+use crate::affinity::CpuSet;
+use crate::completion::Completion;
+use crate::misc::{
+    name_error, read_stream, spawn_thread_sized, write_stream, Aborted, ThreadPanicked,
+};
+use crate::{Filter, OwnedPipeEnd, ReadStream, RunningFilter, WriteStream};
 
 /// [`Tee`] takes input from a [`ReadStream`] and copies it simultaneously to
 /// any number of [`Write`] streams.
 pub struct Tee {
-    threads: Vec<JoinHandle<io::Result<()>>>,
+    /// The output threads, or why one couldn't be started.
+    threads: Vec<io::Result<JoinHandle<io::Result<()>>>>,
     channels: Vec<SyncSender<Arc<RwLock<Vec<u8>>>>>,
     notify: Arc<(Mutex<usize>, Condvar)>,
     buffer: Arc<RwLock<Vec<u8>>>,
+    aborted: Arc<AtomicBool>,
+    completion: Completion,
+    name: Option<String>,
+    cpu_affinity: Option<Vec<usize>>,
+    stack_size: Option<usize>,
 }
 
 impl Tee {
@@ -26,10 +36,40 @@ impl Tee {
             channels: vec![],
             notify: Arc::new((Mutex::new(0), Condvar::new())),
             buffer: Arc::new(RwLock::new(vec![0; buffer_size])),
+            aborted: Arc::new(AtomicBool::new(false)),
+            completion: Completion::new(),
+            name: None,
+            cpu_affinity: None,
+            stack_size: None,
         }
     }
 
-    /// Add a destination [`Write`] stream to the tee.
+    /// Give the filter a name, which is included in errors and used to name its threads. Threads
+    /// for outputs which were already added with [`Tee::add_output()`] keep their old names.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Run the filter's threads only on the given logical CPUs, numbered from 0. As with
+    /// [`Tee::name()`], threads for outputs which were already added aren't affected. The CPUs are
+    /// checked against the number in the system when the filter is started; an output thread
+    /// which can't be moved to them fails without writing anything. This needs Linux.
+    pub fn cpu_affinity(mut self, cpus: &[usize]) -> Self {
+        self.cpu_affinity = Some(cpus.to_vec());
+        self
+    }
+
+    /// Give the filter's threads stacks of the given size, instead of Rust's default, for outputs
+    /// which run code of their own as they're written to. As with [`Tee::name()`], threads for
+    /// outputs which were already added aren't affected.
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Add a destination [`Write`] stream to the tee. If its thread can't be started, starting
+    /// the tee fails with the error.
     pub fn add_output(&mut self, mut w: impl Write + Send + 'static) {
         let (tx, rx) = sync_channel(0);
         self.channels.push(tx);
@@ -40,14 +80,36 @@ impl Tee {
             *n += 1;
             cv.notify_all();
         };
-        let t = thread::spawn(move || {
+        let aborted = Arc::clone(&self.aborted);
+        let thread_name = format!(
+            "{} output {}",
+            self.name.as_deref().unwrap_or("tee"),
+            self.threads.len()
+        );
+        let cpus = self.cpu_affinity.clone();
+        let guard = self.completion.guard();
+        let t = spawn_thread_sized(Some(thread_name), self.stack_size, move || {
+            let _guard = guard;
+            if let Some(cpus) = cpus {
+                CpuSet::new(&cpus)?.apply()?;
+            }
             while let Ok(buf) = rx.recv() {
                 let res = w.write_all(&buf.read());
                 notify();
+                if aborted.load(Ordering::SeqCst) {
+                    return Err(Aborted::ioerr());
+                }
                 res?;
             }
-            Ok(())
+            if aborted.load(Ordering::SeqCst) {
+                return Err(Aborted::ioerr());
+            }
+            w.flush()
         });
+        if t.is_err() {
+            // Leave the channel's other end dropped, so the main thread skips this output.
+            self.channels.pop();
+        }
         self.threads.push(t);
     }
 }
@@ -63,6 +125,82 @@ fn read_loop(mut f: impl Read, buf: &mut [u8]) -> io::Result<usize> {
     }
 }
 
+/// Read from `input` into the buffer and hand it to each output thread, and to `local` on this
+/// thread, until EOF.
+fn tee_loop(
+    mut input: impl Read,
+    buffer: Arc<RwLock<Vec<u8>>>,
+    mut channels: Vec<SyncSender<Arc<RwLock<Vec<u8>>>>>,
+    notify: &(Mutex<usize>, Condvar),
+    aborted: &AtomicBool,
+    mut local: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let (mx, cv) = notify;
+    loop {
+        if aborted.load(Ordering::SeqCst) {
+            return Err(Aborted::ioerr());
+        }
+        let mut buf_write = buffer.write();
+        let n = read_loop(&mut input, &mut buf_write)?;
+        if n == 0 {
+            break;
+        }
+        buf_write.truncate(n);
+        drop(buf_write);
+        let mut dead = vec![];
+        for (i, tx) in channels.iter().enumerate() {
+            if tx.send(Arc::clone(&buffer)).is_err() {
+                dead.push(i);
+            }
+        }
+        for i in dead.iter().rev() {
+            channels.remove(*i);
+        }
+        local(&buffer.read());
+        let mut n = mx.lock();
+        while *n < channels.len() && !aborted.load(Ordering::SeqCst) {
+            cv.wait(&mut n);
+        }
+        *n = 0;
+    }
+    Ok(())
+}
+
+impl Tee {
+    /// Copy `input` to the streams added previously with [`Tee::add_output()`] and to `output`,
+    /// like [`Filter::start()`] followed by [`RunningFilter::wait()`], but reading the input and
+    /// writing to `output` on the current thread. Neither stream needs to be [`Send`].
+    ///
+    /// The results are in the same order as from [`RunningTee`]: reading the input first, then
+    /// each output added with [`Tee::add_output()`], then `output`.
+    pub fn run_blocking(self, input: impl Read, mut output: impl Write) -> Vec<io::Result<()>> {
+        let mut output_result = Ok(());
+        let result = tee_loop(
+            input,
+            self.buffer,
+            self.channels,
+            &self.notify,
+            &self.aborted,
+            |buf| {
+                if output_result.is_ok() {
+                    output_result = output.write_all(buf);
+                }
+            },
+        );
+        if output_result.is_ok() {
+            output_result = output.flush();
+        }
+        let name = self.name.as_deref();
+        std::iter::once(result)
+            .chain(self.threads.into_iter().map(|t| {
+                t.and_then(|t| t.join().unwrap_or_else(|p| Err(ThreadPanicked::ioerr(p))))
+            }))
+            .chain(std::iter::once(output_result))
+            .map(|r| r.map_err(|e| name_error(name, e)))
+            .collect()
+    }
+}
+
 impl Filter for Tee {
     type Running = RunningTee;
     type Error = io::Error;
@@ -77,6 +215,12 @@ impl Filter for Tee {
         input: ReadStream,
         output: WriteStream,
     ) -> Result<Self::Running, Self::Error> {
+        let cpus = self
+            .cpu_affinity
+            .as_deref()
+            .map(CpuSet::new)
+            .transpose()
+            .map_err(|e| name_error(self.name.as_deref(), e))?;
         let (mut in_rx, in_tx) = read_stream(input)?;
         let mut output_pipe = None;
 
@@ -85,41 +229,33 @@ impl Filter for Tee {
             self.add_output(out_tx);
             output_pipe = out_rx.map(Into::into);
         }
+        let mut threads = self
+            .threads
+            .into_iter()
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| name_error(self.name.as_deref(), e))?;
 
         let buffer = self.buffer;
-        let mut channels = self.channels;
-        let mut threads = self.threads;
-        let t = thread::spawn(move || {
-            let (mx, cv) = &*self.notify;
-            loop {
-                let mut buf_write = buffer.write();
-                let n = read_loop(&mut in_rx, &mut buf_write)?;
-                if n == 0 {
-                    break;
-                }
-                buf_write.truncate(n);
-                drop(buf_write);
-                let mut dead = vec![];
-                for (i, tx) in channels.iter().enumerate() {
-                    if tx.send(Arc::clone(&buffer)).is_err() {
-                        dead.push(i);
-                    }
-                }
-                for i in dead.iter().rev() {
-                    channels.remove(*i);
-                }
-                let mut n = mx.lock();
-                while *n < channels.len() {
-                    cv.wait(&mut n);
-                }
-                *n = 0;
+        let channels = self.channels;
+        let notify = Arc::clone(&self.notify);
+        let aborted = Arc::clone(&self.aborted);
+        let guard = self.completion.guard();
+        let thread_name = self.name.clone().unwrap_or_else(|| "tee".to_owned());
+        let t = spawn_thread_sized(Some(thread_name), self.stack_size, move || {
+            let _guard = guard;
+            if let Some(cpus) = cpus {
+                cpus.apply()?;
             }
-            Ok(())
-        });
+            tee_loop(&mut in_rx, buffer, channels, &notify, &aborted, |_| ())
+        })?;
         threads.insert(0, t); // wait on this thread before others
 
         Ok(RunningTee {
             threads,
+            notify: self.notify,
+            aborted: self.aborted,
+            completion: self.completion,
+            name: self.name,
             input_pipe: in_tx.map(Into::into),
             output_pipe,
         })
@@ -129,25 +265,61 @@ impl Filter for Tee {
 /// A running instance of a [`Tee`].
 pub struct RunningTee {
     threads: Vec<JoinHandle<io::Result<()>>>,
-    input_pipe: Option<OwnedFd>,
-    output_pipe: Option<OwnedFd>,
+    notify: Arc<(Mutex<usize>, Condvar)>,
+    aborted: Arc<AtomicBool>,
+    completion: Completion,
+    name: Option<String>,
+    input_pipe: Option<OwnedPipeEnd>,
+    output_pipe: Option<OwnedPipeEnd>,
 }
 
 impl RunningFilter for RunningTee {
     type Result = Vec<io::Result<()>>;
 
     fn wait(self) -> Self::Result {
+        let aborted = self.aborted.load(Ordering::SeqCst);
         self.threads
             .into_iter()
-            .map(|t| t.join().unwrap_or(Err(ThreadPanicked::ioerr())))
+            .map(|t| {
+                if aborted && !t.is_finished() {
+                    // Probably stuck writing; leave it behind.
+                    return Err(Aborted::ioerr());
+                }
+                t.join().unwrap_or_else(|p| Err(ThreadPanicked::ioerr(p)))
+            })
+            .map(|r| r.map_err(|e| name_error(self.name.as_deref(), e)))
             .collect()
     }
 
-    fn input_pipe(&mut self) -> Option<OwnedFd> {
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn abort(&mut self) {
+        {
+            // Hold the lock so the main thread can't miss the notification.
+            let (mx, cv) = &*self.notify;
+            let _n = mx.lock();
+            self.aborted.store(true, Ordering::SeqCst);
+            cv.notify_all();
+        }
+        self.input_pipe = None;
+        self.output_pipe = None;
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.threads.iter().all(JoinHandle::is_finished)
+    }
+
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static) {
+        self.completion.on_complete(f);
+    }
+
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
         self.input_pipe.take()
     }
 
-    fn output_pipe(&mut self) -> Option<OwnedFd> {
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
         self.output_pipe.take()
     }
 }
@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use crate::completion::Completion;
+use crate::{Filter, OwnedPipeEnd, ReadStream, RunningFilter, WriteStream};
+
+/// Two filters composed into one, with the output of the first piped into the input of the
+/// second. Created by [`Filter::then()`].
+pub struct Then<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> Then<A, B> {
+    pub(crate) fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A, B> Filter for Then<A, B>
+where
+    A: Filter,
+    B: Filter,
+    A::Error: 'static,
+    B::Error: 'static,
+{
+    type Running = RunningThen<A::Running, B::Running>;
+    type Error = ThenError<A::Error, B::Error>;
+
+    /// Starts the first filter with the given input and the second filter with the given output.
+    /// If the second filter fails to start, the first is aborted.
+    fn start(self, input: ReadStream, output: WriteStream) -> Result<Self::Running, Self::Error> {
+        let mut first = self
+            .first
+            .start(input, WriteStream::PipeRequested)
+            .map_err(ThenError::First)?;
+        let Some(pipe) = first.output_pipe() else {
+            abort(first);
+            return Err(ThenError::NoPipe);
+        };
+        match self.second.start(ReadStream::Fd(pipe), output) {
+            Ok(second) => Ok(RunningThen { first, second }),
+            Err(e) => {
+                abort(first);
+                Err(ThenError::Second(e))
+            }
+        }
+    }
+}
+
+fn abort(mut running: impl RunningFilter) {
+    running.abort();
+    let _ = running.wait();
+}
+
+/// A running [`Then`] filter.
+pub struct RunningThen<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: RunningFilter, B: RunningFilter> RunningFilter for RunningThen<A, B> {
+    /// The results of the first and second filters.
+    type Result = (A::Result, B::Result);
+
+    fn wait(self) -> Self::Result {
+        let first = self.first.wait();
+        let second = self.second.wait();
+        (first, second)
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.first.is_finished() && self.second.is_finished()
+    }
+
+    fn abort(&mut self) {
+        self.first.abort();
+        self.second.abort();
+    }
+
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static) {
+        let completion = Completion::new();
+        let first = completion.guard();
+        let second = completion.guard();
+        completion.on_complete(f);
+        self.first.on_complete(move || drop(first));
+        self.second.on_complete(move || drop(second));
+    }
+
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.first.input_pipe()
+    }
+
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
+        self.second.output_pipe()
+    }
+}
+
+/// An error from starting a [`Then`] filter.
+#[derive(Debug)]
+pub enum ThenError<A, B> {
+    /// The first filter failed to start.
+    First(A),
+    /// The second filter failed to start.
+    Second(B),
+    /// The first filter didn't provide an output pipe when asked for one.
+    NoPipe,
+}
+
+impl<A: Display, B: Display> Display for ThenError<A, B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThenError::First(e) => write!(f, "first filter failed to start: {e}"),
+            ThenError::Second(e) => write!(f, "second filter failed to start: {e}"),
+            ThenError::NoPipe => f.write_str("first filter didn't provide an output pipe"),
+        }
+    }
+}
+
+impl<A: Error + 'static, B: Error + 'static> Error for ThenError<A, B> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ThenError::First(e) => Some(e),
+            ThenError::Second(e) => Some(e),
+            ThenError::NoPipe => None,
+        }
+    }
+}
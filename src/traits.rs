@@ -1,41 +1,405 @@
+use std::borrow::Cow;
 use std::error::Error;
-use std::io::{Read, Write};
-use std::os::fd::OwnedFd;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::fd::BorrowedFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{ChildStdin, ChildStdout};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::Arc;
+
+use os_pipe::{PipeReader, PipeWriter};
+
+use crate::misc::IterReader;
+use crate::{BufferedInput, InputPipe, IoChainError, NormalizedResult, OutputPipe, Then};
+
+/// An owned OS-level handle to one end of a pipe, file, socket, or similar: a file descriptor on
+/// Unix, and a handle on Windows.
+#[cfg(unix)]
+pub type OwnedPipeEnd = std::os::fd::OwnedFd;
+
+/// An owned OS-level handle to one end of a pipe, file, socket, or similar: a file descriptor on
+/// Unix, and a handle on Windows.
+#[cfg(windows)]
+pub type OwnedPipeEnd = std::os::windows::io::OwnedHandle;
 
 /// A source for reading data.
 pub enum ReadStream {
-    /// A file descriptor. The filter will close it when it finishes.
-    Fd(OwnedFd),
+    /// A file descriptor (or handle). The filter will close it when it finishes.
+    Fd(OwnedPipeEnd),
 
     /// A Rust [`Read`] stream.
     Rust(Box<dyn Read + Send>),
 
+    /// A path to a file, which will be opened for reading when the filter starts.
+    File(PathBuf),
+
+    /// A range of bytes from a file, read with positional reads so that several ranges of one
+    /// shared [`File`] can be read at the same time without interfering. The stream ends early if
+    /// the file is shorter than `offset + len`.
+    FileRange {
+        /// The file to read.
+        file: FileRef,
+        /// Where in the file to start reading.
+        offset: u64,
+        /// The number of bytes to read.
+        len: u64,
+    },
+
+    /// A path to a file, which will be memory-mapped when the filter starts. This avoids read
+    /// system calls when the whole of a large file is going to be consumed by the Rust copy loops.
+    ///
+    /// The file must not be modified while the filter runs.
+    #[cfg(feature = "mmap")]
+    Mmap(PathBuf),
+
+    /// A named pipe (FIFO), which is created when the filter starts if it doesn't exist yet.
+    ///
+    /// So that starting the filter doesn't block until something opens the other end, the FIFO
+    /// is only opened when the filter first uses it, and a [`ChildProcess`](crate::ChildProcess)
+    /// accesses it through a copy thread.
+    Fifo {
+        /// The path of the FIFO.
+        path: PathBuf,
+        /// Whether to remove the FIFO when the stream is finished, if it was created by this
+        /// stream. Make sure the other end has opened it by then.
+        remove: bool,
+    },
+
+    /// An in-memory buffer.
+    Bytes(Cow<'static, [u8]>),
+
+    /// Chunks of data received from a channel, in order. The stream ends when all senders are
+    /// dropped.
+    Channel(Receiver<Vec<u8>>),
+
+    /// Several streams read one after another, like `cat`. All of them are opened when the filter
+    /// starts. [`ReadStream::PipeRequested`] can't be used inside a chain.
+    Chain(Vec<ReadStream>),
+
+    /// At most `limit` bytes from another stream, like `head -c`. The inner stream is closed as soon
+    /// as the limit is reached, so a process writing to it gets `EPIPE`. The inner stream can't be
+    /// [`ReadStream::PipeRequested`].
+    Limited {
+        /// The stream to read from.
+        inner: Box<ReadStream>,
+        /// The maximum number of bytes to read.
+        limit: u64,
+    },
+
+    /// The given pattern, repeated forever. Usually combined with [`ReadStream::Limited`]. An empty
+    /// pattern gives an empty stream.
+    Repeat(Vec<u8>),
+
+    /// Zero bytes, forever, like `/dev/zero` (but without opening it). Usually combined with
+    /// [`ReadStream::Limited`].
+    Zeros,
+
+    /// This process's standard input. A child process inherits it directly; other filters read
+    /// from [`std::io::stdin()`], so any data already buffered there is not lost.
+    Inherit,
+
     /// Request the filter to create a pipe and attach it to the input when it starts up. The write
     /// end of the pipe will be available by calling [`RunningFilter::input_pipe()`] on the result
     /// of starting the filter.
     PipeRequested,
 
+    /// Like [`ReadStream::PipeRequested`], with extra options for the pipe.
+    Pipe(PipeOpts),
+
     /// No input, like `/dev/null`.
     Null,
 }
 
+impl ReadStream {
+    /// Read from a duplicate of the given file descriptor, leaving the original open and owned by
+    /// the caller.
+    ///
+    /// The duplicate is made immediately, rather than when the filter starts, and is close-on-exec
+    /// so it won't leak into unrelated child processes. (A [`ChildProcess`](crate::ChildProcess)
+    /// still gets it as its stdin.)
+    pub fn fd_dup(fd: BorrowedFd<'_>) -> io::Result<Self> {
+        fd.try_clone_to_owned().map(ReadStream::Fd)
+    }
+
+    /// Read from an iterator of chunks of data. An error from the iterator is returned from the
+    /// read, and ends the stream.
+    pub fn from_chunks<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = io::Result<Vec<u8>>>,
+        I::IntoIter: Send + 'static,
+    {
+        ReadStream::Rust(Box::new(IterReader::new(iter.into_iter())))
+    }
+
+    /// Read from a FIFO at the given path, creating it if needed and leaving it in place afterwards.
+    /// See [`ReadStream::Fifo`].
+    pub fn fifo(path: impl Into<PathBuf>) -> Self {
+        ReadStream::Fifo {
+            path: path.into(),
+            remove: false,
+        }
+    }
+
+    /// Request a pipe, as with [`ReadStream::PipeRequested`], with the given capacity in bytes.
+    /// See [`PipeOpts::capacity()`].
+    pub fn pipe_with_capacity(bytes: usize) -> Self {
+        ReadStream::Pipe(PipeOpts::new().capacity(bytes))
+    }
+
+    /// Request a pipe, as with [`ReadStream::PipeRequested`], with the returned end in non-blocking
+    /// mode. See [`PipeOpts::nonblocking()`].
+    pub fn pipe_nonblocking() -> Self {
+        ReadStream::Pipe(PipeOpts::new().nonblocking(true))
+    }
+
+    pub(crate) fn is_pipe(&self) -> bool {
+        matches!(self, ReadStream::PipeRequested | ReadStream::Pipe(_))
+    }
+}
+
+impl From<Vec<u8>> for ReadStream {
+    fn from(v: Vec<u8>) -> Self {
+        ReadStream::Bytes(Cow::Owned(v))
+    }
+}
+
+impl From<&'static str> for ReadStream {
+    fn from(s: &'static str) -> Self {
+        ReadStream::Bytes(Cow::Borrowed(s.as_bytes()))
+    }
+}
+
+macro_rules! from_fd {
+    ($stream:ident: $($t:ty),+) => {
+        $(
+            impl From<$t> for $stream {
+                fn from(x: $t) -> Self {
+                    $stream::Fd(OwnedPipeEnd::from(x))
+                }
+            }
+        )+
+    };
+}
+
+from_fd!(ReadStream: OwnedPipeEnd, File, TcpStream, UnixStream, PipeReader, ChildStdout, OutputPipe);
+
 /// A destination for writing data.
 pub enum WriteStream {
-    /// A file descriptor. The filter will close it when it finishes.
-    Fd(OwnedFd),
+    /// A file descriptor (or handle). The filter will close it when it finishes.
+    Fd(OwnedPipeEnd),
 
     /// A Rust [`Write`] stream.
     Rust(Box<dyn Write + Send>),
 
+    /// A path to a file, which will be opened for writing with the given options when the filter
+    /// starts.
+    File {
+        /// The path to open.
+        path: PathBuf,
+        /// How to open it.
+        options: FileOpts,
+    },
+
+    /// This process's standard output. A child process inherits it directly; other filters write
+    /// to [`std::io::stdout()`], flushing after every write.
+    ///
+    /// Several filters can inherit stdout at once, but nothing orders their output: it will be
+    /// interleaved in whatever size chunks each filter happens to write.
+    Inherit,
+
+    /// Write to a file at the given offset using positional writes, so that several filters can
+    /// write to different regions of one shared [`File`] at the same time. If given a path, the
+    /// file is created if needed but never truncated.
+    ///
+    /// A [`ChildProcess`](crate::ChildProcess) writes to this through a copy thread, since giving
+    /// it the file directly would share the file's offset.
+    FileAt {
+        /// The file to write.
+        file: FileRef,
+        /// Where in the file to start writing.
+        offset: u64,
+    },
+
+    /// A named pipe (FIFO), which is created when the filter starts if it doesn't exist yet.
+    ///
+    /// So that starting the filter doesn't block until something opens the other end, the FIFO
+    /// is only opened when the filter first uses it, and a [`ChildProcess`](crate::ChildProcess)
+    /// accesses it through a copy thread.
+    Fifo {
+        /// The path of the FIFO.
+        path: PathBuf,
+        /// Whether to remove the FIFO when the stream is finished, if it was created by this
+        /// stream. Make sure the other end has opened it by then.
+        remove: bool,
+    },
+
+    /// Several streams which each get a copy of all the data, written one after another on the
+    /// filter's thread. (Use a [`Tee`](crate::Tee) to write to them in parallel instead.) All of
+    /// them are opened when the filter starts. [`WriteStream::PipeRequested`] can't be used here.
+    Multi(Vec<WriteStream>),
+
+    /// Send the data as chunks over a channel. Writes block while the channel is full, and fail
+    /// with [`BrokenPipe`](std::io::ErrorKind::BrokenPipe) once the receiver is dropped.
+    Channel(SyncSender<Vec<u8>>),
+
     /// Request the filter to create a pipe and attach it to the output when it starts up. The read
     /// end of the pipe will be available by calling [`RunningFilter::output_pipe()`] on the result
     /// of starting the filter.
     PipeRequested,
 
+    /// Like [`WriteStream::PipeRequested`], with extra options for the pipe.
+    Pipe(PipeOpts),
+
     /// No output, like `/dev/null`.
     Null,
 }
 
+impl WriteStream {
+    /// Write to a duplicate of the given file descriptor, leaving the original open and owned by
+    /// the caller.
+    ///
+    /// The duplicate is made immediately, rather than when the filter starts, and is close-on-exec
+    /// so it won't leak into unrelated child processes. (A [`ChildProcess`](crate::ChildProcess)
+    /// still gets it as its stdout.)
+    pub fn fd_dup(fd: BorrowedFd<'_>) -> io::Result<Self> {
+        fd.try_clone_to_owned().map(WriteStream::Fd)
+    }
+
+    /// Write to a FIFO at the given path, creating it if needed and leaving it in place afterwards.
+    /// See [`WriteStream::Fifo`].
+    pub fn fifo(path: impl Into<PathBuf>) -> Self {
+        WriteStream::Fifo {
+            path: path.into(),
+            remove: false,
+        }
+    }
+
+    /// Request a pipe, as with [`WriteStream::PipeRequested`], with the given capacity in bytes.
+    /// See [`PipeOpts::capacity()`].
+    pub fn pipe_with_capacity(bytes: usize) -> Self {
+        WriteStream::Pipe(PipeOpts::new().capacity(bytes))
+    }
+
+    /// Request a pipe, as with [`WriteStream::PipeRequested`], with the returned end in non-blocking
+    /// mode. See [`PipeOpts::nonblocking()`].
+    pub fn pipe_nonblocking() -> Self {
+        WriteStream::Pipe(PipeOpts::new().nonblocking(true))
+    }
+
+    pub(crate) fn is_pipe(&self) -> bool {
+        matches!(self, WriteStream::PipeRequested | WriteStream::Pipe(_))
+    }
+}
+
+from_fd!(WriteStream: OwnedPipeEnd, File, TcpStream, UnixStream, PipeWriter, ChildStdin, InputPipe);
+
+/// Options for opening a [`WriteStream::File`]. These mirror the relevant parts of
+/// [`OpenOptions`](std::fs::OpenOptions); the file is always opened write-only.
+///
+/// The default options open an existing file without truncating it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileOpts {
+    pub(crate) create: bool,
+    pub(crate) create_new: bool,
+    pub(crate) append: bool,
+    pub(crate) truncate: bool,
+}
+
+impl FileOpts {
+    /// Options equivalent to the default: open an existing file without truncating it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the file if it doesn't exist.
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Create the file, failing if it already exists.
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Open the file in append mode (`O_APPEND`), so that each write goes to the end of the file
+    /// even if other processes are writing to it too.
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// Truncate the file to zero length when opening it.
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+}
+
+/// Options for a pipe requested with [`ReadStream::Pipe`] or [`WriteStream::Pipe`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipeOpts {
+    pub(crate) capacity: Option<usize>,
+    pub(crate) nonblocking: bool,
+}
+
+impl PipeOpts {
+    /// Options equivalent to a plain [`ReadStream::PipeRequested`] or
+    /// [`WriteStream::PipeRequested`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask for the pipe's buffer to hold the given number of bytes. This is only a hint: it is
+    /// ignored on platforms other than Linux, and the kernel may round it up or refuse it (for
+    /// example, if it exceeds `/proc/sys/fs/pipe-max-size`). Use [`pipe_capacity()`](crate::pipe_capacity)
+    /// on the pipe returned from the running filter to find out what it actually got.
+    pub fn capacity(mut self, bytes: usize) -> Self {
+        self.capacity = Some(bytes);
+        self
+    }
+
+    /// Put the end of the pipe that is returned from the running filter in non-blocking mode
+    /// (`O_NONBLOCK`), for use with an event loop. The filter's end, and any copying the filter
+    /// does internally, stays blocking.
+    pub fn nonblocking(mut self, nonblocking: bool) -> Self {
+        self.nonblocking = nonblocking;
+        self
+    }
+}
+
+/// A file for the stream variants which use positional I/O, either as a path to be opened when
+/// the filter starts, or as an already open file that may be shared with other streams.
+#[derive(Debug, Clone)]
+pub enum FileRef {
+    /// A path to open.
+    Path(PathBuf),
+    /// An open file.
+    File(Arc<File>),
+}
+
+impl From<PathBuf> for FileRef {
+    fn from(path: PathBuf) -> Self {
+        FileRef::Path(path)
+    }
+}
+
+impl From<File> for FileRef {
+    fn from(file: File) -> Self {
+        FileRef::File(Arc::new(file))
+    }
+}
+
+impl From<Arc<File>> for FileRef {
+    fn from(file: Arc<File>) -> Self {
+        FileRef::File(file)
+    }
+}
+
 /// An I/O filter.
 pub trait Filter {
     /// The type returned to reference the running filter.
@@ -47,6 +411,14 @@ pub trait Filter {
     /// Starts up the filter with the configured input and output streams, and runs it in the
     /// background.
     fn start(self, input: ReadStream, output: WriteStream) -> Result<Self::Running, Self::Error>;
+
+    /// Compose this filter with another one, which gets this filter's output as its input.
+    fn then<B: Filter>(self, next: B) -> Then<Self, B>
+    where
+        Self: Sized,
+    {
+        Then::new(self, next)
+    }
 }
 
 /// A running I/O filter.
@@ -57,11 +429,94 @@ pub trait RunningFilter {
     /// Wait for the filter to finish successfully or fail.
     fn wait(self) -> Self::Result;
 
+    /// Wait for the filter, and convert its result to a plain success or failure with
+    /// [`NormalizedResult::into_chain_result()`].
+    fn wait_ok(self) -> Result<(), IoChainError>
+    where
+        Self: Sized,
+        Self::Result: NormalizedResult,
+    {
+        self.wait().into_chain_result()
+    }
+
+    /// Check whether the filter has finished, without blocking. Once this returns true,
+    /// [`RunningFilter::wait()`] will return without blocking.
+    ///
+    /// The default implementation always returns false.
+    fn is_finished(&mut self) -> bool {
+        false
+    }
+
+    /// Get the result of the filter if it has finished, or get the running filter back if it
+    /// hasn't.
+    fn try_wait(mut self) -> Result<Self::Result, Self>
+    where
+        Self: Sized,
+    {
+        if self.is_finished() {
+            Ok(self.wait())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Call `f` once the filter has finished, so that [`RunningFilter::wait()`] won't block
+    /// (except briefly, while its threads exit). If the filter has already finished, `f` is called
+    /// right away; otherwise it's called exactly once, from one of the filter's threads, even if
+    /// the filter panics.
+    ///
+    /// This lets one thread supervise many filters, e.g. by having each callback send on a shared
+    /// channel. A thread left stuck after [`RunningFilter::abort()`] never finishes, so nor does
+    /// the filter.
+    ///
+    /// The default implementation, for filters which can't tell when they've finished, calls `f`
+    /// right away, so a supervisor goes straight on to [`RunningFilter::wait()`] and blocks there.
+    fn on_complete(&mut self, f: impl FnOnce() + Send + 'static)
+    where
+        Self: Sized,
+    {
+        f();
+    }
+
+    /// The name given to the filter when it was created, if any.
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Stop the filter early. After this, [`RunningFilter::wait()`] returns promptly, with an
+    /// [`Aborted`](crate::Aborted) error for any part of the filter that didn't finish on its own.
+    ///
+    /// Child processes are killed. Threads stop at the next buffer boundary, and any pipes still
+    /// held by the running filter are closed. A thread blocked on a stream outside of the filter's
+    /// control (such as a Rust stream that never returns) can't be interrupted, and is left to
+    /// finish in the background.
+    ///
+    /// The default implementation does nothing.
+    fn abort(&mut self) {}
+
     /// If the filter was started with [`ReadStream::PipeRequested`] as its input, this will return
     /// the write half of a pipe which can be used to write input to the filter.
-    fn input_pipe(&mut self) -> Option<OwnedFd>;
+    fn input_pipe(&mut self) -> Option<OwnedPipeEnd>;
 
     /// If the filter was started with [`WriteStream::PipeRequested`] as its output, this will
     /// return the read half of a pipe which can be used to read input from the filter.
-    fn output_pipe(&mut self) -> Option<OwnedFd>;
+    fn output_pipe(&mut self) -> Option<OwnedPipeEnd>;
+
+    /// Like [`RunningFilter::input_pipe()`], but returns the pipe as an [`InputPipe`], which
+    /// implements [`Write`].
+    fn input_writer(&mut self) -> Option<InputPipe> {
+        self.input_pipe().map(InputPipe::from)
+    }
+
+    /// Like [`RunningFilter::input_writer()`], but buffered. Dropping or closing the
+    /// [`BufferedInput`] flushes it and closes the pipe, so the filter gets EOF.
+    fn buffered_input_writer(&mut self) -> Option<BufferedInput> {
+        self.input_writer().map(BufferedInput::from)
+    }
+
+    /// Like [`RunningFilter::output_pipe()`], but returns the pipe as an [`OutputPipe`], which
+    /// implements [`Read`].
+    fn output_reader(&mut self) -> Option<OutputPipe> {
+        self.output_pipe().map(OutputPipe::from)
+    }
 }
@@ -0,0 +1,351 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::lambda::{LambdaThread, RunningLambda};
+use crate::misc::{copy_epipe_ok, name_error, Aborted};
+use crate::{Filter, ReadStream, WriteStream};
+
+/// An operation which changes a stream of data as it goes through, such as masking or
+/// substituting parts of it. Unlike a [`Lambda`](crate::Lambda), it decides what gets written
+/// out, so it can write more or less than it's given, and hold data back across buffers.
+pub trait Transform: Sized {
+    /// The result from calling [`Transform::finish()`] when the stream is done.
+    type FinishResult: Send;
+
+    /// Handle a buffer of input, writing whatever should come out for it to `out`.
+    fn transform(&mut self, input: &[u8], out: &mut dyn Write) -> io::Result<()>;
+
+    /// Called at the end of the input, to write anything still held back.
+    fn finish(self, out: &mut dyn Write) -> io::Result<Self::FinishResult>;
+}
+
+impl<F: FnMut(&[u8], &mut dyn Write) -> io::Result<()>> Transform for F {
+    type FinishResult = ();
+
+    fn transform(&mut self, input: &[u8], out: &mut dyn Write) -> io::Result<()> {
+        (self)(input, out)
+    }
+
+    fn finish(self, _out: &mut dyn Write) -> io::Result<Self::FinishResult> {
+        Ok(())
+    }
+}
+
+/// An operation which edits a stream of data in place, without changing its length, such as
+/// masking or translating bytes. It's simpler and cheaper than a [`Transform`]: each buffer is
+/// edited where it was read, then written out as it is.
+pub trait MutateLambda: Sized {
+    /// The result from calling [`MutateLambda::finish()`] when the stream is done.
+    type FinishResult: Send;
+
+    /// Edit a buffer of data, which is then passed on.
+    fn handle(&mut self, buf: &mut [u8]);
+
+    /// Called when the stream is finished.
+    fn finish(self) -> Self::FinishResult;
+}
+
+impl<F: FnMut(&mut [u8])> MutateLambda for F {
+    type FinishResult = ();
+
+    fn handle(&mut self, buf: &mut [u8]) {
+        (self)(buf)
+    }
+
+    fn finish(self) -> Self::FinishResult {}
+}
+
+/// An I/O filter which runs a [`Transform`] on each buffer in a background thread, and writes
+/// out whatever it produces. This is the in-process counterpart to running a command like `sed`
+/// with [`ChildProcess`](crate::ChildProcess).
+pub struct TransformFilter<T> {
+    transform: T,
+    name: Option<String>,
+    output_epipe_ok: bool,
+    cpu_affinity: Option<Vec<usize>>,
+    stack_size: Option<usize>,
+}
+
+impl<T: Transform> TransformFilter<T> {
+    /// Create a new instance from a given transform, or a closure which takes each buffer and
+    /// writes what should come out.
+    pub fn new(transform: T) -> Self {
+        Self {
+            transform,
+            name: None,
+            output_epipe_ok: false,
+            cpu_affinity: None,
+            stack_size: None,
+        }
+    }
+
+    /// Give the filter a name, which is included in errors and used to name its thread.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// If whatever reads the filter's output stops before the end (as `head` does), stop
+    /// transforming and finish normally instead of failing with
+    /// [`BrokenPipe`](io::ErrorKind::BrokenPipe). Anything [`Transform::finish()`] writes is then
+    /// thrown away. By default, the error is returned.
+    pub fn output_epipe_ok(mut self, ok: bool) -> Self {
+        self.output_epipe_ok = ok;
+        self
+    }
+
+    /// Run the filter's thread only on the given logical CPUs, like
+    /// [`LambdaFilter::cpu_affinity()`](crate::LambdaFilter::cpu_affinity).
+    pub fn cpu_affinity(mut self, cpus: &[usize]) -> Self {
+        self.cpu_affinity = Some(cpus.to_vec());
+        self
+    }
+
+    /// Give the filter's thread a stack of the given size, like
+    /// [`LambdaFilter::stack_size()`](crate::LambdaFilter::stack_size).
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Run the filter on the current thread, transforming `input` into `output` and returning the
+    /// result of [`Transform::finish()`]. Neither the streams nor the transform need to be
+    /// [`Send`].
+    pub fn run_blocking(self, input: impl Read, output: impl Write) -> io::Result<T::FinishResult> {
+        run(
+            self.transform,
+            input,
+            output,
+            self.output_epipe_ok,
+            Arc::new(AtomicBool::new(false)),
+        )
+        .map_err(|e| name_error(self.name.as_deref(), e))
+    }
+}
+
+impl<T: Transform + Send + 'static> Filter for TransformFilter<T> {
+    type Running = RunningLambda<T::FinishResult>;
+    type Error = io::Error;
+
+    fn start(self, input: ReadStream, output: WriteStream) -> Result<Self::Running, Self::Error> {
+        let transform = self.transform;
+        let epipe_ok = self.output_epipe_ok;
+        let thread = LambdaThread {
+            name: self.name,
+            kind: "transform",
+            cpu_affinity: self.cpu_affinity.as_deref(),
+            stack_size: self.stack_size,
+        };
+        RunningLambda::spawn(
+            thread,
+            None,
+            input,
+            output,
+            move |input, output, aborted| Ok(run(transform, input, output, epipe_ok, aborted)?),
+        )
+    }
+}
+
+/// Feed `input` through the transform into `output`, then finish it. If `epipe_ok` is set,
+/// `output` being closed early just ends the stream.
+fn run<T: Transform>(
+    transform: T,
+    mut input: impl Read,
+    output: impl Write,
+    epipe_ok: bool,
+    aborted: Arc<AtomicBool>,
+) -> io::Result<T::FinishResult> {
+    let mut shim = Shim {
+        transform,
+        next_write: output,
+        aborted,
+    };
+    let result = copy_epipe_ok(&mut input, &mut shim, epipe_ok, None);
+    if shim.aborted.load(Ordering::SeqCst) {
+        return Err(Aborted::ioerr());
+    }
+    let (_, cut_short) = result?;
+    if cut_short {
+        return shim.transform.finish(&mut io::sink());
+    }
+    let mut out = shim.next_write;
+    let result = shim.transform.finish(&mut out)?;
+    out.flush()?;
+    Ok(result)
+}
+
+/// Takes each buffer copied from the input, and has the transform write to the real output.
+struct Shim<T, W> {
+    transform: T,
+    next_write: W,
+    aborted: Arc<AtomicBool>,
+}
+
+impl<T: Transform, W: Write> Write for Shim<T, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.aborted.load(Ordering::SeqCst) {
+            return Err(Aborted::ioerr());
+        }
+        self.transform.transform(buf, &mut self.next_write)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.next_write.flush()
+    }
+}
+
+/// An I/O filter which reads into a buffer of its own, lets a [`MutateLambda`] edit it, and writes
+/// the whole of it out, all in a background thread.
+pub struct MutateFilter<M> {
+    handler: M,
+    name: Option<String>,
+    output_epipe_ok: bool,
+    buffer_size: Option<usize>,
+    cpu_affinity: Option<Vec<usize>>,
+    stack_size: Option<usize>,
+}
+
+impl<M: MutateLambda> MutateFilter<M> {
+    /// Create a new instance from a given lambda, or a closure which edits each buffer.
+    pub fn new(handler: M) -> Self {
+        Self {
+            handler,
+            name: None,
+            output_epipe_ok: false,
+            buffer_size: None,
+            cpu_affinity: None,
+            stack_size: None,
+        }
+    }
+
+    /// Give the filter a name, which is included in errors and used to name its thread.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// If whatever reads the filter's output stops before the end (as `head` does), stop and
+    /// finish normally instead of failing with [`BrokenPipe`](io::ErrorKind::BrokenPipe). By
+    /// default, the error is returned.
+    pub fn output_epipe_ok(mut self, ok: bool) -> Self {
+        self.output_epipe_ok = ok;
+        self
+    }
+
+    /// Read the input into a buffer of the given size, instead of the default of 8 KiB. The
+    /// lambda is still given less whenever a read comes up short.
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = Some(bytes);
+        self
+    }
+
+    /// Run the filter's thread only on the given logical CPUs, like
+    /// [`LambdaFilter::cpu_affinity()`](crate::LambdaFilter::cpu_affinity).
+    pub fn cpu_affinity(mut self, cpus: &[usize]) -> Self {
+        self.cpu_affinity = Some(cpus.to_vec());
+        self
+    }
+
+    /// Give the filter's thread a stack of the given size, like
+    /// [`LambdaFilter::stack_size()`](crate::LambdaFilter::stack_size).
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Run the filter on the current thread, copying `input` to `output` through the lambda and
+    /// returning the result of [`MutateLambda::finish()`]. Neither the streams nor the lambda need
+    /// to be [`Send`].
+    pub fn run_blocking(self, input: impl Read, output: impl Write) -> io::Result<M::FinishResult> {
+        run_mutate(
+            self.handler,
+            input,
+            output,
+            self.output_epipe_ok,
+            self.buffer_size,
+            &AtomicBool::new(false),
+        )
+        .map_err(|e| name_error(self.name.as_deref(), e))
+    }
+}
+
+impl<M: MutateLambda + Send + 'static> Filter for MutateFilter<M> {
+    type Running = RunningLambda<M::FinishResult>;
+    type Error = io::Error;
+
+    fn start(self, input: ReadStream, output: WriteStream) -> Result<Self::Running, Self::Error> {
+        let handler = self.handler;
+        let epipe_ok = self.output_epipe_ok;
+        let buffer_size = self.buffer_size;
+        let thread = LambdaThread {
+            name: self.name,
+            kind: "mutate",
+            cpu_affinity: self.cpu_affinity.as_deref(),
+            stack_size: self.stack_size,
+        };
+        RunningLambda::spawn(
+            thread,
+            None,
+            input,
+            output,
+            move |input, output, aborted| {
+                Ok(run_mutate(
+                    handler,
+                    input,
+                    output,
+                    epipe_ok,
+                    buffer_size,
+                    &aborted,
+                )?)
+            },
+        )
+    }
+}
+
+/// Read `input` a buffer at a time, have the lambda edit each one, and write it to `output`, then
+/// finish the lambda. If `epipe_ok` is set, `output` being closed early just ends the stream.
+fn run_mutate<M: MutateLambda>(
+    mut handler: M,
+    mut input: impl Read,
+    mut output: impl Write,
+    epipe_ok: bool,
+    buffer_size: Option<usize>,
+    aborted: &AtomicBool,
+) -> io::Result<M::FinishResult> {
+    let result = mutate_loop(&mut handler, &mut input, &mut output, buffer_size, aborted);
+    if aborted.load(Ordering::SeqCst) {
+        return Err(Aborted::ioerr());
+    }
+    match result {
+        Ok(()) => output.flush()?,
+        Err(e) if epipe_ok && e.kind() == io::ErrorKind::BrokenPipe => (),
+        Err(e) => return Err(e),
+    }
+    Ok(handler.finish())
+}
+
+fn mutate_loop(
+    handler: &mut impl MutateLambda,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    buffer_size: Option<usize>,
+    aborted: &AtomicBool,
+) -> io::Result<()> {
+    // The same default size as io::copy uses.
+    let mut buf = vec![0; buffer_size.unwrap_or(8 * 1024).max(1)];
+    loop {
+        if aborted.load(Ordering::SeqCst) {
+            return Err(Aborted::ioerr());
+        }
+        let n = match input.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        handler.handle(&mut buf[..n]);
+        output.write_all(&buf[..n])?;
+    }
+}
@@ -0,0 +1,179 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::process::Command;
+
+use crate::ChildProcess;
+
+impl ChildProcess {
+    /// Create a [`ChildProcess`] from a command line, split into words the way a POSIX shell
+    /// would: words are separated by whitespace, and single quotes, double quotes, and backslashes
+    /// work as usual. Nothing is expanded, so `$HOME`, `*` and `~` are passed along as they are.
+    pub fn parse(cmdline: &str) -> Result<Self, ParseError> {
+        let mut words = split_words(cmdline)?.into_iter();
+        let Some(program) = words.next() else {
+            return Err(ParseError {
+                kind: ParseErrorKind::Empty,
+                position: 0,
+            });
+        };
+        let mut cmd = Command::new(program);
+        cmd.args(words);
+        Ok(ChildProcess::new(cmd))
+    }
+}
+
+/// Shows the command line quoted so that pasting it into a shell runs the same thing, starting with
+/// any environment variables set or removed on the [`Command`]. It doesn't show the working
+/// directory, or how the child's streams are connected.
+impl Display for ChildProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cmd = self.command_ref();
+        let envs = cmd.get_envs().collect::<Vec<_>>();
+        let mut removed = envs.iter().filter(|(_, v)| v.is_none()).peekable();
+        if removed.peek().is_some() {
+            f.write_str("env")?;
+            for (key, _) in removed {
+                write!(f, " -u {}", quote(key))?;
+            }
+            f.write_str(" ")?;
+        }
+        for (key, value) in &envs {
+            if let Some(value) = value {
+                write!(f, "{}={} ", key.to_string_lossy(), quote(value))?;
+            }
+        }
+        f.write_str(&quote(cmd.get_program()))?;
+        for arg in cmd.get_args() {
+            write!(f, " {}", quote(arg))?;
+        }
+        Ok(())
+    }
+}
+
+/// Quote a word for a POSIX shell, if it needs it.
+fn quote(word: &OsStr) -> Cow<'_, str> {
+    let word = word.to_string_lossy();
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', "'\\''")))
+    }
+}
+
+fn split_words(cmdline: &str) -> Result<Vec<String>, ParseError> {
+    let mut words = vec![];
+    let mut word = String::new();
+    // Whether there's a word in progress, which might be empty if it was just a pair of quotes.
+    let mut in_word = false;
+    let mut chars = cmdline.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\\' => match chars.next() {
+                Some((_, '\n')) => (),
+                Some((_, c)) => {
+                    word.push(c);
+                    in_word = true;
+                }
+                None => {
+                    return Err(ParseError {
+                        kind: ParseErrorKind::TrailingBackslash,
+                        position: i,
+                    })
+                }
+            },
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) => break,
+                        Some((_, c)) => word.push(c),
+                        None => {
+                            return Err(ParseError {
+                                kind: ParseErrorKind::UnterminatedSingleQuote,
+                                position: i,
+                            })
+                        }
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.peek() {
+                            Some((_, '\n')) => {
+                                chars.next();
+                            }
+                            Some(&(_, c @ ('$' | '`' | '"' | '\\'))) => {
+                                chars.next();
+                                word.push(c);
+                            }
+                            _ => word.push('\\'),
+                        },
+                        Some((_, c)) => word.push(c),
+                        None => {
+                            return Err(ParseError {
+                                kind: ParseErrorKind::UnterminatedDoubleQuote,
+                                position: i,
+                            })
+                        }
+                    }
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// An error from [`ChildProcess::parse()`].
+#[derive(Debug)]
+pub struct ParseError {
+    /// What was wrong with the command line.
+    pub kind: ParseErrorKind,
+    /// The byte offset in the command line where the problem starts.
+    pub position: usize,
+}
+
+/// What was wrong with a command line given to [`ChildProcess::parse()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// There were no words at all.
+    Empty,
+    /// A single quote was never closed.
+    UnterminatedSingleQuote,
+    /// A double quote was never closed.
+    UnterminatedDoubleQuote,
+    /// The command line ended with a backslash, with nothing for it to escape.
+    TrailingBackslash,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = match self.kind {
+            ParseErrorKind::Empty => "empty command line",
+            ParseErrorKind::UnterminatedSingleQuote => "unterminated single quote",
+            ParseErrorKind::UnterminatedDoubleQuote => "unterminated double quote",
+            ParseErrorKind::TrailingBackslash => "trailing backslash",
+        };
+        write!(f, "{what} at position {}", self.position)
+    }
+}
+
+impl Error for ParseError {}
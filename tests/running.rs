@@ -0,0 +1,2340 @@
+use std::ops::ControlFlow;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use io_chain::{
+    Aborted, ChildProcess, CountingFilter, Filter, InvalidUtf8, Lambda, LambdaFilter, LineLambda,
+    LongLines, MutateFilter, MutateLambda, ReadStream, RunningFilter, SampleCounts, Sampling,
+    ShutdownOutcome, StreamEnd, Tee, Transform, TransformFilter, TryLambda, Utf8Lambda,
+    WriteStream,
+};
+
+#[test]
+fn abort_stuck_chain() {
+    let mut lambda = LambdaFilter::new(|_: &[u8]| ())
+        .start(ReadStream::Zeros, WriteStream::PipeRequested)
+        .unwrap();
+    // Never reads its input, so the lambda gets stuck writing.
+    let mut sleep = Command::new("sleep");
+    sleep.arg("1000");
+    let mut sleep = ChildProcess::new(sleep)
+        .start(lambda.output_reader().unwrap().into(), WriteStream::Null)
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!lambda.is_finished());
+
+    let start = Instant::now();
+    lambda.abort();
+    sleep.abort();
+    let err = lambda.wait().unwrap_err();
+    assert!(Aborted::is(&err.error), "{err}");
+    assert!(!sleep.wait().child.unwrap().success());
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn abort_tee_with_stuck_output() {
+    let (stuck_rx, stuck_tx) = os_pipe::pipe().unwrap();
+    let mut tee = Tee::new(4096);
+    tee.add_output(stuck_tx);
+    let (output, _count) = WriteStream::counting();
+    let mut tee = tee.start(ReadStream::Zeros, output).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!tee.is_finished());
+
+    tee.abort();
+    let results = tee.wait();
+    assert!(results.iter().any(|r| r.as_ref().is_err_and(Aborted::is)));
+    drop(stuck_rx);
+}
+
+#[test]
+fn boxed_filters_in_a_vec() {
+    use io_chain::BoxedFilter;
+
+    let filters = vec![
+        BoxedFilter::new(ChildProcess::new(Command::new("cat"))),
+        BoxedFilter::new(LambdaFilter::new(|_: &[u8]| ())),
+        BoxedFilter::new(Tee::new(1024)),
+        BoxedFilter::new(ChildProcess::new(Command::new("cat"))),
+    ];
+    let (output, captured) = WriteStream::capture();
+    let mut input = Some(ReadStream::from("boxed\n"));
+    let mut output = Some(output);
+    let last = filters.len() - 1;
+    let mut running = vec![];
+    for (i, filter) in filters.into_iter().enumerate() {
+        let out = if i == last {
+            output.take().unwrap()
+        } else {
+            WriteStream::PipeRequested
+        };
+        let mut r = filter.start(input.take().unwrap(), out).unwrap();
+        input = r.output_reader().map(Into::into);
+        running.push(r);
+    }
+    for r in running {
+        r.wait().unwrap();
+    }
+    assert_eq!(captured.into_bytes(), b"boxed\n");
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("exit 3");
+    let err = BoxedFilter::new(ChildProcess::new(cmd))
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait()
+        .unwrap_err();
+    assert!(err.to_string().contains("exit status: 3"), "{err}");
+}
+
+#[test]
+fn then_composition() {
+    let mut tr = Command::new("tr");
+    tr.arg("a-z").arg("A-Z");
+    let chain = ChildProcess::new(Command::new("cat"))
+        .then(LambdaFilter::new(|_: &[u8]| ()))
+        .then(ChildProcess::new(tr));
+    let (output, captured) = WriteStream::capture();
+    let ((cat, lambda), tr) = chain
+        .start(ReadStream::from("then\n"), output)
+        .unwrap()
+        .wait();
+    cat.combine().unwrap();
+    lambda.unwrap();
+    tr.combine().unwrap();
+    assert_eq!(captured.into_bytes(), b"THEN\n");
+
+    // The second filter fails to start, so the first is cleaned up.
+    let chain = ChildProcess::new(Command::new("cat")).then(ChildProcess::new(Command::new(
+        "/nonexistent/io-chain-test",
+    )));
+    match chain.start(ReadStream::PipeRequested, WriteStream::Null) {
+        Err(io_chain::ThenError::Second(_)) => (),
+        Err(e) => panic!("wrong error: {e}"),
+        Ok(_) => panic!("start should fail"),
+    }
+}
+
+#[test]
+fn unified_errors() {
+    use io_chain::IoChainError;
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("exit 2");
+    let err = ChildProcess::new(cmd)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait()
+        .into_result()
+        .unwrap_err();
+    match err {
+        IoChainError::ChildFailed { status, .. } => assert_eq!(status.code(), Some(2)),
+        other => panic!("wrong error: {other}"),
+    }
+
+    let results = vec![
+        Ok(()),
+        Err(std::io::Error::other("a")),
+        Err(std::io::Error::other("b")),
+    ];
+    match IoChainError::collect(results) {
+        Err(IoChainError::Multiple(errors)) => assert_eq!(errors.len(), 2),
+        other => panic!("wrong result: {other:?}"),
+    }
+    assert!(IoChainError::collect(vec![Ok(())]).is_ok());
+}
+
+#[test]
+fn named_filters() {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("exit 1");
+    let child = ChildProcess::new(cmd)
+        .name("failing")
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    assert_eq!(child.name(), Some("failing"));
+    let err = child.wait().combine().unwrap_err();
+    assert!(
+        err.to_string().starts_with("filter 'failing': 'sh' exited"),
+        "{err}"
+    );
+
+    let thread_name = std::sync::Arc::new(parking_lot::Mutex::new(None));
+    let seen = std::sync::Arc::clone(&thread_name);
+    let lambda = LambdaFilter::new(move |_: &[u8]| {
+        *seen.lock() = std::thread::current().name().map(str::to_owned);
+    })
+    .name("counter")
+    .start(ReadStream::from("x"), WriteStream::Null)
+    .unwrap();
+    lambda.wait().unwrap();
+    assert_eq!(thread_name.lock().as_deref(), Some("counter"));
+}
+
+#[test]
+fn scoped_filters_borrow_locals() {
+    let input = b"borrowed input\n".to_vec();
+    let mut count = 0;
+    let mut output = vec![];
+    io_chain::scope(|s| {
+        let lambda = LambdaFilter::new(|buf: &[u8]| count += buf.len());
+        let mut lambda = s
+            .start(
+                lambda,
+                s.reader(&input[..]).unwrap(),
+                WriteStream::PipeRequested,
+            )
+            .unwrap();
+        let cat = ChildProcess::new(Command::new("cat"))
+            .start(
+                lambda.output_reader().unwrap().into(),
+                s.writer(&mut output).unwrap(),
+            )
+            .unwrap();
+        lambda.wait().unwrap();
+        cat.wait().combine().unwrap();
+    })
+    .unwrap();
+    assert_eq!(count, input.len());
+    assert_eq!(output, input);
+}
+
+#[test]
+fn completion_notifications() {
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let mut sleep = Command::new("sleep");
+    sleep.arg("0.2");
+    let mut child = ChildProcess::new(sleep)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let mut lambda = LambdaFilter::new(|_: &[u8]| ())
+        .start(ReadStream::from("x"), WriteStream::Null)
+        .unwrap();
+    let mut tee = Tee::new(1024)
+        .start(ReadStream::from("y"), WriteStream::Null)
+        .unwrap();
+    let notify = |id: usize| {
+        let tx = tx.clone();
+        move || tx.send(id).unwrap()
+    };
+    child.on_complete(notify(0));
+    lambda.on_complete(notify(1));
+    tee.on_complete(notify(2));
+    drop(tx);
+
+    let mut done: Vec<usize> = rx.iter().collect();
+    done.sort();
+    assert_eq!(done, [0, 1, 2]);
+    assert!(child.is_finished());
+    child.wait().combine().unwrap();
+    lambda.wait().unwrap();
+    assert!(tee.wait().into_iter().all(|r| r.is_ok()));
+}
+
+#[test]
+fn duplex_child_half_close() {
+    use std::io::{Read, Write};
+
+    let mut cat = io_chain::DuplexChild::new(Command::new("cat"))
+        .start()
+        .unwrap();
+    let mut socket = cat.socket().unwrap();
+    socket.write_all(b"both ways\n").unwrap();
+    socket.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut output = String::new();
+    socket.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "both ways\n");
+    cat.wait().combine().unwrap();
+}
+
+#[test]
+fn run_blocking_without_send() {
+    use std::cell::Cell;
+    use std::io::Read;
+    use std::rc::Rc;
+
+    let count = Rc::new(Cell::new(0));
+    let counter = Rc::clone(&count);
+    let mut output = vec![];
+    LambdaFilter::new(move |buf: &[u8]| counter.set(counter.get() + buf.len()))
+        .run_blocking(&b"on this thread"[..], &mut output)
+        .unwrap();
+    assert_eq!(count.get(), 14);
+    assert_eq!(output, b"on this thread");
+
+    let (mut other_rx, other_tx) = os_pipe::pipe().unwrap();
+    let mut tee = Tee::new(4);
+    tee.add_output(other_tx);
+    let mut output = vec![];
+    let results = tee.run_blocking(&b"teed on this thread"[..], &mut output);
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|r| r.is_ok()));
+    assert_eq!(output, b"teed on this thread");
+    let mut other = vec![];
+    other_rx.read_to_end(&mut other).unwrap();
+    assert_eq!(other, b"teed on this thread");
+}
+
+#[test]
+fn wait_ok_for_any_filter() {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("exit 3");
+    let child = ChildProcess::new(cmd)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    assert!(matches!(
+        child.wait_ok(),
+        Err(io_chain::IoChainError::ChildFailed { .. })
+    ));
+
+    let lambda = LambdaFilter::new(|_: &[u8]| ())
+        .start(ReadStream::from("ok"), WriteStream::Null)
+        .unwrap();
+    lambda.wait_ok().unwrap();
+
+    let tee = Tee::new(16)
+        .start(ReadStream::from("ok"), WriteStream::Null)
+        .unwrap();
+    tee.wait_ok().unwrap();
+}
+
+#[test]
+fn kill_and_terminate_child() {
+    use std::os::unix::process::ExitStatusExt;
+
+    // Never reads its output, so cat gets stuck writing, and so does the copy thread feeding it.
+    let (stuck_rx, stuck_tx) = os_pipe::pipe().unwrap();
+    let mut cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::Zeros, WriteStream::from(stuck_tx))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    cat.kill().unwrap();
+    cat.kill().unwrap();
+    let exit = cat.wait();
+    assert_eq!(exit.child.unwrap().signal(), Some(libc::SIGKILL));
+    assert!(exit.read_thread.unwrap().is_err());
+    drop(stuck_rx);
+
+    let mut sleep = Command::new("sleep");
+    sleep.arg("1000");
+    let mut sleep = ChildProcess::new(sleep)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    sleep.terminate().unwrap();
+    let status = sleep.wait().child.unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+}
+
+#[test]
+fn signal_child() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut sleep = Command::new("sleep");
+    sleep.arg("1000");
+    let mut sleep = ChildProcess::new(sleep)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    sleep.signal(libc::SIGHUP).unwrap();
+    while !sleep.is_finished() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let err = sleep.signal(libc::SIGHUP).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+    assert_eq!(sleep.wait().child.unwrap().signal(), Some(libc::SIGHUP));
+}
+
+#[test]
+fn child_pid_and_into_child() {
+    let cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    let pid = cat.pid();
+    let (mut child, threads) = cat.into_child();
+    assert_eq!(child.id(), pid);
+    assert!(threads.iter().all(Option::is_none));
+    drop(child.stdin.take());
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn wait_timeout_child() {
+    let mut sleep = Command::new("sleep");
+    sleep.arg("10");
+    let sleep = ChildProcess::new(sleep)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let start = Instant::now();
+    let mut sleep = match sleep.wait_timeout(Duration::from_millis(100)) {
+        Ok(_) => panic!("sleep finished early"),
+        Err(running) => running,
+    };
+    assert!(start.elapsed() < Duration::from_secs(5));
+    sleep.kill().unwrap();
+    let exit = sleep.wait_timeout(Duration::from_secs(5)).ok().unwrap();
+    assert!(!exit.child.unwrap().success());
+
+    let echo = ChildProcess::new(Command::new("true"))
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    echo.wait_timeout(Duration::from_secs(5))
+        .ok()
+        .unwrap()
+        .combine()
+        .unwrap();
+}
+
+#[test]
+fn kill_on_drop_child() {
+    let mut sleep = Command::new("sleep");
+    sleep.arg("1000");
+    let sleep = ChildProcess::new(sleep)
+        .kill_on_drop(true)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let pid = sleep.pid() as libc::pid_t;
+    drop(sleep);
+    // Once it's been killed and reaped, the PID is gone.
+    let start = Instant::now();
+    while unsafe { libc::kill(pid, 0) } == 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // Waiting doesn't kill it.
+    ChildProcess::new(Command::new("true"))
+        .kill_on_drop(true)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+}
+
+#[test]
+fn kill_process_group() {
+    use std::io::{BufRead, BufReader, Read};
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("sleep 1000 & echo started; wait");
+    let mut sh = ChildProcess::new(cmd)
+        .new_process_group(true)
+        .start(ReadStream::Null, WriteStream::PipeRequested)
+        .unwrap();
+    let mut output = BufReader::new(sh.output_reader().unwrap());
+    let mut line = String::new();
+    output.read_line(&mut line).unwrap();
+    assert_eq!(line, "started\n");
+
+    sh.kill_group().unwrap();
+    // The grandchild sleep holds the pipe open too, so this only finishes if it was killed.
+    output.read_to_end(&mut vec![]).unwrap();
+    assert!(!sh.wait().child.unwrap().success());
+
+    let cat = ChildProcess::new(Command::new("true"))
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    assert_eq!(
+        cat.kill_group().unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+    cat.wait().combine().unwrap();
+}
+
+#[test]
+fn child_exit_accessors() {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("exit 5");
+    let exit = ChildProcess::new(cmd)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait();
+    assert!(!exit.success());
+    assert_eq!(exit.exit_code(), Some(5));
+    assert_eq!(exit.signal(), None);
+    assert!(exit.thread_error().is_none());
+    assert!(exit.combine().is_err());
+
+    let exit = ChildProcess::new(Command::new("true"))
+        .start(
+            ReadStream::from_chunks([Err(std::io::Error::other("bad input"))]),
+            WriteStream::Null,
+        )
+        .unwrap()
+        .wait();
+    assert_eq!(exit.exit_code(), Some(0));
+    assert!(!exit.success());
+    assert_eq!(exit.thread_error().unwrap().to_string(), "bad input");
+}
+
+#[test]
+fn combine_allowing_sigpipe() {
+    let yes = || {
+        let (tx, rx) = std::sync::mpsc::sync_channel(0);
+        drop(rx);
+        ChildProcess::new(Command::new("yes"))
+            .start(ReadStream::Null, WriteStream::Channel(tx))
+            .unwrap()
+            .wait()
+    };
+
+    let exit = yes();
+    assert_eq!(exit.signal(), Some(libc::SIGPIPE));
+    assert!(exit.combine().is_err());
+
+    // The copy thread's EPIPE is accepted along with the signal.
+    let exit = yes();
+    assert_eq!(
+        exit.write_thread
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::BrokenPipe
+    );
+    exit.combine_allow_sigpipe().unwrap();
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("kill -HUP $$");
+    let exit = ChildProcess::new(cmd)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait();
+    exit.combine_with(&io_chain::ExitPolicy::new().allow_signal(libc::SIGHUP))
+        .unwrap();
+}
+
+#[test]
+fn combine_allowing_codes() {
+    let grep = |input: &'static str| {
+        let mut cmd = Command::new("grep");
+        cmd.arg("needle");
+        ChildProcess::new(cmd)
+            .start(ReadStream::from(input), WriteStream::Null)
+            .unwrap()
+            .wait()
+    };
+
+    assert!(grep("hay\n").combine().is_err());
+    grep("hay\n").combine_allow_codes(&[1]).unwrap();
+    assert!(grep("hay\n").combine_allow_codes(&[2]).is_err());
+    assert_eq!(grep("hay\n").combine_with_code().unwrap(), 1);
+    assert_eq!(grep("needle\n").combine_with_code().unwrap(), 0);
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("kill -KILL $$");
+    let killed = ChildProcess::new(cmd)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait();
+    assert!(killed.combine_with_code().is_err());
+}
+
+#[test]
+fn child_exit_error_kinds() {
+    use io_chain::ChildExitErrorKind;
+    use std::error::Error;
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("exit 1");
+    let err = ChildProcess::new(cmd)
+        .start(
+            ReadStream::from_chunks([Err(std::io::Error::other("bad input"))]),
+            WriteStream::Null,
+        )
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap_err();
+    assert!(matches!(err.kind(), ChildExitErrorKind::ChildExit(_)));
+    assert!(err.source().is_none());
+    assert_eq!(err.iter().count(), 2);
+    let next = err.next.as_ref().unwrap();
+    assert_eq!(next.source().unwrap().to_string(), "bad input");
+    let kinds = err.into_kinds();
+    assert!(matches!(kinds[1], ChildExitErrorKind::ReadThread(_)));
+
+    // A waiter thread that couldn't be started doesn't hide how the child exited.
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("exit 3");
+    let mut exit = ChildProcess::new(cmd)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait();
+    exit.waiter = Some(std::io::Error::other("no threads"));
+    assert_eq!(exit.exit_code(), Some(3));
+    let kinds = exit.combine().unwrap_err().into_kinds();
+    assert!(matches!(kinds[0], ChildExitErrorKind::ChildExit(_)));
+    assert!(matches!(&kinds[1], ChildExitErrorKind::Waiter(e) if e.to_string() == "no threads"));
+}
+
+#[test]
+fn shutdown_escalation() {
+    // cat exits by itself once the endless input is cut off.
+    let cat = ChildProcess::new(Command::new("cat"))
+        .start(
+            ReadStream::Rust(Box::new(std::io::repeat(b'y'))),
+            WriteStream::Null,
+        )
+        .unwrap();
+    let exit = cat.shutdown(Duration::from_secs(5), Duration::from_secs(5));
+    assert_eq!(exit.shutdown, Some(ShutdownOutcome::Exited));
+    exit.combine().unwrap();
+
+    let mut sleep = Command::new("sleep");
+    sleep.arg("1000");
+    let sleep = ChildProcess::new(sleep)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let exit = sleep.shutdown(Duration::from_millis(100), Duration::from_secs(5));
+    assert_eq!(exit.shutdown, Some(ShutdownOutcome::Terminated));
+    assert_eq!(exit.signal(), Some(libc::SIGTERM));
+
+    let sh = ChildProcess::shell("trap '' TERM; while :; do sleep 1; done")
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let exit = sh.shutdown(Duration::from_millis(100), Duration::from_millis(100));
+    assert_eq!(exit.shutdown, Some(ShutdownOutcome::Killed));
+    assert_eq!(exit.signal(), Some(libc::SIGKILL));
+}
+
+#[test]
+fn input_epipe_ok() {
+    let head = |epipe_ok: bool| {
+        let (output, captured) = WriteStream::capture();
+        let exit = ChildProcess::command("head", ["-c", "10"])
+            .input_epipe_ok(epipe_ok)
+            .start(ReadStream::Rust(Box::new(std::io::repeat(b'y'))), output)
+            .unwrap()
+            .wait();
+        assert_eq!(captured.into_bytes(), b"yyyyyyyyyy");
+        exit
+    };
+
+    let exit = head(false);
+    assert_eq!(
+        exit.thread_error().unwrap().kind(),
+        std::io::ErrorKind::BrokenPipe
+    );
+    assert!(!exit.input_stopped_early);
+
+    let exit = head(true);
+    assert!(exit.input_stopped_early);
+    assert!(exit.read_bytes.unwrap() >= 10);
+    exit.combine().unwrap();
+
+    let lambda = |epipe_ok: bool| {
+        let (tx, rx) = std::sync::mpsc::sync_channel(0);
+        drop(rx);
+        LambdaFilter::new(|_: &[u8]| ())
+            .output_epipe_ok(epipe_ok)
+            .start(ReadStream::Zeros, WriteStream::Channel(tx))
+            .unwrap()
+            .wait()
+    };
+    assert_eq!(
+        lambda(false).unwrap_err().error.kind(),
+        std::io::ErrorKind::BrokenPipe
+    );
+    lambda(true).unwrap();
+}
+
+#[test]
+fn respawn_child() {
+    use io_chain::RespawnPolicy;
+
+    // Each child handles one line and then fails, until the input runs out.
+    let policy = RespawnPolicy::new().backoff(Duration::from_millis(1), Duration::from_millis(10));
+    let (output, captured) = WriteStream::capture();
+    let exit = ChildProcess::shell("read line || exit 0; echo \"got $line\"; exit 1")
+        .respawn(policy.clone())
+        .start(ReadStream::from("a\nb\nc\n"), output)
+        .unwrap()
+        .wait();
+    assert_eq!(exit.restarts, 3);
+    exit.combine().unwrap();
+    assert_eq!(captured.into_bytes(), b"got a\ngot b\ngot c\n");
+
+    let exit = ChildProcess::shell("exit 3")
+        .respawn(policy.max_restarts(2))
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait();
+    assert_eq!(exit.restarts, 2);
+    assert_eq!(exit.exit.exit_code(), Some(3));
+
+    let mut sleep = ChildProcess::shell("sleep 1000")
+        .respawn(RespawnPolicy::new())
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    assert!(sleep.pid().is_some());
+    sleep.abort();
+    let exit = sleep.wait();
+    assert_eq!(exit.restarts, 0);
+    assert_eq!(exit.exit.signal(), Some(libc::SIGKILL));
+}
+
+#[test]
+fn resource_limits() {
+    use io_chain::Resource;
+
+    let path = std::env::temp_dir().join(format!("io-chain-test-limit-{}", std::process::id()));
+    let exit = ChildProcess::command("head", ["-c", "100000", "/dev/zero"])
+        .limit(Resource::FileSize, 1000, Resource::UNLIMITED)
+        .start(
+            ReadStream::Null,
+            WriteStream::File {
+                path: path.clone(),
+                options: io_chain::FileOpts::new().create(true).truncate(true),
+            },
+        )
+        .unwrap()
+        .wait();
+    assert_eq!(exit.signal(), Some(libc::SIGXFSZ));
+    assert_eq!(exit.limit_exceeded(), Some(Resource::FileSize));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 1000);
+    std::fs::remove_file(&path).unwrap();
+
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell("ulimit -n")
+        .limit(Resource::OpenFiles, 50, 50)
+        .start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"50\n");
+
+    let err = match ChildProcess::new(Command::new("true"))
+        .limit(Resource::CoreSize, 2, 1)
+        .start(ReadStream::Null, WriteStream::Null)
+    {
+        Ok(_) => panic!("start should fail"),
+        Err(e) => e,
+    };
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn child_priority() {
+    use io_chain::IoPriority;
+
+    let (output, captured) = WriteStream::capture();
+    let exit = ChildProcess::shell("nice; ionice -p $$; cat")
+        .nice(19)
+        .io_priority(IoPriority::Idle)
+        .copy_thread_nice(19)
+        .start(
+            // Evaluated on the copy thread, once it has set its niceness.
+            ReadStream::from_chunks(std::iter::once_with(|| {
+                // SAFETY: FFI calls with no pointers.
+                let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, libc::gettid() as _) };
+                Ok(format!("{nice}\n").into_bytes())
+            })),
+            output,
+        )
+        .unwrap()
+        .wait();
+    exit.combine().unwrap();
+    assert_eq!(captured.into_bytes(), b"19\nidle\n19\n");
+}
+
+#[test]
+fn child_credentials() {
+    // SAFETY: FFI call with no pointers.
+    if unsafe { libc::getuid() } != 0 {
+        // Switching to another user needs root, so just check that failing stops the child.
+        let err = match ChildProcess::new(Command::new("true"))
+            .uid(0)
+            .start(ReadStream::Null, WriteStream::Null)
+        {
+            Ok(_) => panic!("start should fail"),
+            Err(e) => e,
+        };
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        return;
+    }
+
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell("id -u; id -g; id -G")
+        .uid(65534)
+        .gid(65533)
+        .groups(&[65532])
+        .start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"65534\n65533\n65533 65532\n");
+
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell("id -G")
+        .uid(65534)
+        .gid(65534)
+        .start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"65534\n");
+}
+
+#[test]
+fn child_on_pty() {
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell("[ -t 0 ] && [ -t 1 ] && [ -t 2 ] && stty size && cat")
+        .pty(true)
+        .pty_size(24, 80)
+        .start(ReadStream::from("hello\n"), output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"24 80\nhello\n");
+
+    // The master end is returned as the input pipe, and the output pipe ends cleanly.
+    let mut child = ChildProcess::shell("read line; echo \"got $line\"; stty size")
+        .pty(true)
+        .start(ReadStream::PipeRequested, WriteStream::PipeRequested)
+        .unwrap();
+    let mut input = child.input_writer().unwrap();
+    let mut output = child.output_reader().unwrap();
+    child.set_window_size(30, 100).unwrap();
+    std::io::Write::write_all(&mut input, b"hi\n").unwrap();
+    let mut out = String::new();
+    std::io::Read::read_to_string(&mut output, &mut out).unwrap();
+    assert_eq!(out, "got hi\n30 100\n");
+    child.wait().combine().unwrap();
+
+    let cat = ChildProcess::new(Command::new("cat"))
+        .pty(true)
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    cat.send_eof().unwrap();
+    cat.wait().combine().unwrap();
+
+    let mut cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    assert!(cat.send_eof().is_err());
+    drop(cat.input_pipe());
+    cat.wait().combine().unwrap();
+}
+
+#[test]
+fn pass_extra_fds() {
+    let path = std::env::temp_dir().join(format!("io-chain-test-passfd-{}", std::process::id()));
+    std::fs::write(&path, "from a file\n").unwrap();
+    let mut child = ChildProcess::shell("cat <&4 >&3; echo status >&5")
+        .pass_fd(3, WriteStream::PipeRequested)
+        .pass_fd(4, ReadStream::File(path.clone()))
+        .pass_fd(5, WriteStream::PipeRequested)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    assert!(child.extra_pipe(4).is_none());
+    let mut fd3 = io_chain::OutputPipe::from(child.extra_pipe(3).unwrap());
+    let mut fd5 = io_chain::OutputPipe::from(child.extra_pipe(5).unwrap());
+    let mut out = String::new();
+    std::io::Read::read_to_string(&mut fd3, &mut out).unwrap();
+    assert_eq!(out, "from a file\n");
+    out.clear();
+    std::io::Read::read_to_string(&mut fd5, &mut out).unwrap();
+    assert_eq!(out, "status\n");
+    child.wait().combine().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let start = |child: ChildProcess| match child.start(ReadStream::Null, WriteStream::Null) {
+        Ok(_) => panic!("start should fail"),
+        Err(e) => e.kind(),
+    };
+    let invalid = std::io::ErrorKind::InvalidInput;
+    assert_eq!(
+        start(ChildProcess::new(Command::new("true")).pass_fd(1, WriteStream::Null)),
+        invalid
+    );
+    assert_eq!(
+        start(
+            ChildProcess::new(Command::new("true"))
+                .pass_fd(3, WriteStream::Null)
+                .pass_fd(3, ReadStream::Null)
+        ),
+        invalid
+    );
+    assert_eq!(
+        start(ChildProcess::new(Command::new("true")).pass_fd(3, ReadStream::Zeros)),
+        invalid
+    );
+}
+
+#[test]
+fn tagged_child_output() {
+    use io_chain::OutputSource;
+    use std::sync::{Arc, Mutex};
+
+    let chunks = Arc::new(Mutex::new(vec![]));
+    let chunks2 = Arc::clone(&chunks);
+    ChildProcess::shell("echo out; sleep 0.2; echo err >&2; sleep 0.2; echo out again")
+        .tagged_output(move |source, buf| chunks2.lock().unwrap().push((source, buf.to_vec())))
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(
+        *chunks.lock().unwrap(),
+        [
+            (OutputSource::Stdout, b"out\n".to_vec()),
+            (OutputSource::Stderr, b"err\n".to_vec()),
+            (OutputSource::Stdout, b"out again\n".to_vec()),
+        ]
+    );
+
+    let err = match ChildProcess::new(Command::new("true"))
+        .tagged_output(|_, _| ())
+        .start(ReadStream::Null, WriteStream::PipeRequested)
+    {
+        Ok(_) => panic!("start should fail"),
+        Err(e) => e,
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn timed_child_lines() {
+    use io_chain::{LineTiming, OutputSource};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let seen = Arc::new(AtomicUsize::new(0));
+    let seen2 = Arc::clone(&seen);
+    let start = Instant::now();
+    let (output_stream, output) = WriteStream::capture();
+    let exit = ChildProcess::shell(
+        "echo first; sleep 0.2; echo toolongline >&2; sleep 0.2; echo; printf partial",
+    )
+    .time_lines(
+        LineTiming::new()
+            .max_lines(3)
+            .max_line_len(7)
+            .on_line(move |_| {
+                seen2.fetch_add(1, Ordering::SeqCst);
+            }),
+    )
+    .start(ReadStream::Null, output_stream)
+    .unwrap()
+    .wait();
+    assert_eq!(seen.load(Ordering::SeqCst), 4);
+    let lines = exit.timed_lines.clone().unwrap();
+    exit.combine().unwrap();
+    // All the output still goes to stdout.
+    assert_eq!(output.into_bytes(), b"first\n\npartial");
+
+    // The first line was dropped to keep only 3.
+    let summary = lines
+        .iter()
+        .map(|l| (l.source, l.line.as_slice(), l.truncated))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            (OutputSource::Stderr, &b"toolong"[..], true),
+            (OutputSource::Stdout, &b""[..], false),
+            (OutputSource::Stdout, &b"partial"[..], false),
+        ]
+    );
+    assert!(lines[0].time - start >= Duration::from_millis(200));
+    assert!(lines[2].time - lines[0].time >= Duration::from_millis(200));
+
+    // The lines can be looked at while the callback is busy.
+    let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let child = ChildProcess::shell("echo first; echo second")
+        .time_lines(LineTiming::new().on_line(move |_| {
+            let _ = entered_tx.send(());
+            let _ = release_rx.recv_timeout(Duration::from_secs(5));
+        }))
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    entered_rx.recv().unwrap();
+    let start = Instant::now();
+    assert_eq!(child.timed_lines(), Some(vec![]));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    release_tx.send(()).unwrap();
+    release_tx.send(()).unwrap();
+    assert_eq!(child.wait().timed_lines.unwrap().len(), 2);
+}
+
+#[cfg(feature = "signals")]
+#[test]
+fn forward_signals_to_children() {
+    io_chain::install_signal_forwarding(&[libc::SIGTERM]).unwrap();
+
+    let mut child = ChildProcess::command("sleep", ["10"])
+        .new_process_group(true)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    child.forward_signals().unwrap();
+    // SAFETY: FFI call with no pointers. The handler catches the signal.
+    unsafe { libc::raise(libc::SIGTERM) };
+    let start = Instant::now();
+    let exit = child.wait();
+    assert_eq!(exit.signal(), Some(libc::SIGTERM));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // Once waited, the child is no longer registered.
+    let mut child = ChildProcess::command("sleep", ["0.2"])
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    child.forward_signals().unwrap();
+    assert!(child.wait_timeout(Duration::from_secs(5)).is_ok());
+    let child = ChildProcess::command("sleep", ["0.2"])
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    // SAFETY: as above.
+    unsafe { libc::raise(libc::SIGTERM) };
+    child.wait().combine().unwrap();
+}
+
+#[test]
+fn pause_and_resume() {
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    let (output_stream, output) = WriteStream::capture();
+    let mut cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::PipeRequested, output_stream)
+        .unwrap();
+    let mut input = std::fs::File::from(cat.input_pipe().unwrap());
+    cat.pause().unwrap();
+    cat.pause().unwrap();
+
+    // Much more than fits in the pipe, so the writer blocks while cat is paused.
+    let (tx, rx) = mpsc::channel();
+    let writer = std::thread::spawn(move || {
+        input.write_all(&vec![b'x'; 1024 * 1024]).unwrap();
+        tx.send(()).unwrap();
+    });
+    assert_eq!(
+        rx.recv_timeout(Duration::from_millis(300)),
+        Err(mpsc::RecvTimeoutError::Timeout)
+    );
+
+    cat.resume().unwrap();
+    cat.resume().unwrap();
+    rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    writer.join().unwrap();
+
+    // The input has been closed, so cat exits, and then it can't be paused any more.
+    while !cat.is_finished() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(cat.pause().unwrap_err().raw_os_error(), Some(libc::ESRCH));
+    cat.wait().combine().unwrap();
+    assert_eq!(output.into_bytes().len(), 1024 * 1024);
+}
+
+#[test]
+fn stall_timeout() {
+    use io_chain::ChildExitErrorKind;
+
+    let start = Instant::now();
+    let exit = ChildProcess::command("sleep", ["10"])
+        .stall_timeout(Duration::from_millis(300))
+        .start(ReadStream::Null, WriteStream::PipeRequested)
+        .unwrap()
+        .wait();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(exit.stalled, Some(Duration::from_millis(300)));
+    assert_eq!(exit.signal(), Some(libc::SIGKILL));
+    let err = exit.combine().unwrap_err();
+    assert!(matches!(err.kind(), ChildExitErrorKind::Stalled(_)));
+
+    // Slow, but never quiet for long enough.
+    let (output_stream, output) = WriteStream::capture();
+    let exit = ChildProcess::shell("for i in 1 2 3 4 5 6; do sleep 0.1; echo $i; done")
+        .stall_timeout(Duration::from_millis(300))
+        .start(ReadStream::Null, output_stream)
+        .unwrap()
+        .wait();
+    assert_eq!(exit.stalled, None);
+    exit.combine().unwrap();
+    assert_eq!(output.into_bytes(), b"1\n2\n3\n4\n5\n6\n");
+
+    // Exits right away, but isn't waited for until after the timeout.
+    let (output_stream, output) = WriteStream::capture();
+    let child = ChildProcess::shell("echo done")
+        .stall_timeout(Duration::from_millis(300))
+        .start(ReadStream::Null, output_stream)
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(600));
+    let exit = child.wait();
+    assert_eq!(exit.stalled, None);
+    exit.combine().unwrap();
+    assert_eq!(output.into_bytes(), b"done\n");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn parent_death_signal() {
+    // The signal comes when the thread that started the child exits, which is easier to arrange
+    // in a test than this whole process dying.
+    let child = std::thread::spawn(|| {
+        ChildProcess::command("sleep", ["10"])
+            .parent_death_signal(libc::SIGTERM)
+            .start(ReadStream::Null, WriteStream::Null)
+            .unwrap()
+    })
+    .join()
+    .unwrap();
+    let exit = child.wait_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(exit.signal(), Some(libc::SIGTERM));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn dropped_child_is_reaped() {
+    fn state(pid: u32) -> Option<char> {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        stat.rsplit(") ").next()?.chars().next()
+    }
+    fn wait_for(mut f: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    // Exited before the drop.
+    let child = ChildProcess::new(Command::new("true"))
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let pid = child.pid();
+    assert!(wait_for(|| state(pid) == Some('Z')));
+    drop(child);
+    assert_eq!(state(pid), None);
+
+    // Still running at the drop, so reaped in the background.
+    let child = ChildProcess::command("sleep", ["0.2"])
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let pid = child.pid();
+    drop(child);
+    assert!(wait_for(|| state(pid).is_none()));
+
+    // Detached, so left for us to reap.
+    let child = ChildProcess::new(Command::new("true"))
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let pid = child.pid();
+    child.detach();
+    assert!(wait_for(|| state(pid) == Some('Z')));
+    // SAFETY: FFI call with a null status pointer, which waitpid allows.
+    assert_eq!(
+        unsafe { libc::waitpid(pid as libc::pid_t, std::ptr::null_mut(), 0) },
+        pid as libc::pid_t
+    );
+}
+
+#[test]
+fn progress_reports() {
+    use io_chain::Direction;
+    use std::sync::{Arc, Mutex};
+
+    let reports = Arc::new(Mutex::new(vec![]));
+    let reports2 = Arc::clone(&reports);
+    let num_bytes = 1024 * 1024;
+    let (output_stream, output) = WriteStream::capture();
+    ChildProcess::new(Command::new("cat"))
+        .on_progress(Duration::ZERO, move |direction, n| {
+            reports2.lock().unwrap().push((direction, n))
+        })
+        .start(
+            ReadStream::Limited {
+                inner: Box::new(ReadStream::Zeros),
+                limit: num_bytes,
+            },
+            output_stream,
+        )
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(output.into_bytes().len() as u64, num_bytes);
+
+    let reports = reports.lock().unwrap();
+    for direction in [Direction::Input, Direction::Stdout] {
+        let counts = reports
+            .iter()
+            .filter(|(d, _)| *d == direction)
+            .map(|(_, n)| *n)
+            .collect::<Vec<_>>();
+        assert!(counts.len() > 1, "{direction:?}: {counts:?}");
+        assert!(counts.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(counts.last(), Some(&num_bytes));
+    }
+    assert!(!reports.iter().any(|(d, _)| *d == Direction::Stderr));
+}
+
+#[test]
+fn check_program() {
+    use io_chain::SpawnCheckError;
+    use std::os::unix::fs::PermissionsExt;
+
+    ChildProcess::new(Command::new("sh")).check().unwrap();
+    ChildProcess::new(Command::new("/bin/sh")).check().unwrap();
+
+    let missing = ChildProcess::new(Command::new("io-chain-test-missing"));
+    let err = missing.check().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "program 'io-chain-test-missing' not found in PATH"
+    );
+    let err = match missing.start(ReadStream::Null, WriteStream::Null) {
+        Ok(_) => panic!("start should fail"),
+        Err(e) => e,
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(err.to_string().contains("io-chain-test-missing"), "{err}");
+
+    // Only in the command's own PATH, and not executable.
+    let dir = std::env::temp_dir().join(format!("io-chain-test-check-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("io-chain-test-script");
+    std::fs::write(&file, "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let err = ChildProcess::new(Command::new("io-chain-test-script"))
+        .env("PATH", &dir)
+        .check()
+        .unwrap_err();
+    assert_eq!(
+        err,
+        SpawnCheckError::NotExecutable {
+            program: "io-chain-test-script".into(),
+            path: file.clone(),
+        }
+    );
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+    ChildProcess::new(Command::new("io-chain-test-script"))
+        .env("PATH", &dir)
+        .check()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn command_in_diagnostics() {
+    let child = ChildProcess::command("sh", ["-c", "exit 3"])
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    assert_eq!(child.program(), "sh");
+    assert_eq!(child.args(), ["-c", "exit 3"]);
+    assert!(format!("{child:?}").contains(child.command_line()));
+
+    let exit = child.wait();
+    assert_eq!(exit.program, "sh");
+    assert_eq!(exit.args, ["-c", "exit 3"]);
+    assert!(format!("{exit:?}").contains("exit 3"));
+    let err = exit.combine().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "'sh' exited unsuccessfully: exit status: 3"
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn cpu_affinity() {
+    fn allowed() -> String {
+        let status = std::fs::read_to_string("/proc/thread-self/status").unwrap();
+        let line = status
+            .lines()
+            .find(|line| line.starts_with("Cpus_allowed_list:"))
+            .unwrap();
+        format!("{line}\n")
+    }
+
+    let lambda_cpus = std::sync::Arc::new(parking_lot::Mutex::new(String::new()));
+    let seen = std::sync::Arc::clone(&lambda_cpus);
+    let lambda = LambdaFilter::new(move |_: &[u8]| *seen.lock() = allowed())
+        .cpu_affinity(&[0])
+        .start(ReadStream::from("x"), WriteStream::Null)
+        .unwrap();
+    lambda.wait().unwrap();
+    assert_eq!(*lambda_cpus.lock(), "Cpus_allowed_list:\t0\n");
+
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell("grep Cpus_allowed_list /proc/self/status; cat")
+        .cpu_affinity(&[0])
+        .copy_thread_cpu_affinity(&[0])
+        .start(
+            // Evaluated on the copy thread, once it has moved.
+            ReadStream::from_chunks(std::iter::once_with(|| Ok(allowed().into_bytes()))),
+            output,
+        )
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(
+        captured.into_bytes(),
+        b"Cpus_allowed_list:\t0\nCpus_allowed_list:\t0\n"
+    );
+
+    // Checked before anything is started.
+    let err = ChildProcess::new(Command::new("true"))
+        .cpu_affinity(&[usize::MAX])
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn umask_and_chroot() {
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell("umask")
+        .umask(0o077)
+        .start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"0077\n");
+
+    // SAFETY: FFI call with no pointers.
+    if unsafe { libc::getuid() } != 0 {
+        let err = ChildProcess::new(Command::new("true"))
+            .chroot("/")
+            .start(ReadStream::Null, WriteStream::Null)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        return;
+    }
+
+    // The root changes before the user does, and the working directory goes with it.
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell("pwd; id -u")
+        .current_dir("/tmp")
+        .chroot("/")
+        .uid(65534)
+        .start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"/\n65534\n");
+
+    // Nothing to run in an empty root.
+    let dir = std::env::temp_dir().join(format!("io-chain-chroot-{}", std::process::id()));
+    std::fs::create_dir(&dir).unwrap();
+    let err = ChildProcess::new(Command::new("true"))
+        .chroot(&dir)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap_err();
+    std::fs::remove_dir(&dir).unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn transform_filter() {
+    /// Numbers each line, holding back partial lines until they're finished.
+    struct NumberLines {
+        count: usize,
+        partial: Vec<u8>,
+    }
+
+    impl Transform for NumberLines {
+        type FinishResult = usize;
+
+        fn transform(&mut self, input: &[u8], out: &mut dyn std::io::Write) -> std::io::Result<()> {
+            self.partial.extend_from_slice(input);
+            while let Some(i) = self.partial.iter().position(|&b| b == b'\n') {
+                self.count += 1;
+                write!(out, "{} ", self.count)?;
+                out.write_all(&self.partial[..=i])?;
+                self.partial.drain(..=i);
+            }
+            Ok(())
+        }
+
+        fn finish(mut self, out: &mut dyn std::io::Write) -> std::io::Result<usize> {
+            if !self.partial.is_empty() {
+                self.count += 1;
+                write!(out, "{} ", self.count)?;
+                out.write_all(&self.partial)?;
+            }
+            Ok(self.count)
+        }
+    }
+
+    let chunks = ["one\ntw", "o\n", "three"].map(|s| Ok(s.as_bytes().to_vec()));
+    let (output, captured) = WriteStream::capture();
+    let count = TransformFilter::new(NumberLines {
+        count: 0,
+        partial: vec![],
+    })
+    .start(ReadStream::from_chunks(chunks), output)
+    .unwrap()
+    .wait()
+    .unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(captured.into_bytes(), b"1 one\n2 two\n3 three");
+
+    let mut output = vec![];
+    TransformFilter::new(|buf: &[u8], out: &mut dyn std::io::Write| {
+        out.write_all(&buf.to_ascii_uppercase())
+    })
+    .run_blocking(&b"shout"[..], &mut output)
+    .unwrap();
+    assert_eq!(output, b"SHOUT");
+}
+
+#[test]
+fn failing_lambda() {
+    /// Counts buffers, and rejects any with a `!` in them.
+    struct Reject(usize);
+
+    impl TryLambda for Reject {
+        type FinishResult = usize;
+
+        fn handle(&mut self, buf: &[u8]) -> std::io::Result<ControlFlow<()>> {
+            if buf.contains(&b'!') {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "bad buffer",
+                ));
+            }
+            self.0 += 1;
+            Ok(ControlFlow::Continue(()))
+        }
+
+        fn finish(self, _end: StreamEnd) -> std::io::Result<usize> {
+            Ok(self.0)
+        }
+    }
+
+    let chunks = ["ok\n", "bad!\n", "never\n"].map(|s| Ok(s.as_bytes().to_vec()));
+    let mut lambda = LambdaFilter::new(Reject(0))
+        .name("reject")
+        .start(ReadStream::from_chunks(chunks), WriteStream::PipeRequested)
+        .unwrap();
+    let (output, captured) = WriteStream::capture();
+    let cat = ChildProcess::new(Command::new("cat"))
+        .start(lambda.output_reader().unwrap().into(), output)
+        .unwrap();
+
+    let err = lambda.wait().unwrap_err();
+    assert!(io_chain::LambdaFailed::is(&err.error));
+    assert_eq!(
+        err.to_string(),
+        "filter 'reject': lambda failed: bad buffer"
+    );
+    // The next filter sees the end of the stream, including the buffer that failed, and nothing
+    // after it.
+    cat.wait().combine().unwrap();
+    assert_eq!(captured.into_bytes(), b"ok\nbad!\n");
+
+    // Without a failure, the result comes from finish().
+    let count = LambdaFilter::new(Reject(0))
+        .run_blocking(&b"fine"[..], std::io::sink())
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn lambda_stops_early() {
+    /// Stops after the first buffer, and says how the stream ended.
+    struct Header;
+
+    impl TryLambda for Header {
+        type FinishResult = StreamEnd;
+
+        fn handle(&mut self, _buf: &[u8]) -> std::io::Result<ControlFlow<()>> {
+            Ok(ControlFlow::Break(()))
+        }
+
+        fn finish(self, end: StreamEnd) -> std::io::Result<StreamEnd> {
+            Ok(end)
+        }
+    }
+
+    let mut yes = ChildProcess::new(Command::new("yes"))
+        .start(ReadStream::Null, WriteStream::PipeRequested)
+        .unwrap();
+    let (output, captured) = WriteStream::capture();
+    let lambda = LambdaFilter::new(Header)
+        .start(yes.output_reader().unwrap().into(), output)
+        .unwrap();
+    assert_eq!(lambda.wait().unwrap(), StreamEnd::Stopped);
+    // Upstream gets a broken pipe, just as with `head`.
+    let exit = yes.wait_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(exit.signal(), Some(libc::SIGPIPE));
+    assert!(captured.into_bytes().starts_with(b"y\ny\n"));
+}
+
+#[test]
+fn lambda_offsets() {
+    /// Takes at most a few bytes at a time.
+    struct Dribble(Vec<u8>);
+
+    impl std::io::Write for Dribble {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let input = b"the quick brown fox jumps over the lazy dog";
+    let mut seen = vec![];
+    let mut output = Dribble(vec![]);
+    LambdaFilter::with_offsets(|offset, buf: &[u8]| seen.push((offset, buf.to_vec())))
+        .run_blocking(&input[..], &mut output)
+        .unwrap();
+    assert_eq!(output.0, input);
+    let mut expected = 0;
+    for (offset, buf) in &seen {
+        assert_eq!(*offset, expected);
+        assert_eq!(buf[..], input[expected as usize..][..buf.len()]);
+        expected += buf.len() as u64;
+    }
+    assert_eq!(expected, input.len() as u64);
+}
+
+#[test]
+fn line_lambda() {
+    let chunks = ["one\nt", "w", "o\n\nthreeee", "eeee\nfour"].map(|s| Ok(s.as_bytes().to_vec()));
+    let lines = std::sync::Arc::new(parking_lot::Mutex::new(vec![]));
+    let seen = std::sync::Arc::clone(&lines);
+    LambdaFilter::new(LineLambda::new(move |line: &[u8]| {
+        seen.lock().push(String::from_utf8(line.to_vec()).unwrap())
+    }))
+    .start(ReadStream::from_chunks(chunks), WriteStream::Null)
+    .unwrap()
+    .wait()
+    .unwrap();
+    assert_eq!(*lines.lock(), ["one", "two", "", "threeeeeeee", "four"]);
+
+    let mut lines = vec![];
+    LambdaFilter::new(
+        LineLambda::new(|line: &[u8]| lines.push(String::from_utf8(line.to_vec()).unwrap()))
+            .max_line_len(4, LongLines::Split),
+    )
+    .run_blocking(&b"one\nthreeeeeeee\nfour"[..], std::io::sink())
+    .unwrap();
+    assert_eq!(lines, ["one", "thre", "eeee", "eee", "four"]);
+
+    let err = LambdaFilter::new(LineLambda::new(|_: &[u8]| ()).max_line_len(4, LongLines::Fail))
+        .run_blocking(&b"one\nthreeeeeeee\nfour"[..], std::io::sink())
+        .unwrap_err();
+    assert!(io_chain::LambdaFailed::is(&err.error));
+
+    // The separator can be split between buffers too.
+    let mut lines = vec![];
+    let mut lambda =
+        LineLambda::new(|line: &[u8]| lines.push(String::from_utf8(line.to_vec()).unwrap()))
+            .separator("\r\n")
+            .max_line_len(6, LongLines::Fail);
+    for chunk in ["one\r", "\ntwo\r\nthree\r", "\r\n\r"] {
+        assert!(lambda.handle(chunk.as_bytes()).unwrap().is_continue());
+    }
+    lambda.finish(StreamEnd::Eof).unwrap();
+    assert_eq!(lines, ["one", "two", "three\r", "\r"]);
+
+    let mut lines = vec![];
+    LambdaFilter::new(LineLambda::new(|line: &[u8]| lines.push(line.to_vec())).nul_separated())
+        .run_blocking(&b"a b\0c\nd\0"[..], std::io::sink())
+        .unwrap();
+    assert_eq!(lines, [&b"a b"[..], b"c\nd"]);
+}
+
+#[test]
+fn utf8_lambda() {
+    let input = "héllo wörld €1 🦀".as_bytes();
+    // Every way of splitting the input in two, through the middle of each character.
+    for i in 0..input.len() {
+        let mut text = String::new();
+        let mut lambda = Utf8Lambda::new(|s: &str| text.push_str(s));
+        for chunk in [&input[..i], &input[i..]] {
+            assert!(lambda.handle(chunk).unwrap().is_continue());
+        }
+        lambda.finish(StreamEnd::Eof).unwrap();
+        assert_eq!(text.as_bytes(), input);
+    }
+
+    // Invalid bytes in the middle and an unfinished character at the end.
+    let chunks: [&[u8]; 3] = [b"ok \xff\xe2", b"\x82", b"\xac ok \xf0\x9f"];
+    let mut text = String::new();
+    let mut lambda = Utf8Lambda::new(|s: &str| text.push_str(s)).invalid(InvalidUtf8::Replace);
+    for chunk in chunks {
+        assert!(lambda.handle(chunk).unwrap().is_continue());
+    }
+    lambda.finish(StreamEnd::Eof).unwrap();
+    assert_eq!(text, "ok \u{FFFD}€ ok \u{FFFD}");
+
+    let invalid = std::sync::Arc::new(parking_lot::Mutex::new(vec![]));
+    let seen = std::sync::Arc::clone(&invalid);
+    let mut lambda =
+        Utf8Lambda::new(|_: &str| ()).invalid(InvalidUtf8::Bytes(Box::new(move |b| {
+            seen.lock().push(b.to_vec())
+        })));
+    for chunk in chunks {
+        assert!(lambda.handle(chunk).unwrap().is_continue());
+    }
+    lambda.finish(StreamEnd::Eof).unwrap();
+    assert_eq!(*invalid.lock(), [&b"\xff"[..], b"\xf0\x9f"]);
+
+    let err = LambdaFilter::new(Utf8Lambda::new(|_: &str| ()))
+        .run_blocking(&b"ok \xff"[..], std::io::sink())
+        .unwrap_err();
+    assert!(io_chain::LambdaFailed::is(&err.error));
+}
+
+#[test]
+fn lambda_buffer_size() {
+    let input = vec![0; 1 << 20];
+    let mut calls = 0;
+    LambdaFilter::new(|_: &[u8]| calls += 1)
+        .buffer_size(4096)
+        .run_blocking(&input[..], std::io::sink())
+        .unwrap();
+    assert_eq!(calls, 256);
+
+    let mut sizes = vec![];
+    LambdaFilter::new(|buf: &[u8]| sizes.push(buf.len()))
+        .buffer_size(128 << 10)
+        .run_blocking(&input[..], std::io::sink())
+        .unwrap();
+    assert_eq!(sizes, [128 << 10; 8]);
+}
+
+#[test]
+fn lambda_partial_result() {
+    /// Gives some data, then fails.
+    struct Broken(Option<&'static [u8]>);
+
+    impl std::io::Read for Broken {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.take() {
+                Some(data) => {
+                    buf[..data.len()].copy_from_slice(data);
+                    Ok(data.len())
+                }
+                None => Err(std::io::ErrorKind::ConnectionReset.into()),
+            }
+        }
+    }
+
+    /// Counts bytes, and notes how the stream ended.
+    struct Count(usize);
+
+    impl TryLambda for Count {
+        type FinishResult = (usize, StreamEnd);
+
+        fn handle(&mut self, buf: &[u8]) -> std::io::Result<ControlFlow<()>> {
+            self.0 += buf.len();
+            Ok(ControlFlow::Continue(()))
+        }
+
+        fn finish(self, end: StreamEnd) -> std::io::Result<(usize, StreamEnd)> {
+            Ok((self.0, end))
+        }
+    }
+
+    let err = LambdaFilter::new(Count(0))
+        .run_blocking(Broken(Some(b"some data")), std::io::sink())
+        .unwrap_err();
+    assert_eq!(err.error.kind(), std::io::ErrorKind::ConnectionReset);
+    assert_eq!(err.partial, Some((9, StreamEnd::Error)));
+
+    let (total, end) = LambdaFilter::new(Count(0))
+        .start(ReadStream::from("all of it"), WriteStream::Null)
+        .unwrap()
+        .wait()
+        .unwrap();
+    assert_eq!((total, end), (9, StreamEnd::Eof));
+}
+
+#[test]
+fn lambda_observe_before_write() {
+    /// Takes only the first few bytes, then fails.
+    struct Short(usize);
+
+    impl std::io::Write for Short {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.0 == 0 {
+                return Err(std::io::ErrorKind::ConnectionReset.into());
+            }
+            let n = buf.len().min(self.0);
+            self.0 -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Counts bytes.
+    struct Count(usize);
+
+    impl Lambda for Count {
+        type FinishResult = usize;
+
+        fn handle(&mut self, buf: &[u8]) {
+            self.0 += buf.len();
+        }
+
+        fn finish(self) -> usize {
+            self.0
+        }
+    }
+
+    let err = LambdaFilter::new(Count(0))
+        .run_blocking(&b"some data"[..], Short(4))
+        .unwrap_err();
+    assert_eq!(err.error.kind(), std::io::ErrorKind::ConnectionReset);
+    assert_eq!(err.partial, Some(4));
+
+    let err = LambdaFilter::new(Count(0))
+        .observe_before_write(true)
+        .run_blocking(&b"some data"[..], Short(4))
+        .unwrap_err();
+    assert_eq!(err.error.kind(), std::io::ErrorKind::ConnectionReset);
+    assert_eq!(err.partial, Some(9));
+}
+
+#[test]
+fn flush_at_end() {
+    /// Collects everything written, where the test can see it.
+    #[derive(Clone, Default)]
+    struct Sink(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Big enough that nothing is written out until it's flushed.
+    let buffered = |sink: &Sink| std::io::BufWriter::with_capacity(1 << 20, sink.clone());
+
+    let sink = Sink::default();
+    LambdaFilter::new(|_: &[u8]| ())
+        .start(
+            ReadStream::from("through a lambda"),
+            WriteStream::Rust(Box::new(buffered(&sink))),
+        )
+        .unwrap()
+        .wait()
+        .unwrap();
+    assert_eq!(*sink.0.lock().unwrap(), b"through a lambda");
+
+    let sink = Sink::default();
+    let mut tee = Tee::new(4);
+    tee.add_output(buffered(&sink));
+    let results = tee
+        .start(ReadStream::from("through a tee"), WriteStream::Null)
+        .unwrap()
+        .wait();
+    assert!(results.iter().all(Result::is_ok));
+    assert_eq!(*sink.0.lock().unwrap(), b"through a tee");
+
+    /// Fails to flush, like a socket wrapper which finds the connection gone.
+    struct BadFlush;
+
+    impl std::io::Write for BadFlush {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    let err = LambdaFilter::new(|_: &[u8]| ())
+        .run_blocking(&b"data"[..], BadFlush)
+        .unwrap_err();
+    assert_eq!(err.error.kind(), std::io::ErrorKind::ConnectionReset);
+
+    let mut tee = Tee::new(4);
+    tee.add_output(BadFlush);
+    let results = tee.run_blocking(&b"data"[..], BadFlush);
+    assert!(results[0].is_ok());
+    assert_eq!(
+        results[1].as_ref().unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionReset
+    );
+    assert_eq!(
+        results[2].as_ref().unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionReset
+    );
+}
+
+#[test]
+fn lambda_consumer() {
+    /// Counts bytes and buffers, and notes how the stream ended.
+    struct Count(usize, usize);
+
+    impl TryLambda for Count {
+        type FinishResult = (usize, usize, StreamEnd);
+
+        fn handle(&mut self, buf: &[u8]) -> std::io::Result<ControlFlow<()>> {
+            self.0 += buf.len();
+            self.1 += 1;
+            Ok(ControlFlow::Continue(()))
+        }
+
+        fn finish(self, end: StreamEnd) -> std::io::Result<Self::FinishResult> {
+            Ok((self.0, self.1, end))
+        }
+    }
+
+    let data = vec![b'x'; 10_000];
+    let result = LambdaFilter::new(Count(0, 0))
+        .buffer_size(4096)
+        .start(ReadStream::from(data), WriteStream::Null)
+        .unwrap()
+        .wait()
+        .unwrap();
+    assert_eq!(result, (10_000, 3, StreamEnd::Eof));
+
+    let mut seen = 0;
+    io_chain::scope(|s| {
+        let running = s
+            .start(
+                LambdaFilter::new(|buf: &[u8]| seen += buf.len()),
+                ReadStream::from("in a scope"),
+                WriteStream::Null,
+            )
+            .unwrap();
+        running.wait().unwrap();
+    })
+    .unwrap();
+    assert_eq!(seen, 10);
+}
+
+#[test]
+fn lambda_prologue_epilogue() {
+    /// Wraps the data in brackets, followed by its length.
+    struct Envelope(usize);
+
+    impl TryLambda for Envelope {
+        type FinishResult = usize;
+
+        fn handle(&mut self, buf: &[u8]) -> std::io::Result<ControlFlow<()>> {
+            self.0 += buf.len();
+            Ok(ControlFlow::Continue(()))
+        }
+
+        fn finish(self, _end: StreamEnd) -> std::io::Result<usize> {
+            Ok(self.0)
+        }
+
+        fn prologue(&mut self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+            out.write_all(b"[")
+        }
+
+        fn epilogue(&mut self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+            writeln!(out, "] {}", self.0)
+        }
+    }
+
+    let mut output = vec![];
+    let len = LambdaFilter::new(Envelope(0))
+        .run_blocking(&b"payload"[..], &mut output)
+        .unwrap();
+    assert_eq!(len, 7);
+    assert_eq!(output, b"[payload] 7\n");
+
+    // The handler doesn't see what it writes itself.
+    let mut running = LambdaFilter::new(Envelope(0))
+        .start(ReadStream::from("abc"), WriteStream::PipeRequested)
+        .unwrap();
+    let mut reader = running.output_reader().unwrap();
+    let mut output = String::new();
+    std::io::Read::read_to_string(&mut reader, &mut output).unwrap();
+    assert_eq!(running.wait().unwrap(), 3);
+    assert_eq!(output, "[abc] 3\n");
+
+    /// Fails to write anything.
+    struct Closed;
+
+    impl std::io::Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::ConnectionReset.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let err = LambdaFilter::new(Envelope(0))
+        .run_blocking(&b"payload"[..], Closed)
+        .unwrap_err();
+    assert_eq!(err.error.kind(), std::io::ErrorKind::ConnectionReset);
+    assert_eq!(err.partial, None);
+}
+
+#[test]
+fn lambda_chunk_size() {
+    let run = |input: &'static str, buffer_size: usize| {
+        let mut chunks = vec![];
+        let mut output = vec![];
+        LambdaFilter::new(|buf: &[u8]| chunks.push(String::from_utf8(buf.to_vec()).unwrap()))
+            .buffer_size(buffer_size)
+            .chunk_size(4)
+            .run_blocking(input.as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(output, input.as_bytes());
+        chunks
+    };
+
+    // Reads of 3 bytes, so blocks span them.
+    assert_eq!(run("abcdefghij", 3), ["abcd", "efgh", "ij"]);
+    assert_eq!(run("abcdefgh", 3), ["abcd", "efgh"]);
+    // Several blocks in one read.
+    assert_eq!(run("abcdefghijk", 100), ["abcd", "efgh", "ijk"]);
+    // Less than one block in all.
+    assert_eq!(run("ab", 1), ["ab"]);
+    assert!(run("", 3).is_empty());
+
+    // Without an output to pass it on to.
+    let mut chunks = vec![];
+    io_chain::scope(|s| {
+        s.start(
+            LambdaFilter::new(|buf: &[u8]| chunks.push(buf.len()))
+                .buffer_size(5)
+                .chunk_size(4),
+            ReadStream::from("0123456789"),
+            WriteStream::Null,
+        )
+        .unwrap()
+        .wait()
+        .unwrap();
+    })
+    .unwrap();
+    assert_eq!(chunks, [4, 4, 2]);
+}
+
+#[test]
+fn sampling_lambda() {
+    /// Keeps everything it's given.
+    struct Collect(Vec<u8>);
+
+    impl Lambda for Collect {
+        type FinishResult = Vec<u8>;
+
+        fn handle(&mut self, buf: &[u8]) {
+            self.0.extend_from_slice(buf);
+        }
+
+        fn finish(self) -> Vec<u8> {
+            self.0
+        }
+    }
+
+    let run = |sampling: fn(Collect) -> Sampling<Collect>| {
+        let mut output = vec![];
+        let (sampled, counts) = LambdaFilter::new(sampling(Collect(vec![])))
+            .buffer_size(3)
+            .run_blocking(&b"abcdefghijklmn"[..], &mut output)
+            .unwrap();
+        assert_eq!(output, b"abcdefghijklmn");
+        (String::from_utf8(sampled).unwrap(), counts)
+    };
+
+    // Buffers are "abc", "def", "ghi", "jkl", "mn"; bytes 0, 5 and 10 are in the first, second
+    // and fourth.
+    let (sampled, counts) = run(|v| Sampling::every_bytes(5, v));
+    assert_eq!(sampled, "abcdefjkl");
+    assert_eq!(
+        counts,
+        SampleCounts {
+            total: 14,
+            sampled: 9
+        }
+    );
+
+    let (sampled, counts) = run(|v| Sampling::every_nth(2, v));
+    assert_eq!(sampled, "abcghimn");
+    assert_eq!(
+        counts,
+        SampleCounts {
+            total: 14,
+            sampled: 8
+        }
+    );
+}
+
+#[test]
+fn mutate_filter() {
+    let mut output = vec![];
+    MutateFilter::new(|buf: &mut [u8]| buf.make_ascii_uppercase())
+        .buffer_size(4)
+        .run_blocking(&b"shout this"[..], &mut output)
+        .unwrap();
+    assert_eq!(output, b"SHOUT THIS");
+
+    /// XORs each byte with a key, and counts them.
+    struct Mask(u8, usize);
+
+    impl MutateLambda for Mask {
+        type FinishResult = usize;
+
+        fn handle(&mut self, buf: &mut [u8]) {
+            buf.iter_mut().for_each(|b| *b ^= self.0);
+            self.1 += buf.len();
+        }
+
+        fn finish(self) -> usize {
+            self.1
+        }
+    }
+
+    let mut masked = MutateFilter::new(Mask(0x20, 0))
+        .start(ReadStream::from("MiXeD"), WriteStream::PipeRequested)
+        .unwrap();
+    let mut output = String::new();
+    std::io::Read::read_to_string(&mut masked.output_reader().unwrap(), &mut output).unwrap();
+    assert_eq!(masked.wait().unwrap(), 5);
+    assert_eq!(output, "mIxEd");
+}
+
+#[test]
+fn lambda_whole_buffers() {
+    /// Takes one byte at a time, and fails after a while.
+    struct Dribble(usize);
+
+    impl std::io::Write for Dribble {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.0 == 0 {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            self.0 -= 1;
+            Ok(buf.len().min(1))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut calls = vec![];
+    LambdaFilter::new(|buf: &[u8]| calls.push(buf.len()))
+        .buffer_size(4)
+        .run_blocking(&b"0123456789"[..], Dribble(100))
+        .unwrap();
+    assert_eq!(calls, [1; 10]);
+
+    let mut calls = vec![];
+    LambdaFilter::new(|buf: &[u8]| calls.push(buf.len()))
+        .buffer_size(4)
+        .whole_buffers(true)
+        .run_blocking(&b"0123456789"[..], Dribble(100))
+        .unwrap();
+    assert_eq!(calls, [4, 4, 2]);
+
+    // The output closes partway through the second buffer.
+    let mut calls = vec![];
+    LambdaFilter::new(|buf: &[u8]| calls.push(buf.len()))
+        .buffer_size(4)
+        .whole_buffers(true)
+        .output_epipe_ok(true)
+        .run_blocking(&b"0123456789"[..], Dribble(6))
+        .unwrap();
+    assert_eq!(calls, [4, 2]);
+}
+
+#[test]
+fn lambda_stop_handle() {
+    /// Counts bytes, and notes how the stream ended.
+    struct Count(usize);
+
+    impl TryLambda for Count {
+        type FinishResult = (usize, StreamEnd);
+
+        fn handle(&mut self, buf: &[u8]) -> std::io::Result<ControlFlow<()>> {
+            self.0 += buf.len();
+            Ok(ControlFlow::Continue(()))
+        }
+
+        fn finish(self, end: StreamEnd) -> std::io::Result<Self::FinishResult> {
+            Ok((self.0, end))
+        }
+    }
+
+    let mut filter = LambdaFilter::new(Count(0));
+    let stop = filter.stop_handle();
+    let mut lambda = filter
+        .start(ReadStream::PipeRequested, WriteStream::PipeRequested)
+        .unwrap();
+    let mut input = lambda.input_writer().unwrap();
+    let mut output = lambda.output_reader().unwrap();
+    std::io::Write::write_all(&mut input, b"hello").unwrap();
+    let mut buf = [0; 5];
+    std::io::Read::read_exact(&mut output, &mut buf).unwrap();
+
+    // The input is still open, so the filter is waiting to read more.
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!lambda.is_finished());
+    stop.stop();
+    assert!(stop.is_stopped());
+    assert_eq!(lambda.wait().unwrap(), (5, StreamEnd::Stopped));
+    // Downstream sees the end.
+    let mut rest = vec![];
+    std::io::Read::read_to_end(&mut output, &mut rest).unwrap();
+    assert!(rest.is_empty());
+    drop(input);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn thread_names() {
+    let thread_names = || {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .map(|task| {
+                let comm = std::fs::read_to_string(task.unwrap().path().join("comm")).unwrap();
+                comm.trim_end().to_owned()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut lambda = LambdaFilter::new(|_: &[u8]| ())
+        .name("named-lambda")
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    let mut tee = Tee::new(16);
+    tee.add_output(std::io::sink());
+    let mut tee = tee
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    let (stdin_tx, stdin_rx) = std::sync::mpsc::sync_channel(0);
+    let cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::Channel(stdin_rx), WriteStream::Null)
+        .unwrap();
+
+    let names = thread_names();
+    for name in ["named-lambda", "tee", "tee output 0", "cat stdin"] {
+        assert!(names.iter().any(|n| n == name), "no {name} in {names:?}");
+    }
+    drop(lambda.input_writer());
+    drop(tee.input_writer());
+    drop(stdin_tx);
+    lambda.wait().unwrap();
+    assert!(tee.wait().iter().all(Result::is_ok));
+    cat.wait().combine().unwrap();
+}
+
+#[test]
+fn thread_stack_size() {
+    // Much more than the default stack has room for.
+    const BIG: usize = 8 << 20;
+    LambdaFilter::new(|buf: &[u8]| {
+        let mut big = [0u8; BIG];
+        big[..buf.len()].copy_from_slice(buf);
+        std::hint::black_box(&big);
+    })
+    .stack_size(BIG * 2)
+    .start(ReadStream::from("data"), WriteStream::Null)
+    .unwrap()
+    .wait()
+    .unwrap();
+}
+
+#[test]
+fn panic_message() {
+    use io_chain::IoChainError;
+
+    let start = || {
+        LambdaFilter::new(|_: &[u8]| panic!("lambda gave up on {}", "purpose"))
+            .name("doomed")
+            .start(ReadStream::from("data"), WriteStream::Null)
+            .unwrap()
+    };
+
+    let err = start().wait().unwrap_err();
+    assert!(
+        err.to_string().contains("lambda gave up on purpose"),
+        "{err}"
+    );
+
+    match start().wait_ok() {
+        Err(IoChainError::Panicked { message }) => {
+            assert_eq!(message.as_deref(), Some("lambda gave up on purpose"));
+        }
+        other => panic!("wrong result: {other:?}"),
+    }
+
+    // A panic with a plain string literal has a different payload type.
+    let err = MutateFilter::new(|_: &mut [u8]| panic!("static message"))
+        .start(ReadStream::from("data"), WriteStream::Null)
+        .unwrap()
+        .wait()
+        .unwrap_err();
+    assert!(err.to_string().contains("static message"), "{err}");
+}
+
+#[test]
+fn counting_filter() {
+    use io_chain::StreamCounts;
+
+    let counts = CountingFilter::new()
+        .buffer_size(4)
+        .run_blocking(&b"hello world"[..], std::io::sink())
+        .unwrap();
+    assert_eq!(
+        counts,
+        StreamCounts {
+            bytes: 11,
+            chunks: 3
+        }
+    );
+
+    // Just consuming.
+    let mut count = CountingFilter::new();
+    let live = count.live_bytes();
+    let counts = count
+        .start(ReadStream::from("hello world"), WriteStream::Null)
+        .unwrap()
+        .wait()
+        .unwrap();
+    assert_eq!(counts.bytes, 11);
+    assert_eq!(live.load(std::sync::atomic::Ordering::SeqCst), 11);
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn hash_filter() {
+    use io_chain::HashFilter;
+
+    fn sha256(data: &'static str) -> String {
+        let hash = HashFilter::<sha2::Sha256>::new()
+            .start(ReadStream::from(data), WriteStream::Null)
+            .unwrap()
+            .wait()
+            .unwrap();
+        format!("{hash:x}")
+    }
+
+    assert_eq!(
+        sha256(""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        sha256("abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+
+    // Passing the data on, with any digest.
+    let mut out = Vec::new();
+    let hash = HashFilter::<sha2::Sha512>::new()
+        .buffer_size(3)
+        .run_blocking(&b"abc"[..], &mut out)
+        .unwrap();
+    assert_eq!(out, b"abc");
+    assert_eq!(
+        format!("{hash:x}"),
+        "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+         2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+    );
+
+    #[cfg(feature = "sha2")]
+    assert_eq!(
+        HashFilter::sha256()
+            .run_blocking(&b"abc"[..], std::io::sink())
+            .unwrap(),
+        HashFilter::<sha2::Sha256>::new()
+            .run_blocking(&b"abc"[..], std::io::sink())
+            .unwrap()
+    );
+}
+
+#[test]
+fn conflicting_options_leave_input_alone() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Notes whether it was ever read.
+    struct Watched(Arc<AtomicBool>);
+
+    impl std::io::Read for Watched {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.store(true, Ordering::SeqCst);
+            Ok(0)
+        }
+    }
+
+    let configs = [
+        ChildProcess::new(Command::new("cat"))
+            .merge_stderr(true)
+            .stderr(WriteStream::Null),
+        ChildProcess::new(Command::new("cat")).tagged_output(|_, _| ()),
+        ChildProcess::new(Command::new("cat"))
+            .capture_stderr_tail(100)
+            .stderr(WriteStream::Null),
+    ];
+    for child in configs {
+        let read = Arc::new(AtomicBool::new(false));
+        let input = ReadStream::Rust(Box::new(Watched(Arc::clone(&read))));
+        let err = match child.start(input, WriteStream::PipeRequested) {
+            Ok(_) => panic!("start should fail"),
+            Err(e) => e,
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!read.load(Ordering::SeqCst));
+    }
+}
+
+#[test]
+fn minimal_running_filter() {
+    use io_chain::OwnedPipeEnd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Implements only what's required.
+    struct Done;
+
+    impl RunningFilter for Done {
+        type Result = ();
+
+        fn wait(self) {}
+
+        fn input_pipe(&mut self) -> Option<OwnedPipeEnd> {
+            None
+        }
+
+        fn output_pipe(&mut self) -> Option<OwnedPipeEnd> {
+            None
+        }
+    }
+
+    let called = Arc::new(AtomicBool::new(false));
+    let called2 = Arc::clone(&called);
+    let mut done = Done;
+    done.on_complete(move || called2.store(true, Ordering::SeqCst));
+    assert!(called.load(Ordering::SeqCst));
+
+    // It can still be used as a trait object.
+    let mut boxed: Box<dyn RunningFilter<Result = ()>> = Box::new(done);
+    assert!(boxed.input_pipe().is_none());
+}
@@ -0,0 +1,750 @@
+use std::process::Command;
+
+use io_chain::{
+    ChildProcess, FileOpts, Filter, LambdaFilter, ReadStream, RunningFilter, Tee, WriteStream,
+};
+
+#[test]
+fn file_not_found_mentions_path() {
+    let path = std::env::temp_dir().join("io-chain-test-does-not-exist");
+    let cat = ChildProcess::new(Command::new("cat"));
+    let err = match cat.start(ReadStream::File(path.clone()), WriteStream::Null) {
+        Ok(_) => panic!("start should fail"),
+        Err(e) => e,
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(err.to_string().contains(&*path.to_string_lossy()));
+}
+
+#[test]
+fn file_append() {
+    let path = std::env::temp_dir().join(format!("io-chain-test-append-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    for word in ["one", "two"] {
+        let mut cmd = Command::new("echo");
+        cmd.arg(word);
+        ChildProcess::new(cmd)
+            .start(
+                ReadStream::Null,
+                WriteStream::File {
+                    path: path.clone(),
+                    options: FileOpts::new().create(true).append(true),
+                },
+            )
+            .unwrap()
+            .wait()
+            .combine()
+            .unwrap();
+    }
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bytes_through_cat() {
+    let big = vec![b'x'; 1024 * 1024];
+    for input in [ReadStream::from("hello\n"), ReadStream::from(big.clone())] {
+        let expected = match &input {
+            ReadStream::Bytes(b) => b.to_vec(),
+            _ => unreachable!(),
+        };
+        let (output, captured) = WriteStream::capture();
+        ChildProcess::new(Command::new("cat"))
+            .start(input, output)
+            .unwrap()
+            .wait()
+            .combine()
+            .unwrap();
+        assert_eq!(captured.into_bytes(), expected);
+    }
+}
+
+#[test]
+fn channel_input() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (output, captured) = WriteStream::capture();
+    let cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::Channel(rx), output)
+        .unwrap();
+    let mut expected = vec![];
+    for i in 0..100 {
+        let chunk = vec![i as u8; i * 1000];
+        expected.extend_from_slice(&chunk);
+        tx.send(chunk).unwrap();
+    }
+    drop(tx);
+    cat.wait().combine().unwrap();
+    assert_eq!(captured.into_bytes(), expected);
+}
+
+#[test]
+fn channel_output() {
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    let input = vec![7u8; 1024 * 1024];
+    let cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::from(input.clone()), WriteStream::Channel(tx))
+        .unwrap();
+    let mut received = vec![];
+    while let Ok(chunk) = rx.recv() {
+        received.extend_from_slice(&chunk);
+    }
+    cat.wait().combine().unwrap();
+    assert_eq!(received, input);
+}
+
+#[test]
+fn unix_stream_by_fd() {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    let (mut ours, theirs) = UnixStream::pair().unwrap();
+    let (output, captured) = WriteStream::capture();
+    let cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::from(theirs), output)
+        .unwrap();
+    ours.write_all(b"over a socket\n").unwrap();
+    drop(ours);
+    let exit = cat.wait();
+    assert!(exit.read_thread.is_none());
+    exit.combine().unwrap();
+    assert_eq!(captured.into_bytes(), b"over a socket\n");
+}
+
+#[test]
+fn chain_inputs() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    tx.send(b"middle ".to_vec()).unwrap();
+    drop(tx);
+    let input = ReadStream::Chain(vec![
+        ReadStream::from("header "),
+        ReadStream::Channel(rx),
+        ReadStream::Null,
+        ReadStream::from(b"trailer".to_vec()),
+    ]);
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::new(Command::new("cat"))
+        .start(input, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"header middle trailer");
+
+    let bad = ReadStream::Chain(vec![ReadStream::Null, ReadStream::PipeRequested]);
+    match ChildProcess::new(Command::new("cat")).start(bad, WriteStream::Null) {
+        Ok(_) => panic!("start should fail"),
+        Err(e) => assert!(e.to_string().contains("chained input 1")),
+    }
+}
+
+#[test]
+fn limited_repeat() {
+    let (output, captured) = WriteStream::capture();
+    let input = ReadStream::Limited {
+        inner: Box::new(ReadStream::Repeat(b"abc".to_vec())),
+        limit: 100_000,
+    };
+    LambdaFilter::new(|_: &[u8]| ())
+        .start(input, output)
+        .unwrap()
+        .wait()
+        .unwrap();
+    let expected = b"abc"
+        .iter()
+        .copied()
+        .cycle()
+        .take(100_000)
+        .collect::<Vec<u8>>();
+    assert_eq!(captured.into_bytes(), expected);
+}
+
+#[test]
+fn limited_zeros() {
+    let (output, captured) = WriteStream::capture();
+    let input = ReadStream::Limited {
+        inner: Box::new(ReadStream::Zeros),
+        limit: 12345,
+    };
+    ChildProcess::new(Command::new("cat"))
+        .start(input, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), vec![0; 12345]);
+}
+
+#[test]
+fn counting_output() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::atomic::Ordering;
+
+    let mut yes = ChildProcess::new(Command::new("yes"))
+        .start(ReadStream::Null, WriteStream::PipeRequested)
+        .unwrap();
+    let (output, count) = WriteStream::counting();
+    let mut head = Command::new("head");
+    head.arg("-c").arg("1000000");
+    let head = ChildProcess::new(head)
+        .start(ReadStream::Fd(yes.output_pipe().unwrap()), output)
+        .unwrap();
+    assert_eq!(yes.wait().child.unwrap().signal(), Some(libc::SIGPIPE));
+    head.wait().combine().unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 1_000_000);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn pipe_capacity_hint() {
+    // 1 MiB is the default pipe-max-size, so unprivileged processes are allowed it.
+    let capacity = 1024 * 1024;
+    let mut cat = ChildProcess::new(Command::new("cat"))
+        .start(
+            ReadStream::pipe_with_capacity(capacity),
+            WriteStream::pipe_with_capacity(capacity),
+        )
+        .unwrap();
+    let input = cat.input_pipe().unwrap();
+    let output = cat.output_pipe().unwrap();
+    assert_eq!(io_chain::pipe_capacity(&input).unwrap(), capacity);
+    assert_eq!(io_chain::pipe_capacity(&output).unwrap(), capacity);
+    drop(input);
+    cat.wait().combine().unwrap();
+}
+
+#[test]
+fn typed_pipes() {
+    use std::io::{Read, Write};
+
+    let mut cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::PipeRequested, WriteStream::PipeRequested)
+        .unwrap();
+    let mut input = cat.input_writer().unwrap();
+    let mut output = cat.output_reader().unwrap();
+    input.write_all(b"typed\n").unwrap();
+    input.close();
+    let mut buf = String::new();
+    output.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "typed\n");
+    cat.wait().combine().unwrap();
+}
+
+#[test]
+fn buffered_input() {
+    use std::io::Write;
+
+    // A child's stdin, closed explicitly.
+    let (output_stream, output) = WriteStream::capture();
+    let mut cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::PipeRequested, output_stream)
+        .unwrap();
+    let mut input = cat.stdin_writer().unwrap();
+    for i in 0..1000 {
+        writeln!(input, "line {i}").unwrap();
+    }
+    input.close().unwrap();
+    cat.wait().combine().unwrap();
+    assert_eq!(output.into_bytes().len(), 8890);
+
+    // A lambda's input, flushed and closed by dropping it.
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::<u8>::new()));
+    let seen2 = std::sync::Arc::clone(&seen);
+    let mut lambda = LambdaFilter::new(move |buf: &[u8]| seen2.lock().unwrap().extend(buf))
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    let mut input = lambda.buffered_input_writer().unwrap();
+    input.write_all(b"dropped\n").unwrap();
+    drop(input);
+    lambda.wait().unwrap();
+    assert_eq!(*seen.lock().unwrap(), b"dropped\n");
+}
+
+#[test]
+fn fd_dup_leaves_original_open() {
+    use std::io::{Seek, Write};
+    use std::os::fd::AsFd;
+
+    let path = std::env::temp_dir().join(format!("io-chain-test-dup-{}", std::process::id()));
+    let mut file = std::fs::File::create(&path).unwrap();
+    for word in ["one", "two"] {
+        let mut cmd = Command::new("echo");
+        cmd.arg(word);
+        ChildProcess::new(cmd)
+            .start(ReadStream::Null, WriteStream::fd_dup(file.as_fd()).unwrap())
+            .unwrap()
+            .wait()
+            .combine()
+            .unwrap();
+    }
+    // Still ours, and shares the offset with the duplicates.
+    assert_eq!(file.stream_position().unwrap(), 8);
+    file.write_all(b"three\n").unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\nthree\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn socket_round_trip() {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    let (input, output) = io_chain::split_socket(server).unwrap();
+    let cat = ChildProcess::new(Command::new("cat"))
+        .start(input, output)
+        .unwrap();
+
+    client.write_all(b"echo echo\n").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut reply = String::new();
+    client.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "echo echo\n");
+
+    let exit = cat.wait();
+    assert!(exit.read_thread.is_none());
+    assert!(exit.write_thread.is_none());
+    exit.combine().unwrap();
+}
+
+#[test]
+fn file_ranges() {
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join(format!("io-chain-test-range-{}", std::process::id()));
+    std::fs::write(&path, b"0123456789").unwrap();
+    let file = Arc::new(std::fs::File::open(&path).unwrap());
+
+    let cat = |input| {
+        let (output, captured) = WriteStream::capture();
+        let running = ChildProcess::new(Command::new("cat"))
+            .start(input, output)
+            .unwrap();
+        (running, captured)
+    };
+    let (a, a_out) = cat(ReadStream::FileRange {
+        file: Arc::clone(&file).into(),
+        offset: 2,
+        len: 3,
+    });
+    let (b, b_out) = cat(ReadStream::FileRange {
+        file: file.into(),
+        offset: 8,
+        len: 100,
+    });
+    let (c, c_out) = cat(ReadStream::FileRange {
+        file: path.clone().into(),
+        offset: 0,
+        len: 4,
+    });
+    for running in [a, b, c] {
+        running.wait().combine().unwrap();
+    }
+    assert_eq!(a_out.into_bytes(), b"234");
+    assert_eq!(b_out.into_bytes(), b"89");
+    assert_eq!(c_out.into_bytes(), b"0123");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_input() {
+    let path = std::env::temp_dir().join(format!("io-chain-test-mmap-{}", std::process::id()));
+    for contents in [&b"mapped\n"[..], b""] {
+        std::fs::write(&path, contents).unwrap();
+        let (output, captured) = WriteStream::capture();
+        ChildProcess::new(Command::new("cat"))
+            .start(ReadStream::Mmap(path.clone()), output)
+            .unwrap()
+            .wait()
+            .combine()
+            .unwrap();
+        assert_eq!(captured.into_bytes(), contents);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn positional_writes() {
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join(format!("io-chain-test-at-{}", std::process::id()));
+    let file = Arc::new(std::fs::File::create(&path).unwrap());
+    file.set_len(20000).unwrap();
+    let halves = [vec![b'a'; 10000], vec![b'b'; 10000]];
+    let running = halves
+        .iter()
+        .enumerate()
+        .map(|(i, half)| {
+            LambdaFilter::new(|_: &[u8]| ())
+                .start(
+                    ReadStream::from(half.clone()),
+                    WriteStream::FileAt {
+                        file: Arc::clone(&file).into(),
+                        offset: i as u64 * 10000,
+                    },
+                )
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    for r in running {
+        r.wait().unwrap();
+    }
+    assert_eq!(std::fs::read(&path).unwrap(), halves.concat());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn fifo_between_chains() {
+    let path = std::env::temp_dir().join(format!("io-chain-test-fifo-{}", std::process::id()));
+    let writer = LambdaFilter::new(|_: &[u8]| ())
+        .start(
+            ReadStream::from("through a fifo\n"),
+            WriteStream::Fifo {
+                path: path.clone(),
+                remove: true,
+            },
+        )
+        .unwrap();
+    let (output, captured) = WriteStream::capture();
+    let reader = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::fifo(&path), output)
+        .unwrap();
+    writer.wait().unwrap();
+    reader.wait().combine().unwrap();
+    assert_eq!(captured.into_bytes(), b"through a fifo\n");
+    assert!(!path.exists());
+}
+
+#[test]
+fn nonblocking_pipe() {
+    use std::io::Read;
+
+    let mut cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::PipeRequested, WriteStream::pipe_nonblocking())
+        .unwrap();
+    let input = cat.input_writer().unwrap();
+    let mut output = cat.output_reader().unwrap();
+    let err = output.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    input.close();
+    cat.wait().combine().unwrap();
+}
+
+#[test]
+fn rust_input_through_three_children() {
+    // If the copy thread's end of the first child's stdin pipe leaked into the later children,
+    // the first child would never see EOF and this would hang.
+    let input = ReadStream::Rust(Box::new(std::io::Cursor::new(vec![b'z'; 100_000])));
+    let mut a = ChildProcess::new(Command::new("cat"))
+        .start(input, WriteStream::PipeRequested)
+        .unwrap();
+    let mut b = ChildProcess::new(Command::new("cat"))
+        .start(
+            a.output_reader().unwrap().into(),
+            WriteStream::PipeRequested,
+        )
+        .unwrap();
+    let (output, captured) = WriteStream::capture();
+    let c = ChildProcess::new(Command::new("cat"))
+        .start(b.output_reader().unwrap().into(), output)
+        .unwrap();
+    a.wait().combine().unwrap();
+    b.wait().combine().unwrap();
+    c.wait().combine().unwrap();
+    assert_eq!(captured.into_bytes(), vec![b'z'; 100_000]);
+}
+
+#[test]
+fn multi_output() {
+    let (a, a_out) = WriteStream::capture();
+    let (b, b_count) = WriteStream::counting();
+    let output = WriteStream::Multi(vec![a, WriteStream::Null, b]);
+    ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::from(vec![1; 50_000]), output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(a_out.into_bytes(), vec![1; 50_000]);
+    assert_eq!(b_count.load(std::sync::atomic::Ordering::SeqCst), 50_000);
+}
+
+#[test]
+fn iterator_input() {
+    // The first chunk is bigger than any copy buffer, so it has to be carried across reads.
+    let chunks = vec![
+        vec![b'a'; 1024 * 1024],
+        b"b".to_vec(),
+        vec![],
+        b"cc".to_vec(),
+    ];
+    let expected = chunks.concat();
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::from_chunks(chunks.into_iter().map(Ok)), output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), expected);
+
+    let failing = vec![Ok(b"ok".to_vec()), Err(std::io::Error::other("boom"))];
+    let err = LambdaFilter::new(|_: &[u8]| ())
+        .start(ReadStream::from_chunks(failing), WriteStream::Null)
+        .unwrap()
+        .wait()
+        .unwrap_err();
+    assert_eq!(err.to_string(), "boom");
+}
+
+#[test]
+fn try_wait_polling() {
+    let mut cat = ChildProcess::new(Command::new("cat"))
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    let input = cat.input_writer().unwrap();
+    let mut cat = cat.try_wait().expect_err("cat should still be running");
+    input.close();
+    loop {
+        match cat.try_wait() {
+            Ok(exit) => break exit.combine().unwrap(),
+            Err(running) => cat = running,
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn try_wait_threads() {
+    let mut lambda = LambdaFilter::new(|_: &[u8]| ())
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    let mut tee = Tee::new(16)
+        .start(ReadStream::PipeRequested, WriteStream::Null)
+        .unwrap();
+    let lambda_input = lambda.input_writer().unwrap();
+    let tee_input = tee.input_writer().unwrap();
+    let lambda = lambda
+        .try_wait()
+        .expect_err("lambda should still be running");
+    let tee = tee.try_wait().expect_err("tee should still be running");
+
+    // Poll both from one thread until they're done.
+    lambda_input.close();
+    tee_input.close();
+    let (mut lambda, mut tee) = (Some(lambda), Some(tee));
+    while lambda.is_some() || tee.is_some() {
+        if let Some(running) = lambda.take() {
+            match running.try_wait() {
+                Ok(result) => result.unwrap(),
+                Err(running) => lambda = Some(running),
+            }
+        }
+        if let Some(running) = tee.take() {
+            match running.try_wait() {
+                Ok(results) => assert!(results.iter().all(Result::is_ok)),
+                Err(running) => tee = Some(running),
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn stderr_streams() {
+    use std::io::Read;
+
+    let sh = |script: &str| {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        ChildProcess::new(cmd)
+    };
+
+    let (stderr, captured) = WriteStream::capture();
+    sh("echo out; echo err >&2")
+        .stderr(stderr)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"err\n");
+
+    let mut child = sh("echo err >&2")
+        .stderr(WriteStream::PipeRequested)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap();
+    let mut err = String::new();
+    std::fs::File::from(child.stderr_pipe().unwrap())
+        .read_to_string(&mut err)
+        .unwrap();
+    assert_eq!(err, "err\n");
+    let exit = child.wait();
+    assert!(exit.err_thread.is_none());
+    exit.combine().unwrap();
+}
+
+#[test]
+fn merged_stderr() {
+    use std::io::Read;
+
+    let sh = || {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo out; echo err >&2");
+        ChildProcess::new(cmd).merge_stderr(true)
+    };
+
+    let (output, captured) = WriteStream::capture();
+    sh().start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"out\nerr\n");
+
+    let mut child = sh()
+        .start(ReadStream::Null, WriteStream::PipeRequested)
+        .unwrap();
+    let mut output = String::new();
+    child
+        .output_reader()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert_eq!(output, "out\nerr\n");
+    child.wait().combine().unwrap();
+
+    let err = sh()
+        .stderr(WriteStream::Null)
+        .start(ReadStream::Null, WriteStream::Null)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn stderr_tail_in_error() {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg("echo first line >&2; echo 'no such thing' >&2; exit 4");
+    let exit = ChildProcess::new(cmd)
+        .capture_stderr_tail(14)
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait();
+    assert_eq!(
+        exit.stderr_tail.as_deref(),
+        Some(&b"...no such thing\n"[..])
+    );
+    let err = exit.combine().unwrap_err().to_string();
+    assert!(err.ends_with("\n   stderr: ...no such thing"), "{err}");
+}
+
+#[test]
+fn copied_byte_counts() {
+    let mut cmd = Command::new("head");
+    cmd.arg("-c").arg("1000");
+    let (output, captured) = WriteStream::capture();
+    let exit = ChildProcess::new(cmd)
+        .start(ReadStream::from(vec![b'x'; 5000]), output)
+        .unwrap()
+        .wait();
+    assert_eq!(exit.write_bytes, Some(1000));
+    assert_eq!(captured.bytes().len(), 1000);
+    assert!(exit.err_bytes.is_none());
+}
+
+#[test]
+fn command_builder() {
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::command("sh", ["-c"])
+        .arg("echo $GREETING from $(pwd)")
+        .env("GREETING", "hello")
+        .current_dir("/")
+        .start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"hello from /\n");
+}
+
+#[test]
+fn shell_scripts() {
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell("tr a-z A-Z | sort -r")
+        .start(ReadStream::from("b\na\nc\n"), output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"C\nB\nA\n");
+
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::shell_args("printf '%s\\n' \"$@\"", ["; not run", "$(nor this)"])
+        .start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"; not run\n$(nor this)\n");
+
+    let exit = ChildProcess::shell("exit 3")
+        .start(ReadStream::Null, WriteStream::Null)
+        .unwrap()
+        .wait();
+    assert_eq!(exit.exit_code(), Some(3));
+}
+
+#[test]
+fn parse_command_line() {
+    let (output, captured) = WriteStream::capture();
+    ChildProcess::parse(r#"printf '%s|' "two words" it\'s "\$HOME" '' x\ y"#)
+        .unwrap()
+        .start(ReadStream::Null, output)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(captured.into_bytes(), b"two words|it's|$HOME||x y|");
+
+    use io_chain::ParseErrorKind;
+    let err = |cmdline| match ChildProcess::parse(cmdline) {
+        Ok(_) => panic!("{cmdline:?} parsed"),
+        Err(e) => (e.kind, e.position),
+    };
+    assert_eq!(err("   "), (ParseErrorKind::Empty, 0));
+    assert_eq!(
+        err("echo 'oops"),
+        (ParseErrorKind::UnterminatedSingleQuote, 5)
+    );
+    assert_eq!(
+        err("echo \"oops"),
+        (ParseErrorKind::UnterminatedDoubleQuote, 5)
+    );
+    assert_eq!(err("echo oops\\"), (ParseErrorKind::TrailingBackslash, 9));
+}
+
+#[test]
+fn display_command_line() {
+    let printf = ChildProcess::command("printf", ["%s|%s\\n", "it's", "$X"]).env("X", "a b");
+    let rendered = printf.to_string();
+    assert_eq!(rendered, r#"X='a b' printf '%s|%s\n' 'it'\''s' '$X'"#);
+
+    // Running the rendering through a shell does the same thing.
+    let (direct, direct_out) = WriteStream::capture();
+    let running = printf.start(ReadStream::Null, direct).unwrap();
+    assert_eq!(running.command_line(), rendered);
+    running.wait().combine().unwrap();
+    let (shell, shell_out) = WriteStream::capture();
+    ChildProcess::shell(rendered)
+        .start(ReadStream::Null, shell)
+        .unwrap()
+        .wait()
+        .combine()
+        .unwrap();
+    assert_eq!(direct_out.into_bytes(), shell_out.into_bytes());
+}
@@ -1,47 +1,7 @@
-use std::io::Write;
-use std::os::unix::process::ExitStatusExt;
 use std::process::Command;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
-use io_chain::{ChildProcess, Filter, LambdaFilter, ReadStream, RunningFilter, WriteStream};
-use parking_lot::{Mutex, MutexGuard};
-
-#[derive(Debug)]
-struct SharedBuf {
-    inner: Mutex<Vec<u8>>,
-}
-
-impl SharedBuf {
-    pub fn new(vec: Vec<u8>) -> Self {
-        SharedBuf {
-            inner: Mutex::new(vec),
-        }
-    }
-
-    pub fn writer(&self) -> SharedBufWriter<'_> {
-        SharedBufWriter {
-            inner: self.inner.lock(),
-        }
-    }
-
-    pub fn bytes(&self) -> Vec<u8> {
-        self.inner.lock().clone()
-    }
-}
-
-struct SharedBufWriter<'a> {
-    inner: MutexGuard<'a, Vec<u8>>,
-}
-
-impl<'a> Write for SharedBufWriter<'a> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.inner.write(buf)
-    }
-    fn flush(&mut self) -> std::io::Result<()> {
-        Ok(())
-    }
-}
+use io_chain::{ChildProcess, CountingFilter, Filter, ReadStream, RunningFilter, WriteStream};
 
 #[cfg(target_os = "linux")]
 #[test]
@@ -53,22 +13,14 @@ fn linux_yes_head_sha() {
     // bash pipeline.
 
     let num_bytes = 1024 * 1024 * 512;
-    let num_bytes_read = Arc::new(AtomicU64::new(0));
-    let num_bytes_write = Arc::clone(&num_bytes_read);
 
     let yes = ChildProcess::new(Command::new("yes"));
-    let head = {
-        let mut cmd = Command::new("head");
-        cmd.arg("-c").arg(format!("{num_bytes}"));
-        ChildProcess::new(cmd)
-    };
-    let count = LambdaFilter::new(move |buf: &[u8]| {
-        num_bytes_write.fetch_add(buf.len() as u64, std::sync::atomic::Ordering::Relaxed);
-    });
+    let head = ChildProcess::command("head", ["-c", &num_bytes.to_string()]);
+    let mut count = CountingFilter::new();
+    let live_bytes = count.live_bytes();
     let sha = ChildProcess::new(Command::new("sha256sum"));
 
-    let output = Box::leak(Box::new(SharedBuf::new(vec![])));
-    let output_writer = Box::new(output.writer());
+    let (output_stream, output) = WriteStream::capture();
 
     let mut yes = yes
         .start(ReadStream::Null, WriteStream::PipeRequested)
@@ -86,27 +38,136 @@ fn linux_yes_head_sha() {
         )
         .unwrap();
     let sha = sha
-        .start(
-            ReadStream::Fd(count.output_pipe().unwrap()),
-            WriteStream::Rust(output_writer),
-        )
+        .start(ReadStream::Fd(count.output_pipe().unwrap()), output_stream)
         .unwrap();
 
     let yes = yes.wait();
-    assert_eq!(yes.child.unwrap().signal(), Some(libc::SIGPIPE));
+    assert_eq!(yes.signal(), Some(libc::SIGPIPE));
     assert!(yes.read_thread.is_none());
     assert!(yes.write_thread.is_none());
 
     head.wait().combine().unwrap();
 
-    let () = count.wait().unwrap();
-    assert_eq!(num_bytes_read.load(Ordering::SeqCst), num_bytes);
+    let counts = count.wait().unwrap();
+    assert_eq!(counts.bytes, num_bytes);
+    assert!(counts.chunks > 0);
+    assert_eq!(live_bytes.load(Ordering::SeqCst), num_bytes);
+
+    sha.wait().combine().unwrap();
+
+    let out_str = String::from_utf8_lossy(&output.into_bytes()).into_owned();
+    assert_eq!(
+        out_str,
+        "d227b8c4d59acf0f9711af6049bd5fcde81229cd70093e36ac4f038a14ecf290  -\n"
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn linux_yes_limited_sha() {
+    // Same as above, but with the lambda's input limited instead of running head.
+
+    let num_bytes = 1024 * 1024 * 512;
+
+    let yes = ChildProcess::new(Command::new("yes"));
+    let count = CountingFilter::new();
+    let sha = ChildProcess::new(Command::new("sha256sum"));
+
+    let (output_stream, output) = WriteStream::capture();
+
+    let mut yes = yes
+        .start(ReadStream::Null, WriteStream::PipeRequested)
+        .unwrap();
+    let mut count = count
+        .start(
+            ReadStream::Limited {
+                inner: Box::new(ReadStream::Fd(yes.output_pipe().unwrap())),
+                limit: num_bytes,
+            },
+            WriteStream::PipeRequested,
+        )
+        .unwrap();
+    let sha = sha
+        .start(ReadStream::Fd(count.output_pipe().unwrap()), output_stream)
+        .unwrap();
+
+    assert_eq!(yes.wait().signal(), Some(libc::SIGPIPE));
+
+    assert_eq!(count.wait().unwrap().bytes, num_bytes);
 
     sha.wait().combine().unwrap();
 
-    let out_str = String::from_utf8_lossy(&output.bytes()).into_owned();
+    let out_str = String::from_utf8_lossy(&output.into_bytes()).into_owned();
     assert_eq!(
         out_str,
         "d227b8c4d59acf0f9711af6049bd5fcde81229cd70093e36ac4f038a14ecf290  -\n"
     );
 }
+
+#[cfg(target_os = "linux")]
+#[test]
+fn linux_repeat_sha_big_buffer() {
+    // Same output as above, but with the data coming from a Rust stream through a copy thread
+    // with a 1 MiB buffer. (The pattern is long so that repeating it is cheap in debug builds.)
+
+    let num_bytes = 1024 * 1024 * 512;
+    let (output_stream, output) = WriteStream::capture();
+    let exit = ChildProcess::new(Command::new("sha256sum"))
+        .copy_buffer_size(1024 * 1024)
+        .start(
+            ReadStream::Limited {
+                inner: Box::new(ReadStream::Repeat(b"y\n".repeat(4096))),
+                limit: num_bytes,
+            },
+            output_stream,
+        )
+        .unwrap()
+        .wait();
+    assert_eq!(exit.read_bytes, Some(num_bytes));
+    exit.combine().unwrap();
+
+    let out_str = String::from_utf8_lossy(&output.into_bytes()).into_owned();
+    assert_eq!(
+        out_str,
+        "d227b8c4d59acf0f9711af6049bd5fcde81229cd70093e36ac4f038a14ecf290  -\n"
+    );
+}
+
+#[cfg(all(target_os = "linux", feature = "digest"))]
+#[test]
+fn linux_yes_head_hash() {
+    // Like the first test, but also hashing in-process on the way to sha256sum, and checking the
+    // two agree. Less data, since hashing is slow in debug builds.
+
+    use io_chain::HashFilter;
+
+    let num_bytes = 1024 * 1024 * 64;
+    let (output_stream, output) = WriteStream::capture();
+
+    let mut yes = ChildProcess::new(Command::new("yes"))
+        .start(ReadStream::Null, WriteStream::PipeRequested)
+        .unwrap();
+    let mut head = ChildProcess::command("head", ["-c", &num_bytes.to_string()])
+        .start(
+            ReadStream::Fd(yes.output_pipe().unwrap()),
+            WriteStream::PipeRequested,
+        )
+        .unwrap();
+    let mut hash = HashFilter::<sha2::Sha256>::new()
+        .start(
+            ReadStream::Fd(head.output_pipe().unwrap()),
+            WriteStream::PipeRequested,
+        )
+        .unwrap();
+    let sha = ChildProcess::new(Command::new("sha256sum"))
+        .start(ReadStream::Fd(hash.output_pipe().unwrap()), output_stream)
+        .unwrap();
+
+    assert_eq!(yes.wait().signal(), Some(libc::SIGPIPE));
+    head.wait().combine().unwrap();
+    let hash = hash.wait().unwrap();
+    sha.wait().combine().unwrap();
+
+    let out_str = String::from_utf8_lossy(&output.into_bytes()).into_owned();
+    assert_eq!(out_str, format!("{hash:x}  -\n"));
+}